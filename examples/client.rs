@@ -43,7 +43,7 @@ async fn main() -> std::io::Result<()> {
 
     sink.subscribe(None)
         .topic_filter(
-            "response".into(),
+            "response",
             v5::codec::SubscriptionOptions {
                 qos: v5::codec::QoS::AtLeastOnce,
                 no_local: false,
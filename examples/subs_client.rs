@@ -66,7 +66,7 @@ async fn main() -> std::io::Result<()> {
     // subscribe to topic
     sink.subscribe(None)
         .topic_filter(
-            "topic1".into(),
+            "topic1",
             v5::codec::SubscriptionOptions {
                 qos: v5::codec::QoS::AtLeastOnce,
                 no_local: false,
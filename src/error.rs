@@ -51,6 +51,9 @@ pub enum ProtocolError {
     /// Keep alive timeout
     #[display(fmt = "Keep alive timeout")]
     KeepAliveTimeout,
+    /// Ack packet for unknown packet id
+    #[display(fmt = "Unexpected ack packet with id {}", _0)]
+    UnexpectedAck(u16),
 }
 
 impl error::Error for ProtocolError {}
@@ -105,6 +108,8 @@ pub enum DecodeError {
     PacketIdRequired,
     MaxSizeExceeded,
     Utf8Error,
+    /// Malformed PROXY protocol header
+    InvalidProxyHeader,
 }
 
 impl error::Error for DecodeError {}
@@ -114,11 +119,42 @@ pub enum EncodeError {
     InvalidLength,
     MalformedPacket,
     PacketIdRequired,
+    PacketIdsExhausted,
     UnsupportedVersion,
+    #[display(fmt = "Invalid publish packet: {}", _0)]
+    InvalidPublish(PublishValidationError),
+    /// Packet exceeds peer's maximum packet size
+    #[display(fmt = "Packet size {} exceeds maximum packet size {}", size, max)]
+    PacketTooLarge {
+        size: usize,
+        max: usize,
+    },
 }
 
 impl error::Error for EncodeError {}
 
+/// Publish packet violates protocol constraints
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Hash)]
+pub enum PublishValidationError {
+    /// Topic is empty and topic alias is not set
+    #[display(fmt = "Topic is empty")]
+    EmptyTopic,
+    /// Topic contains wildcard characters
+    #[display(fmt = "Topic contains wildcard characters")]
+    TopicWildcard,
+    /// QoS-1/2 publish without packet id
+    #[display(fmt = "Packet id is required")]
+    PacketIdRequired,
+    /// QoS-0 publish with packet id
+    #[display(fmt = "Packet id is not allowed for QoS-0 publish")]
+    UnexpectedPacketId,
+    /// QoS-0 publish with DUP flag
+    #[display(fmt = "DUP flag is not allowed for QoS-0 publish")]
+    DupWithQoS0,
+}
+
+impl error::Error for PublishValidationError {}
+
 impl PartialEq for DecodeError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -135,6 +171,7 @@ impl PartialEq for DecodeError {
             (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
             (DecodeError::Utf8Error, DecodeError::Utf8Error) => true,
+            (DecodeError::InvalidProxyHeader, DecodeError::InvalidProxyHeader) => true,
             _ => false,
         }
     }
@@ -143,13 +180,26 @@ impl PartialEq for DecodeError {
 #[derive(Debug, Display, PartialEq)]
 pub enum SendPacketError {
     /// Encoder error
+    #[display(fmt = "Encode error: {}", _0)]
     Encode(EncodeError),
     /// Provided packet id is in use
-    #[display(fmt = "Provided packet id is in use")]
+    #[display(fmt = "Provided packet id is in use: {}", _0)]
     PacketIdInUse(u16),
+    /// Ack packet does not match request
+    #[display(fmt = "Ack packet does not match request")]
+    Protocol,
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Outbound rate limit is exceeded
+    #[display(fmt = "Rate limit exceeded")]
+    RateLimited,
+    /// Ack is not received within timeout
+    #[display(fmt = "Ack timeout")]
+    Timeout,
+    /// Publish QoS is not supported by send method
+    #[display(fmt = "Unsupported QoS: {:?}", _0)]
+    UnsupportedQoS(crate::types::QoS),
 }
 
 impl error::Error for SendPacketError {}
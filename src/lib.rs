@@ -14,6 +14,7 @@ pub mod v5;
 
 mod inflight;
 mod io;
+mod proxy_protocol;
 mod server;
 mod service;
 mod session;
@@ -23,7 +24,8 @@ mod version;
 pub use self::error::MqttError;
 pub use self::server::MqttServer;
 pub use self::session::Session;
-pub use self::topic::{Level as TopicLevel, Topic};
+pub use self::topic::{Level as TopicLevel, Topic, TopicFilterError, TopicMatcher};
+pub use self::version::ProtocolVersion;
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
@@ -0,0 +1,225 @@
+//! PROXY protocol v1/v2 header decoder
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ntex::codec::Decoder;
+use ntex::io::IoBoxed;
+use ntex::util::{Buf, BytesMut};
+
+use crate::error::{DecodeError, MqttError};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Max length of v1 header, including CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version/command, family/transport and length fields
+const V2_HEADER_LEN: usize = 16;
+
+/// Parsed PROXY protocol header
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    /// Original client address, `None` for `UNKNOWN` and `LOCAL` connections
+    pub(crate) source: Option<SocketAddr>,
+}
+
+/// Decodes and strips PROXY protocol header, data after header stays in buffer
+#[derive(Debug)]
+pub(crate) struct ProxyCodec;
+
+impl Decoder for ProxyCodec {
+    type Item = ProxyHeader;
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        if let Some((len, header)) = parse(src)? {
+            src.advance(len);
+            Ok(Some(header))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Check if buffer starts with PROXY protocol signature, or with part of it
+pub(crate) fn is_header(src: &[u8]) -> bool {
+    starts_with(src, V2_SIGNATURE) || starts_with(src, V1_PREFIX)
+}
+
+/// Parse PROXY protocol header without consuming it, returns header length
+pub(crate) fn parse(src: &[u8]) -> Result<Option<(usize, ProxyHeader)>, DecodeError> {
+    if starts_with(src, V2_SIGNATURE) {
+        parse_v2(src)
+    } else if starts_with(src, V1_PREFIX) {
+        parse_v1(src)
+    } else {
+        Err(DecodeError::InvalidProxyHeader)
+    }
+}
+
+fn starts_with(src: &[u8], prefix: &[u8]) -> bool {
+    let len = src.len().min(prefix.len());
+    src[..len] == prefix[..len]
+}
+
+/// Read and strip PROXY protocol header, returns original client address
+pub(crate) async fn strip_header<E>(io: &IoBoxed) -> Result<Option<SocketAddr>, MqttError<E>> {
+    let header = io
+        .recv(&ProxyCodec)
+        .await
+        .map_err(|err| {
+            log::trace!("Error is received during proxy header decoding: {:?}", err);
+            MqttError::from(err)
+        })?
+        .ok_or_else(|| {
+            log::trace!("Server mqtt is disconnected during proxy header decoding");
+            MqttError::Disconnected(None)
+        })?;
+    Ok(header.source)
+}
+
+fn parse_v1(src: &[u8]) -> Result<Option<(usize, ProxyHeader)>, DecodeError> {
+    let end = match src.iter().take(V1_MAX_LEN).position(|b| *b == b'\n') {
+        Some(pos) => pos + 1,
+        None if src.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(DecodeError::InvalidProxyHeader),
+    };
+    let line = std::str::from_utf8(&src[..end])
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or(DecodeError::InvalidProxyHeader)?;
+
+    let mut parts = line.split(' ').skip(1);
+    let source = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some(proto @ "TCP4") | Some(proto @ "TCP6") => {
+            let mut next = || parts.next().ok_or(DecodeError::InvalidProxyHeader);
+            let ip: IpAddr = next()?.parse().map_err(|_| DecodeError::InvalidProxyHeader)?;
+            let _dst: IpAddr = next()?.parse().map_err(|_| DecodeError::InvalidProxyHeader)?;
+            let port: u16 = next()?.parse().map_err(|_| DecodeError::InvalidProxyHeader)?;
+            let _dst_port: u16 =
+                next()?.parse().map_err(|_| DecodeError::InvalidProxyHeader)?;
+            if ip.is_ipv4() != (proto == "TCP4") {
+                return Err(DecodeError::InvalidProxyHeader);
+            }
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+
+    Ok(Some((end, ProxyHeader { source })))
+}
+
+fn parse_v2(src: &[u8]) -> Result<Option<(usize, ProxyHeader)>, DecodeError> {
+    if src.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let ver_cmd = src[12];
+    let family = src[13];
+    let len = u16::from_be_bytes(src[14..16].try_into().unwrap()) as usize;
+    if src.len() < V2_HEADER_LEN + len {
+        return Ok(None);
+    }
+    ensure!(ver_cmd >> 4 == 2, DecodeError::InvalidProxyHeader);
+
+    let addr = &src[V2_HEADER_LEN..V2_HEADER_LEN + len];
+    let source = match (ver_cmd & 0x0f, family >> 4) {
+        // LOCAL command, connection established by proxy itself
+        (0, _) => None,
+        // PROXY command, AF_INET
+        (1, 1) => {
+            ensure!(len >= 12, DecodeError::InvalidProxyHeader);
+            let ip: [u8; 4] = addr[..4].try_into().unwrap();
+            let port = u16::from_be_bytes(addr[8..10].try_into().unwrap());
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        // PROXY command, AF_INET6
+        (1, 2) => {
+            ensure!(len >= 36, DecodeError::InvalidProxyHeader);
+            let ip: [u8; 16] = addr[..16].try_into().unwrap();
+            let port = u16::from_be_bytes(addr[32..34].try_into().unwrap());
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        // PROXY command, AF_UNSPEC or AF_UNIX
+        (1, _) => None,
+        _ => return Err(DecodeError::InvalidProxyHeader),
+    };
+
+    Ok(Some((V2_HEADER_LEN + len, ProxyHeader { source })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(src: &[u8]) -> (Result<Option<ProxyHeader>, DecodeError>, BytesMut) {
+        let mut buf = BytesMut::from(src);
+        (ProxyCodec.decode(&mut buf), buf)
+    }
+
+    fn header(addr: &str) -> Result<Option<ProxyHeader>, DecodeError> {
+        Ok(Some(ProxyHeader { source: Some(addr.parse().unwrap()) }))
+    }
+
+    #[test]
+    fn test_decode_v1() {
+        let (res, buf) = decode(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10");
+        assert_eq!(res, header("192.168.0.1:56324"));
+        assert_eq!(&buf[..], b"\x10");
+
+        let (res, _) = decode(b"PROXY TCP6 ::1 ::2 4000 1883\r\n");
+        assert_eq!(res, header("[::1]:4000"));
+
+        let (res, buf) = decode(b"PROXY UNKNOWN\r\n");
+        assert_eq!(res, Ok(Some(ProxyHeader { source: None })));
+        assert!(buf.is_empty());
+
+        // incomplete
+        assert_eq!(decode(b"").0, Ok(None));
+        assert_eq!(decode(b"PRO").0, Ok(None));
+        assert_eq!(decode(b"PROXY TCP4 192.168.0.1").0, Ok(None));
+
+        for src in [
+            &b"PROXY TCP4 ::1 ::2 4000 1883\r\n"[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY UDP4 192.168.0.1 192.168.0.11 56324 1883\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\n",
+            &[b'P'; V1_MAX_LEN][..],
+            b"\x10\x98\x02\0\x04MQTT\x05",
+        ] {
+            assert_eq!(decode(src).0, Err(DecodeError::InvalidProxyHeader));
+        }
+    }
+
+    #[test]
+    fn test_decode_v2() {
+        let mut v4 = V2_SIGNATURE.to_vec();
+        v4.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 168, 0, 1, 192, 168, 0, 11]);
+        v4.extend_from_slice(&[0xdc, 0x04, 0x07, 0x5b, 0x10]);
+        let (res, buf) = decode(&v4);
+        assert_eq!(res, header("192.168.0.1:56324"));
+        assert_eq!(&buf[..], b"\x10");
+
+        // incomplete
+        assert_eq!(decode(&v4[..8]).0, Ok(None));
+        assert_eq!(decode(&v4[..20]).0, Ok(None));
+
+        let mut v6 = V2_SIGNATURE.to_vec();
+        v6.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&[0; 16]);
+        v6.extend_from_slice(&[0x0f, 0xa0, 0x07, 0x5b]);
+        assert_eq!(decode(&v6).0, header("[::1]:4000"));
+
+        // LOCAL command with TLV data
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 3, 1, 2, 3]);
+        let (res, buf) = decode(&local);
+        assert_eq!(res, Ok(Some(ProxyHeader { source: None })));
+        assert!(buf.is_empty());
+
+        // unsupported version
+        let mut invalid = V2_SIGNATURE.to_vec();
+        invalid.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert_eq!(decode(&invalid).0, Err(DecodeError::InvalidProxyHeader));
+    }
+}
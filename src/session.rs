@@ -1,5 +1,9 @@
-use std::ops::Deref;
-use std::rc::Rc;
+use std::any::{Any, TypeId};
+use std::{cell::RefCell, net::SocketAddr, ops::Deref, rc::Rc};
+
+use ntex::util::{ByteString, HashMap};
+
+use crate::{v5::codec, version::ProtocolVersion};
 
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
@@ -9,6 +13,17 @@ struct SessionInner<T, St> {
     sink: T,
     max_receive: u16,
     max_topic_alias: u16,
+    connect: ConnectInfo,
+    user_data: RefCell<HashMap<TypeId, Rc<dyn Any>>>,
+}
+
+/// Client parameters from CONNECT packet
+#[derive(Default)]
+pub(crate) struct ConnectInfo {
+    pub(crate) client_id: ByteString,
+    pub(crate) clean_start: bool,
+    pub(crate) keep_alive: u16,
+    pub(crate) session_expiry: u32,
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -20,11 +35,24 @@ impl<T, St> Clone for Session<T, St> {
 
 impl<T, St> Session<T, St> {
     pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+        Session::new_v5(st, sink, 0, 0, ConnectInfo::default())
     }
 
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new_v5(
+        st: St,
+        sink: T,
+        max_receive: u16,
+        max_topic_alias: u16,
+        connect: ConnectInfo,
+    ) -> Self {
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            max_receive,
+            max_topic_alias,
+            connect,
+            user_data: RefCell::new(HashMap::default()),
+        }))
     }
 
     #[inline]
@@ -37,11 +65,74 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    /// Attach value to the session
+    ///
+    /// Previously attached value of the same type is replaced.
+    pub fn set_user_data<U: 'static>(&self, data: U) {
+        self.0.user_data.borrow_mut().insert(TypeId::of::<U>(), Rc::new(data));
+    }
+
+    /// Get value attached to the session
+    pub fn user_data<U: 'static>(&self) -> Option<Rc<U>> {
+        self.0
+            .user_data
+            .borrow()
+            .get(&TypeId::of::<U>())
+            .cloned()
+            .and_then(|d| d.downcast().ok())
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
         (self.0.max_receive, self.0.max_topic_alias)
     }
 }
 
+impl<St> Session<crate::v5::MqttSink, St> {
+    /// Client identifier
+    ///
+    /// Identifier assigned by server is returned if client connected with empty one.
+    #[inline]
+    pub fn client_id(&self) -> &ByteString {
+        &self.0.connect.client_id
+    }
+
+    /// Clean start flag from CONNECT packet
+    #[inline]
+    pub fn clean_start(&self) -> bool {
+        self.0.connect.clean_start
+    }
+
+    /// Keep-alive interval requested by client, in seconds
+    #[inline]
+    pub fn keep_alive(&self) -> u16 {
+        self.0.connect.keep_alive
+    }
+
+    /// Session expiry interval requested by client, in seconds
+    #[inline]
+    pub(crate) fn session_expiry(&self) -> u32 {
+        self.0.connect.session_expiry
+    }
+
+    /// Mqtt protocol version of the connection
+    #[inline]
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        ProtocolVersion::MQTT5
+    }
+
+    /// Client's address, `None` if transport does not provide it
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.sink.peer_addr()
+    }
+}
+
+impl<St> Session<crate::v3::MqttSink, St> {
+    /// Client's address, `None` if transport does not provide it
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.sink.peer_addr()
+    }
+}
+
 impl<T, St> Deref for Session<T, St> {
     type Target = St;
 
@@ -50,3 +141,123 @@ impl<T, St> Deref for Session<T, St> {
         &self.0.st
     }
 }
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Session state snapshot
+///
+/// Could be used for persistent sessions, see `MqttSink::snapshot()`
+/// and `MqttSink::restore()`. With `serde` feature enabled snapshot
+/// implements `Serialize` and `Deserialize`, publish packets are
+/// serialized in mqtt wire format.
+pub struct SessionSnapshot {
+    /// Un-acked QoS-1 and QoS-2 publish packets, in send order
+    pub inflight: Vec<codec::Publish>,
+    /// Ids of released QoS-2 publish packets waiting for PUBCOMP, in send order
+    pub released: Vec<u16>,
+    /// Active subscriptions
+    pub subscriptions: Vec<(ByteString, codec::SubscriptionOptions)>,
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use ntex::codec::{Decoder, Encoder};
+    use ntex::util::{Bytes, BytesMut};
+    use serde::{de::Error as _, ser::Error as _};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{codec, SessionSnapshot};
+    use crate::utils::{Decode, Encode};
+
+    #[derive(Serialize, Deserialize)]
+    struct Snapshot {
+        inflight: Vec<Vec<u8>>,
+        released: Vec<u16>,
+        subscriptions: Vec<(String, u8)>,
+    }
+
+    impl Serialize for SessionSnapshot {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let codec = codec::Codec::new();
+            let mut inflight = Vec::with_capacity(self.inflight.len());
+            for pkt in &self.inflight {
+                let mut buf = BytesMut::new();
+                codec
+                    .encode(codec::Packet::Publish(pkt.clone()), &mut buf)
+                    .map_err(|e| S::Error::custom(format!("{:?}", e)))?;
+                inflight.push(buf.to_vec());
+            }
+
+            let mut subscriptions = Vec::with_capacity(self.subscriptions.len());
+            for (filter, opts) in &self.subscriptions {
+                let mut buf = BytesMut::new();
+                opts.encode(&mut buf).map_err(|e| S::Error::custom(format!("{:?}", e)))?;
+                subscriptions.push((filter.to_string(), buf[0]));
+            }
+
+            Snapshot { inflight, released: self.released.clone(), subscriptions }
+                .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for SessionSnapshot {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let snapshot = Snapshot::deserialize(deserializer)?;
+
+            let codec = codec::Codec::new();
+            let mut inflight = Vec::with_capacity(snapshot.inflight.len());
+            for data in snapshot.inflight {
+                match codec.decode(&mut BytesMut::from(&data[..])) {
+                    Ok(Some(codec::Packet::Publish(pkt))) => inflight.push(pkt),
+                    Ok(_) => return Err(D::Error::custom("Publish packet is expected")),
+                    Err(e) => return Err(D::Error::custom(format!("{:?}", e))),
+                }
+            }
+
+            let mut subscriptions = Vec::with_capacity(snapshot.subscriptions.len());
+            for (filter, opts) in snapshot.subscriptions {
+                let opts =
+                    codec::SubscriptionOptions::decode(&mut Bytes::copy_from_slice(&[opts]))
+                        .map_err(|e| D::Error::custom(format!("{:?}", e)))?;
+                subscriptions.push((filter.into(), opts));
+            }
+
+            Ok(SessionSnapshot { inflight, released: snapshot.released, subscriptions })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::types::QoS;
+
+        #[test]
+        fn test_snapshot_serde() {
+            let snapshot = SessionSnapshot {
+                inflight: vec![codec::Publish {
+                    dup: false,
+                    retain: true,
+                    qos: QoS::ExactlyOnce,
+                    topic: "topic".into(),
+                    packet_id: std::num::NonZeroU16::new(3),
+                    payload: Bytes::from_static(b"data"),
+                    properties: codec::PublishProperties {
+                        content_type: Some("text".into()),
+                        ..Default::default()
+                    },
+                }],
+                released: vec![1, 2],
+                subscriptions: vec![(
+                    "topic/#".into(),
+                    codec::SubscriptionOptions {
+                        qos: QoS::AtLeastOnce,
+                        no_local: true,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::AtSubscribeNew,
+                    },
+                )],
+            };
+            let data = serde_json::to_string(&snapshot).unwrap();
+            assert_eq!(serde_json::from_str::<SessionSnapshot>(&data).unwrap(), snapshot);
+        }
+    }
+}
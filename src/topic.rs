@@ -1,5 +1,7 @@
 use std::fmt::{self, Write};
-use std::{io, ops, str::FromStr};
+use std::{error, io, ops, str::FromStr};
+
+use ntex::util::ByteString;
 
 fn is_metadata<T: AsRef<str>>(s: T) -> bool {
     s.as_ref().starts_with('$')
@@ -11,6 +13,107 @@ pub enum TopicError {
     InvalidLevel,
 }
 
+#[derive(Clone, Debug, PartialEq, derive_more::Display)]
+pub enum TopicFilterError {
+    /// Topic filter is malformed
+    #[display(fmt = "Invalid topic filter: {}", _0)]
+    InvalidFilter(ByteString),
+}
+
+impl error::Error for TopicFilterError {}
+
+/// Check topic filter
+///
+/// Filter must not be empty or contain null characters, `#` is allowed
+/// only as last level and `+` only as whole level.
+pub(crate) fn validate_topic_filter(
+    filter: ByteString,
+) -> Result<ByteString, TopicFilterError> {
+    let mut levels = filter.split('/').peekable();
+    let mut valid = !filter.is_empty() && !filter.contains('\0');
+
+    while let Some(level) = levels.next() {
+        valid &= match level {
+            "#" => levels.peek().is_none(),
+            "+" => true,
+            _ => !level.contains(['+', '#']),
+        };
+    }
+
+    if valid {
+        Ok(filter)
+    } else {
+        Err(TopicFilterError::InvalidFilter(filter))
+    }
+}
+
+/// Topic filter matcher
+///
+/// Matches topic names against a validated topic filter, following
+/// MQTT wildcard rules. Topics starting with `$` are not matched by
+/// wildcards at the first level.
+///
+/// ```rust
+/// use ntex_mqtt::TopicMatcher;
+///
+/// let matcher = TopicMatcher::new("sport/+/player1").unwrap();
+/// assert!(matcher.matches("sport/tennis/player1"));
+/// assert!(!matcher.matches("sport/tennis/player2"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicMatcher {
+    filter: ByteString,
+}
+
+impl TopicMatcher {
+    /// Create new matcher from topic filter
+    pub fn new(filter: &str) -> Result<Self, TopicFilterError> {
+        Self::from_filter(ByteString::from(filter))
+    }
+
+    pub(crate) fn from_filter(filter: ByteString) -> Result<Self, TopicFilterError> {
+        Ok(TopicMatcher { filter: validate_topic_filter(filter)? })
+    }
+
+    /// Topic filter
+    pub fn filter(&self) -> &ByteString {
+        &self.filter
+    }
+
+    /// Check if topic name matches filter
+    pub fn matches(&self, topic: &str) -> bool {
+        matches_filter(&self.filter, topic)
+    }
+}
+
+/// Check if topic name matches valid topic filter
+pub(crate) fn matches_filter(filter: &str, topic: &str) -> bool {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return false;
+    }
+    if is_metadata(topic) && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(lhs), Some(rhs)) if lhs == rhs => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+impl fmt::Display for TopicMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.filter)
+    }
+}
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Level {
     Normal(String),
@@ -328,6 +431,64 @@ impl<W: io::Write + ?Sized> WriteTopicExt for W {}
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate_topic_filter() {
+        for filter in ["a", "a/b", "+", "#", "a/+/b", "a/#", "+/+/#", "$SYS/#", "/", "a//b"] {
+            assert!(validate_topic_filter(filter.into()).is_ok(), "{}", filter);
+        }
+        for filter in ["", "a/#/b", "#/a", "a#", "a/b+", "+a/b", "a/\0"] {
+            assert_eq!(
+                validate_topic_filter(filter.into()),
+                Err(TopicFilterError::InvalidFilter(filter.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_topic_matcher() {
+        let m = |filter: &str, topic: &str| TopicMatcher::new(filter).unwrap().matches(topic);
+
+        assert!(m("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(m("sport/tennis/player1/#", "sport/tennis/player1/ranking"));
+        assert!(m("sport/tennis/player1/#", "sport/tennis/player1/score/wimbledon"));
+        assert!(m("sport/#", "sport"));
+        assert!(m("#", "sport/tennis"));
+        assert!(m("#", "/"));
+
+        assert!(m("sport/tennis/+", "sport/tennis/player1"));
+        assert!(!m("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(!m("sport/+", "sport"));
+        assert!(m("sport/+", "sport/"));
+        assert!(m("+/+", "/finance"));
+        assert!(m("/+", "/finance"));
+        assert!(!m("+", "/finance"));
+        assert!(m("+/tennis/#", "sport/tennis"));
+
+        assert!(m("sport/tennis", "sport/tennis"));
+        assert!(!m("sport/tennis", "sport/tennis/"));
+        assert!(!m("sport/tennis", "Sport/tennis"));
+        assert!(!m("a//b", "a/b"));
+
+        assert!(!m("#", "$SYS"));
+        assert!(!m("#", "$SYS/monitor/Clients"));
+        assert!(!m("+/monitor/Clients", "$SYS/monitor/Clients"));
+        assert!(m("$SYS/#", "$SYS/"));
+        assert!(m("$SYS/monitor/+", "$SYS/monitor/Clients"));
+        assert!(m("a/+", "a/$b"));
+
+        assert!(!m("#", ""));
+        assert!(!m("sport/+", "sport/+"));
+        assert!(!m("#", "sport/#"));
+
+        for filter in ["", "a/#/b", "a#", "a/b+"] {
+            assert_eq!(
+                TopicMatcher::new(filter),
+                Err(TopicFilterError::InvalidFilter(filter.into()))
+            );
+        }
+        assert_eq!(TopicMatcher::new("a/+").unwrap().to_string(), "a/+");
+    }
+
     #[test]
     fn test_level() {
         assert!(Level::normal("sport").is_normal());
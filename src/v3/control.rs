@@ -208,6 +208,17 @@ impl Subscribe {
             }),
         }
     }
+
+    /// Ack Subscribe packet with return codes
+    ///
+    /// Codes are applied to topic filters in order, filters without
+    /// a code keep current status.
+    pub fn ack_with(mut self, codes: Vec<codec::SubscribeReturnCode>) -> ControlResult {
+        for (status, code) in self.codes.iter_mut().zip(codes) {
+            *status = code;
+        }
+        self.ack()
+    }
 }
 
 impl<'a> IntoIterator for &'a mut Subscribe {
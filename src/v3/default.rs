@@ -6,6 +6,7 @@ use ntex::util::Ready;
 use super::control::{ControlMessage, ControlResult, ControlResultKind};
 use super::publish::Publish;
 use super::Session;
+use crate::types::QoS;
 
 /// Default publish service
 pub struct DefaultPublishService<St, Err> {
@@ -80,12 +81,22 @@ impl<S, E: fmt::Debug> Service<ControlMessage<E>> for DefaultControlService<S, E
 
     #[inline]
     fn call(&self, pkt: ControlMessage<E>) -> Self::Future {
-        log::warn!("MQTT3 Subscribe is not supported");
-
         Ready::Ok(match pkt {
             ControlMessage::Ping(ping) => ping.ack(),
             ControlMessage::Disconnect(disc) => disc.ack(),
             ControlMessage::Closed(msg) => msg.ack(),
+            ControlMessage::Subscribe(mut msg) => {
+                // accept all subscriptions, granted qos is limited to QoS 1
+                for mut sub in &mut msg {
+                    let qos = match sub.qos() {
+                        QoS::ExactlyOnce => QoS::AtLeastOnce,
+                        qos => qos,
+                    };
+                    sub.confirm(qos);
+                }
+                msg.ack()
+            }
+            ControlMessage::Unsubscribe(msg) => msg.ack(),
             _ => {
                 log::warn!("MQTT3 Control service is not configured, pkt: {:?}", pkt);
                 ControlResult { result: ControlResultKind::Disconnect }
@@ -1,4 +1,4 @@
-use std::{mem, num::NonZeroU16};
+use std::{mem, num::NonZeroU16, str::Utf8Error};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
@@ -32,6 +32,7 @@ impl Publish {
     }
 
     #[inline]
+    /// the message should be retained by the server, or was sent as retained message.
     pub fn retain(&self) -> bool {
         self.publish.retain
     }
@@ -80,6 +81,20 @@ impl Publish {
         &self.publish.payload
     }
 
+    /// Interpret payload as utf-8 text.
+    ///
+    /// ```rust
+    /// fn handle(publish: &ntex_mqtt::v3::Publish) {
+    ///     match publish.payload_as_str() {
+    ///         Ok(text) => println!("text message: {}", text),
+    ///         Err(e) => println!("non utf-8 payload: {}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn payload_as_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.publish.payload)
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.publish.payload)
@@ -90,6 +105,20 @@ impl Publish {
         serde_json::from_slice(&self.publish.payload)
     }
 
+    /// Convert to MQTT 5 publish message with empty properties, payload is not copied.
+    pub fn into_v5(self) -> crate::v5::Publish {
+        let codec::Publish { dup, retain, qos, topic, packet_id, payload } = self.publish;
+        crate::v5::Publish::new(crate::v5::codec::Publish {
+            dup,
+            retain,
+            qos,
+            topic,
+            packet_id,
+            payload,
+            properties: crate::v5::codec::PublishProperties::default(),
+        })
+    }
+
     pub(super) fn into_inner(self) -> codec::Publish {
         self.publish
     }
@@ -3,7 +3,9 @@ use std::{future::Future, pin::Pin, rc::Rc};
 
 use ntex::router::{IntoPattern, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
+use ntex::service::{fn_factory_with_config, fn_service};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::Ready;
 
 use super::publish::Publish;
 use super::Session;
@@ -13,6 +15,9 @@ type HandlerService<E> = BoxService<Publish, (), E>;
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
+///
+/// Router is not `Send`, like all ntex services it is created per worker
+/// thread, inside of server factory closure.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
     handlers: Vec<Handler<S, Err>>,
@@ -39,6 +44,19 @@ where
         }
     }
 
+    /// Replace default service.
+    ///
+    /// Previously configured default service is dropped.
+    pub fn set_default<F, U>(mut self, service: F) -> Self
+    where
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = (), Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        self.default = boxed::factory(service.into_factory().map_init_err(Err::from));
+        self
+    }
+
     /// Configure mqtt resource for a specific topic.
     pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
     where
@@ -51,6 +69,47 @@ where
         self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
         self
     }
+
+    /// Configure mqtt resource for a specific topic with async function.
+    pub fn resource_fn<T, F, Fut>(self, address: T, f: F) -> Self
+    where
+        T: IntoPattern,
+        F: Fn(Publish) -> Fut + Clone + 'static,
+        Fut: Future<Output = Result<(), Err>> + 'static,
+    {
+        self.resource(
+            address,
+            fn_factory_with_config(move |_: Session<S>| Ready::Ok(fn_service(f.clone()))),
+        )
+    }
+
+    /// Configure mqtt resource for a set of topics.
+    ///
+    /// All topics are handled by the same service instance.
+    pub fn resources<T, P, F, U>(mut self, addresses: T, service: F) -> Self
+    where
+        T: IntoIterator<Item = P>,
+        P: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = (), Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let idx = self.handlers.len();
+        for address in addresses {
+            self.router.path(address, idx);
+        }
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Finish router configuration and create router service factory
+    pub fn finish(self) -> RouterFactory<S, Err> {
+        RouterFactory {
+            router: Rc::new(self.router.finish()),
+            handlers: self.handlers,
+            default: self.default,
+        }
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>, Publish, Session<S>> for Router<S, Err>
@@ -59,11 +118,7 @@ where
     Err: 'static,
 {
     fn into_factory(self) -> RouterFactory<S, Err> {
-        RouterFactory {
-            router: Rc::new(self.router.finish()),
-            handlers: self.handlers,
-            default: self.default,
-        }
+        self.finish()
     }
 }
 
@@ -133,8 +188,19 @@ impl<Err> Service<Publish> for RouterService<Err> {
 
     fn call(&self, mut req: Publish) -> Self::Future {
         if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            log::trace!(
+                "Router matched topic {:?} to handler index {}, packet id: {:?}",
+                req.publish_topic(),
+                idx,
+                req.id()
+            );
             self.handlers[*idx].call(req)
         } else {
+            log::trace!(
+                "Router dispatching to default service for topic {:?}, packet id: {:?}",
+                req.publish_topic(),
+                req.id()
+            );
             self.default.call(req)
         }
     }
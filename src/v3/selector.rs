@@ -6,6 +6,7 @@ use ntex::time::{Deadline, Millis, Seconds};
 use ntex::util::{select, Either};
 
 use crate::error::{MqttError, ProtocolError};
+use crate::proxy_protocol;
 
 use super::control::{ControlMessage, ControlResult};
 use super::handshake::{Handshake, HandshakeAck};
@@ -27,6 +28,7 @@ pub struct Selector<Err, InitErr> {
     servers: Vec<ServerFactory<Err, InitErr>>,
     max_size: u32,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Err, InitErr)>,
 }
@@ -38,6 +40,7 @@ impl<Err, InitErr> Selector<Err, InitErr> {
             servers: Vec::new(),
             max_size: 0,
             handshake_timeout: Millis(10000),
+            proxy_protocol: false,
             pool: Default::default(),
             _t: marker::PhantomData,
         }
@@ -68,6 +71,9 @@ where
     }
 
     /// Add server variant
+    ///
+    /// PROXY protocol header is expected on new connections if any variant
+    /// has it enabled, see `MqttServer::proxy_protocol()`.
     pub fn variant<F, R, St, C, Cn, P>(
         mut self,
         check: F,
@@ -93,6 +99,7 @@ where
             + fmt::Debug,
     {
         server.pool = self.pool.clone();
+        self.proxy_protocol |= server.proxy_protocol;
         self.servers.push(boxed::factory(server.finish_selector(check)));
         self
     }
@@ -107,6 +114,7 @@ where
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
         let pool = self.pool.clone();
 
         async move {
@@ -114,7 +122,13 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService { max_size, handshake_timeout, pool, servers: Rc::new(servers) })
+            Ok(SelectorService {
+                max_size,
+                handshake_timeout,
+                proxy_protocol,
+                pool,
+                servers: Rc::new(servers),
+            })
         }
     }
 }
@@ -172,6 +186,7 @@ pub struct SelectorService<Err> {
     servers: Rc<Vec<Server<Err>>>,
     max_size: u32,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -243,11 +258,17 @@ where
             16,
             self.pool.clone(),
         ));
+        let proxy_protocol = self.proxy_protocol;
         let mut timeout = Deadline::new(self.handshake_timeout);
 
         Box::pin(async move {
             // read first packet
             let result = select(&mut timeout, async {
+                // strip proxy protocol header
+                if proxy_protocol {
+                    shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+                }
+
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
@@ -320,10 +341,16 @@ where
             16,
             self.pool.clone(),
         ));
+        let proxy_protocol = self.proxy_protocol;
 
         Box::pin(async move {
             // read first packet
             let result = select(&mut timeout, async {
+                // strip proxy protocol header
+                if proxy_protocol {
+                    shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+                }
+
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
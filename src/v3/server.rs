@@ -7,7 +7,7 @@ use ntex::time::{timeout_checked, Millis, Seconds};
 use ntex::util::{select, Either};
 
 use crate::error::{MqttError, ProtocolError};
-use crate::{io::Dispatcher, service};
+use crate::{io::Dispatcher, proxy_protocol, service};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -52,6 +52,7 @@ pub struct MqttServer<St, H, C, P> {
     max_inflight_size: usize,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    pub(super) proxy_protocol: bool,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -77,6 +78,7 @@ where
             max_inflight_size: 65535,
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            proxy_protocol: false,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -102,6 +104,17 @@ where
         self
     }
 
+    /// Expect PROXY protocol v1/v2 header on new connections.
+    ///
+    /// Header is stripped before mqtt handshake, client address from header is
+    /// returned by `Session::peer_addr()`. Connections without header are rejected.
+    /// If server is used as `Selector` variant, selector strips the header for all
+    /// variants. By default PROXY protocol is disabled.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -160,6 +173,7 @@ where
             max_inflight_size: self.max_inflight_size,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            proxy_protocol: self.proxy_protocol,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -181,6 +195,7 @@ where
             max_inflight_size: self.max_inflight_size,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            proxy_protocol: self.proxy_protocol,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -211,6 +226,7 @@ where
                 factory: self.handshake,
                 max_size: self.max_size,
                 handshake_timeout: self.handshake_timeout,
+                proxy_protocol: self.proxy_protocol,
                 pool: self.pool.clone(),
                 _t: PhantomData,
             },
@@ -253,6 +269,7 @@ struct HandshakeFactory<St, H> {
     factory: H,
     max_size: u32,
     handshake_timeout: Seconds,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -274,6 +291,7 @@ where
         let max_size = self.max_size;
         let pool = self.pool.clone();
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
 
         Box::pin(async move {
             let service = fut.await?;
@@ -282,6 +300,7 @@ where
                 pool,
                 service: Rc::new(service),
                 handshake_timeout: handshake_timeout.into(),
+                proxy_protocol,
                 _t: PhantomData,
             })
         })
@@ -293,6 +312,7 @@ struct HandshakeService<St, H> {
     max_size: u32,
     pool: Rc<MqttSinkPool>,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     _t: PhantomData<St>,
 }
 
@@ -324,8 +344,14 @@ where
             self.pool.clone(),
         ));
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
 
         let f = async move {
+            // strip proxy protocol header
+            if proxy_protocol {
+                shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+            }
+
             // read first packet
             let packet = io
                 .recv(&shared.codec)
@@ -1,4 +1,5 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::cell::{Cell, RefCell, RefMut};
+use std::{collections::VecDeque, fmt, net::SocketAddr, num::NonZeroU16, rc::Rc};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
@@ -44,6 +45,8 @@ pub struct MqttShared {
     pub(super) inflight_idx: Cell<u16>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) codec: codec::Codec,
+    /// Client address from PROXY protocol header
+    pub(super) proxy_addr: Cell<Option<SocketAddr>>,
 }
 
 pub(super) struct MqttSharedQueues {
@@ -70,29 +73,57 @@ impl MqttShared {
                 waiters: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            proxy_addr: Cell::new(None),
         }
     }
 
+    /// Run closure with mutable access to queues
+    ///
+    /// Queues must not be accessed from within the closure, nested borrow panics.
+    /// If the closure panics, the panic is propagated and the borrow is released
+    /// during unwinding.
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
-        let mut queues = self.queues.borrow_mut();
-        f(&mut queues)
+        f(&mut self.queues_mut())
+    }
+
+    /// Mutable borrow guard for queues, allows several operations under one borrow
+    ///
+    /// Guard must be dropped before any other queues access, including `with_queues()`.
+    pub(super) fn queues_mut(&self) -> RefMut<'_, MqttSharedQueues> {
+        self.queues.borrow_mut()
     }
 
     pub(super) fn has_credit(&self) -> bool {
         self.cap.get() - self.queues.borrow().inflight.len() > 0
     }
 
-    pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+    /// Allocate next free packet id, packet id 0 is skipped
+    pub(super) fn next_id(&self) -> Result<NonZeroU16, EncodeError> {
+        let queues = self.queues.borrow();
+        for _ in 0..u16::MAX {
+            let idx = NonZeroU16::new(self.inflight_idx.get().wrapping_add(1))
+                .unwrap_or_else(|| NonZeroU16::new(1).unwrap());
+            self.inflight_idx.set(idx.get());
+            if !queues.inflight.contains_key(&idx.get()) {
+                return Ok(idx);
+            }
         }
+        Err(EncodeError::PacketIdsExhausted)
     }
 }
+
+impl fmt::Debug for MqttShared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("MqttShared");
+        f.field("cap", &self.cap.get()).field("closed", &self.io.is_closed());
+        match self.queues.try_borrow() {
+            Ok(q) => f.field("inflight", &q.inflight.len()),
+            Err(_) => f.field("inflight", &format_args!("<borrowed>")),
+        };
+        f.finish()
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = EncodeError;
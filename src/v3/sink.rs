@@ -1,11 +1,18 @@
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{fmt, net::SocketAddr, num::NonZeroU16, rc::Rc};
 
-use ntex::time::{ Millis, timeout};
+use ntex::io::types::PeerAddr;
+use ntex::time::{sleep, timeout, Millis};
 use ntex::util::{poll_fn, ByteString, Bytes, Either, Ready};
 
 use super::shared::{Ack, AckType, MqttShared};
 use super::{codec, error::ProtocolError, error::SendPacketError};
+use crate::topic::{validate_topic_filter, TopicFilterError};
+
+/// Ignored message expiry is logged once per process
+static EXPIRY_IGNORED: AtomicBool = AtomicBool::new(false);
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -15,16 +22,82 @@ impl Clone for MqttSink {
     }
 }
 
+impl PartialEq for MqttSink {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MqttSink {}
+
+impl Hash for MqttSink {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state)
+    }
+}
+
 impl MqttSink {
     pub(crate) fn new(state: Rc<MqttShared>) -> Self {
         MqttSink(state)
     }
 
+    /// Get connection identifier
+    ///
+    /// Identifier is stable for the lifetime of the connection and is shared
+    /// by all clones of the sink. Value is only meaningful within current
+    /// process and must not be persisted.
+    pub fn connection_id(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    /// Peer address, if transport provides it
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0
+            .proxy_addr
+            .get()
+            .or_else(|| self.0.io.query::<PeerAddr>().get().map(PeerAddr::into_inner))
+    }
+
     /// Get client receive credit
     pub fn credit(&self) -> usize {
         self.0.cap.get() - self.0.with_queues(|q| q.inflight.len())
     }
 
+    /// Get max number of in-flight packets
+    pub fn max_inflight(&self) -> usize {
+        self.0.cap.get()
+    }
+
+    /// Get number of in-flight packets
+    pub fn inflight_count(&self) -> usize {
+        if self.0.io.is_closed() {
+            0
+        } else {
+            self.0.with_queues(|q| q.inflight.len())
+        }
+    }
+
+    /// Get sorted list of in-flight packet ids
+    pub fn inflight_packet_ids(&self) -> Vec<u16> {
+        let mut ids: Vec<_> = self.0.with_queues(|q| q.inflight.keys().copied().collect());
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Get in-flight packet ids in the order acks are expected
+    pub fn inflight_order(&self) -> Vec<u16> {
+        self.0.with_queues(|q| q.inflight_order.iter().copied().filter(|id| *id != 0).collect())
+    }
+
+    /// Get number of publish/subscribe requests waiting for credit
+    pub fn waiter_count(&self) -> usize {
+        if self.0.io.is_closed() {
+            0
+        } else {
+            self.0.with_queues(|q| q.waiters.len())
+        }
+    }
+
     /// Get notification when packet could be send to the peer.
     ///
     /// Result indicates if connection is alive
@@ -46,6 +119,33 @@ impl MqttSink {
         }
     }
 
+    /// Wait until write buffer is flushed to the peer
+    ///
+    /// `close()` flushes write buffer on its own, `flush()` is useful
+    /// for fire-and-forget publishes that need to reach the socket before
+    /// sink does more work.
+    ///
+    /// Write buffer is polled every millisecond, sink holds `IoRef` only and
+    /// cannot register flush waker without taking over dispatcher's one.
+    /// Only top level filter's buffer is checked, data buffered by lower
+    /// filters (i.e. TLS) could still be pending when future resolves.
+    pub async fn flush(&self) -> Result<(), SendPacketError> {
+        loop {
+            if self.0.io.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            let len = self
+                .0
+                .io
+                .with_write_buf(|buf| buf.len())
+                .map_err(|_| SendPacketError::Disconnected)?;
+            if len == 0 {
+                return Ok(());
+            }
+            sleep(Millis(1)).await;
+        }
+    }
+
     /// Close mqtt connection
     pub fn close(&self) {
         self.0.io.close();
@@ -55,6 +155,14 @@ impl MqttSink {
         });
     }
 
+    /// Close mqtt connection with reason
+    ///
+    /// MQTT v3.1.1 disconnect packet does not carry reason, it is only logged
+    pub fn close_with_reason(&self, reason: ByteString) {
+        log::trace!("Close mqtt connection, reason: {}", reason);
+        self.close()
+    }
+
     /// Force close mqtt connection. mqtt dispatcher does not wait for uncompleted
     /// responses, but it flushes buffers.
     pub fn force_close(&self) {
@@ -71,9 +179,16 @@ impl MqttSink {
     }
 
     /// Create publish message builder
+    ///
+    /// ```rust,no_run
+    /// # use ntex::util::Bytes;
+    /// # async fn publish(sink: ntex_mqtt::v3::MqttSink) {
+    /// sink.publish("my/topic", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+    /// # }
+    /// ```
     pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
     where
-        ByteString: From<U>,
+        U: Into<ByteString>,
     {
         PublishBuilder {
             packet: codec::Publish {
@@ -88,9 +203,28 @@ impl MqttSink {
         }
     }
 
-    /// Create subscribe packet builder
+    /// Create publish message builder with message expiry interval
     ///
-    /// panics if id is 0
+    /// MQTT v3.1.1 does not support message expiry, expiry value is ignored
+    pub fn publish_with_expiry<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        expiry_secs: u32,
+    ) -> PublishBuilder
+    where
+        U: Into<ByteString>,
+    {
+        if !EXPIRY_IGNORED.swap(true, Ordering::Relaxed) {
+            log::debug!(
+                "Message expiry is not supported by MQTT v3.1.1, ignore: {}s",
+                expiry_secs
+            );
+        }
+        self.publish(topic, payload)
+    }
+
+    /// Create subscribe packet builder
     pub fn subscribe(&self) -> SubscribeBuilder {
         SubscribeBuilder { id: 0, topic_filters: Vec::new(), shared: self.0.clone() }
     }
@@ -136,8 +270,11 @@ impl MqttSink {
                     }
                 }
             } else {
-                log::trace!("Unexpected PublishAck packet: {:?}", pkt.packet_id());
-                Ok(())
+                if !queues.inflight.is_empty() {
+                    log::warn!("In-flight state inconsistency, ack order queue is empty");
+                }
+                log::trace!("Unexpected ack packet: {:?}", pkt.packet_id());
+                Err(ProtocolError::UnexpectedAck(pkt.packet_id()))
             }
         });
         result.map_err(|e| {
@@ -149,15 +286,22 @@ impl MqttSink {
 
 impl fmt::Debug for MqttSink {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("MqttSink").finish()
+        fmt.debug_tuple("MqttSink").field(&self.0).finish()
     }
 }
 
+#[derive(Clone)]
 pub struct PublishBuilder {
     packet: codec::Publish,
     shared: Rc<MqttShared>,
 }
 
+impl fmt::Debug for PublishBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PublishBuilder").field("packet", &self.packet).finish()
+    }
+}
+
 impl PublishBuilder {
     /// Set packet id.
     ///
@@ -165,9 +309,27 @@ impl PublishBuilder {
     /// Packet id management should not be mixed, it should be auto-generated
     /// or set by user. Otherwise collisions could occure.
     ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        let id = NonZeroU16::new(id).expect("id 0 is not allowed");
+    /// Packet id could not be 0, such id is rejected at compile time.
+    ///
+    /// ```rust
+    /// use std::num::NonZeroU16;
+    /// # use ntex::util::Bytes;
+    ///
+    /// # fn test(sink: &ntex_mqtt::v3::MqttSink) {
+    /// assert!(NonZeroU16::new(0).is_none());
+    ///
+    /// let id = NonZeroU16::new(1).unwrap();
+    /// let builder = sink.publish("topic", Bytes::new()).packet_id(id);
+    /// # }
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use ntex::util::Bytes;
+    /// # fn test(sink: &ntex_mqtt::v3::MqttSink) {
+    /// let builder = sink.publish("topic", Bytes::new()).packet_id(0);
+    /// # }
+    /// ```
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
         self.packet.packet_id = Some(id);
         self
     }
@@ -183,6 +345,18 @@ impl PublishBuilder {
         self
     }
 
+    /// Set publish topic
+    ///
+    /// Could be used with cloned builder for publishing same message
+    /// to multiple topics.
+    pub fn topic<U>(mut self, topic: U) -> Self
+    where
+        U: Into<ByteString>,
+    {
+        self.packet.topic = topic.into();
+        self
+    }
+
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
         let packet = self.packet;
@@ -234,16 +408,20 @@ impl PublishBuilder {
         shared: Rc<MqttShared>,
         _timeout: Millis,
     ) -> impl Future<Output = Result<(), SendPacketError>> {
+        // packet id
+        let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+        if idx == 0 {
+            match shared.next_id() {
+                Ok(id) => idx = id.get(),
+                Err(e) => return Either::Left(Ready::Err(SendPacketError::Encode(e))),
+            }
+            packet.packet_id = NonZeroU16::new(idx);
+        }
+
         let rx = shared.with_queues(|queues| {
             // publish ack channel
             let (tx, rx) = shared.pool.queue.channel();
 
-            // packet id
-            let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
-            if idx == 0 {
-                idx = shared.next_id();
-                packet.packet_id = NonZeroU16::new(idx);
-            }
             if queues.inflight.contains_key(&idx) {
                 return Err(SendPacketError::PacketIdInUse(idx));
             }
@@ -295,21 +473,49 @@ pub struct SubscribeBuilder {
     topic_filters: Vec<(ByteString, codec::QoS)>,
 }
 
+impl fmt::Debug for SubscribeBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SubscribeBuilder")
+            .field("id", &self.id)
+            .field("topic_filters", &self.topic_filters)
+            .finish()
+    }
+}
+
 impl SubscribeBuilder {
     /// Set packet id.
-    ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        if id == 0 {
-            panic!("id 0 is not allowed");
-        }
-        self.id = id;
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
+        self.id = id.get();
         self
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString, qos: codec::QoS) -> Self {
-        self.topic_filters.push((filter, qos));
+    pub fn topic_filter<U: Into<ByteString>>(mut self, filter: U, qos: codec::QoS) -> Self {
+        self.topic_filters.push((filter.into(), qos));
+        self
+    }
+
+    /// Add topic filter, filter gets validated
+    pub fn try_topic_filter<U: Into<ByteString>>(
+        self,
+        filter: U,
+        qos: codec::QoS,
+    ) -> Result<Self, TopicFilterError> {
+        Ok(self.topic_filter(validate_topic_filter(filter.into())?, qos))
+    }
+
+    /// Add multiple topic filters
+    pub fn topic_filters<I>(mut self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = (ByteString, codec::QoS)>,
+    {
+        self.topic_filters.extend(filters);
+        self
+    }
+
+    /// Remove all topic filters
+    pub fn clear_topic_filters(mut self) -> Self {
+        self.topic_filters.clear();
         self
     }
 
@@ -329,7 +535,11 @@ impl SubscribeBuilder {
                     return Err(SendPacketError::Disconnected);
                 }
             }
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
+            let idx = if self.id == 0 {
+                shared.next_id().map_err(SendPacketError::Encode)?.get()
+            } else {
+                self.id
+            };
             let rx = shared.with_queues(|queues| {
                 // ack channel
                 let (tx, rx) = shared.clone().pool.queue.channel();
@@ -374,21 +584,48 @@ pub struct UnsubscribeBuilder {
     topic_filters: Vec<ByteString>,
 }
 
+impl fmt::Debug for UnsubscribeBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("UnsubscribeBuilder")
+            .field("id", &self.id)
+            .field("topic_filters", &self.topic_filters)
+            .finish()
+    }
+}
+
 impl UnsubscribeBuilder {
     /// Set packet id.
-    ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        if id == 0 {
-            panic!("id 0 is not allowed");
-        }
-        self.id = id;
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
+        self.id = id.get();
         self
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString) -> Self {
-        self.topic_filters.push(filter);
+    pub fn topic_filter<U: Into<ByteString>>(mut self, filter: U) -> Self {
+        self.topic_filters.push(filter.into());
+        self
+    }
+
+    /// Add topic filter, filter gets validated
+    pub fn try_topic_filter<U: Into<ByteString>>(
+        self,
+        filter: U,
+    ) -> Result<Self, TopicFilterError> {
+        Ok(self.topic_filter(validate_topic_filter(filter.into())?))
+    }
+
+    /// Add multiple topic filters
+    pub fn topic_filters<I>(mut self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = ByteString>,
+    {
+        self.topic_filters.extend(filters);
+        self
+    }
+
+    /// Remove all topic filters
+    pub fn clear_topic_filters(mut self) -> Self {
+        self.topic_filters.clear();
         self
     }
 
@@ -408,7 +645,11 @@ impl UnsubscribeBuilder {
                     return Err(SendPacketError::Disconnected);
                 }
             }
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
+            let idx = if self.id == 0 {
+                shared.next_id().map_err(SendPacketError::Encode)?.get()
+            } else {
+                self.id
+            };
             let rx = shared.with_queues(|queues| {
                 // ack channel
                 let (tx, rx) = shared.pool.queue.channel();
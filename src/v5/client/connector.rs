@@ -232,12 +232,14 @@ where
                     ClientError::Disconnected(None)
                 })?;
 
-            let shared = Rc::new(MqttShared::new(io.get_ref(), codec, 0, pool));
-
             match packet {
                 codec::Packet::ConnectAck(pkt) => {
                     log::trace!("Connect ack response from server: {:#?}", pkt);
                     if pkt.reason_code == codec::ConnectAckReason::Success {
+                        let mut shared = MqttShared::new(io.get_ref(), codec, 0, pool);
+                        shared.server_properties = Some(pkt.clone());
+                        let shared = Rc::new(shared);
+
                         // set max outbound (encoder) packet size
                         if let Some(size) = pkt.max_packet_size {
                             shared.codec.set_max_outbound_size(size);
@@ -248,6 +250,7 @@ where
                         shared
                             .cap
                             .set(pkt.receive_max.map(|v| v.get()).unwrap_or(65535) as usize);
+                        shared.topic_alias_max.set(pkt.topic_alias_max);
 
                         Ok(Client::new(
                             io,
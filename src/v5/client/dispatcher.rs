@@ -214,19 +214,32 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            DispatchItem::Item(codec::Packet::PublishRelease(pubrel)) => {
-                if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish2(pubrel.clone())) {
+            DispatchItem::Item(codec::Packet::PublishReceived(pubrec)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(pubrec)) {
                     Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(err),
                         &self.inner,
                     )))
                 } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(pubcomp)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish2(pubcomp)) {
                     Either::Right(Either::Right(ControlResponse::new(
-                        ControlMessage::pubrel(pubrel),
+                        ControlMessage::proto_error(err),
                         &self.inner,
                     )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishRelease(pubrel)) => {
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::pubrel(pubrel),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -281,6 +294,7 @@ where
                 )))
             }
             DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.pkt_ping_response();
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
             DispatchItem::Item(pkt) => {
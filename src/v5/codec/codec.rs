@@ -3,7 +3,7 @@ use std::cell::Cell;
 use ntex::codec::{Decoder, Encoder};
 use ntex::util::{Buf, BytesMut};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
+use super::{decode::decode_packet, encode::var_int_len, encode::EncodeLtd, Packet};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{FixedHeader, MAX_PACKET_SIZE};
 use crate::utils::decode_variable_length;
@@ -52,11 +52,7 @@ impl Codec {
     ///
     /// If max size is set to `0`, size is unlimited.
     /// By default max size is set to `0`
-    pub fn max_outbound_size(self, mut size: u32) -> Self {
-        if size > 5 {
-            // fixed header = 1, var_len(remaining.max_value()) = 4
-            size -= 5;
-        }
+    pub fn max_outbound_size(self, size: u32) -> Self {
         self.max_out_size.set(size);
         self
     }
@@ -76,6 +72,34 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Get max inbound frame size, `0` means unlimited
+    pub fn get_max_inbound_size(&self) -> u32 {
+        self.max_in_size.get()
+    }
+
+    /// Get max outbound frame size, `0` means unlimited
+    pub fn get_max_outbound_size(&self) -> u32 {
+        self.max_out_size.get()
+    }
+
+    /// Check packet against max outbound frame size, returns encoded content size
+    pub(crate) fn outbound_size<T: EncodeLtd>(&self, item: &T) -> Result<usize, EncodeError> {
+        let max_out_size = self.max_out_size.get();
+        let (max, limit) = if max_out_size != 0 {
+            // fixed header = 1, var_len(remaining.max_value()) = 4
+            (max_out_size as usize, max_out_size.saturating_sub(5))
+        } else {
+            (MAX_PACKET_SIZE as usize + 5, MAX_PACKET_SIZE)
+        };
+        let content_size = item.encoded_size(limit);
+        let size = 1 + var_int_len(content_size) as usize + content_size;
+        if size > max || content_size > MAX_PACKET_SIZE as usize {
+            Err(EncodeError::PacketTooLarge { size, max })
+        } else {
+            Ok(content_size)
+        }
+    }
 }
 
 impl Default for Codec {
@@ -186,12 +210,7 @@ impl Encoder for Codec {
             }
         }
 
-        let max_out_size = self.max_out_size.get();
-        let max_size = if max_out_size != 0 { max_out_size } else { MAX_PACKET_SIZE };
-        let content_size = item.encoded_size(max_size);
-        if content_size > max_size as usize {
-            return Err(EncodeError::InvalidLength); // todo: separate error code
-        }
+        let content_size = self.outbound_size(&item)?;
         dst.reserve(content_size + 5);
         item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
         Ok(())
@@ -209,4 +228,35 @@ mod tests {
         buf.extend_from_slice(b"\0\x09");
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
+
+    #[test]
+    fn test_max_outbound_size() {
+        let publish = |size| {
+            Packet::Publish(super::super::Publish {
+                dup: false,
+                retain: false,
+                qos: crate::types::QoS::AtMostOnce,
+                topic: "test".into(),
+                packet_id: None,
+                payload: vec![0; size].into(),
+                properties: Default::default(),
+            })
+        };
+        let codec = Codec::new().max_outbound_size(100);
+        let mut buf = BytesMut::new();
+
+        assert_eq!(
+            codec.encode(publish(200), &mut buf),
+            Err(EncodeError::PacketTooLarge { size: 210, max: 100 })
+        );
+        assert!(buf.is_empty());
+
+        // fixed header 2 bytes, topic 6 bytes, properties 1 byte
+        codec.encode(publish(91), &mut buf).unwrap();
+        assert_eq!(buf.len(), 100);
+        assert_eq!(
+            codec.encode(publish(92), &mut buf),
+            Err(EncodeError::PacketTooLarge { size: 101, max: 100 })
+        );
+    }
 }
@@ -17,7 +17,7 @@ impl EncodeLtd for Packet {
         // limit -= 5; // fixed header = 1, var_len(remaining.max_value()) = 4
         match self {
             Packet::Connect(connect) => connect.encoded_size(limit),
-            Packet::Publish(publish) => publish.encoded_size(limit),
+            Packet::Publish(publish) => EncodeLtd::encoded_size(publish, limit),
             Packet::ConnectAck(ack) => ack.encoded_size(limit),
             Packet::PublishAck(ack) | Packet::PublishReceived(ack) => ack.encoded_size(limit),
             Packet::PublishRelease(ack) | Packet::PublishComplete(ack) => {
@@ -307,6 +307,89 @@ mod tests {
         assert_eq!(&v[..3], b"\x3d\x89\x02".as_ref());
     }
 
+    #[test]
+    fn test_publish_validate() {
+        use crate::error::PublishValidationError as E;
+
+        let pkt = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("topic"),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: PublishProperties::default(),
+        };
+        assert_eq!(pkt.validate(), Ok(()));
+        assert_eq!(Publish { topic: "".into(), ..pkt.clone() }.validate(), Err(E::EmptyTopic));
+        let mut alias = Publish { topic: "".into(), ..pkt.clone() };
+        alias.properties.topic_alias = Some(packet_id(1));
+        assert_eq!(alias.validate(), Ok(()));
+        assert_eq!(
+            Publish { topic: "a/+".into(), ..pkt.clone() }.validate(),
+            Err(E::TopicWildcard)
+        );
+        assert_eq!(
+            Publish { topic: "#".into(), ..pkt.clone() }.validate(),
+            Err(E::TopicWildcard)
+        );
+        assert_eq!(
+            Publish { packet_id: Some(packet_id(1)), ..pkt.clone() }.validate(),
+            Err(E::UnexpectedPacketId)
+        );
+        assert_eq!(Publish { dup: true, ..pkt.clone() }.validate(), Err(E::DupWithQoS0));
+        assert_eq!(
+            Publish { qos: QoS::AtLeastOnce, ..pkt.clone() }.validate(),
+            Err(E::PacketIdRequired)
+        );
+        assert_eq!(
+            Publish { qos: QoS::ExactlyOnce, dup: true, packet_id: Some(packet_id(1)), ..pkt }
+                .validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_publish_encoded_size() {
+        let mut pkt = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("topic"),
+            packet_id: None,
+            payload: Bytes::from_static(b"payload"),
+            properties: PublishProperties::default(),
+        };
+        let sizes = |pkt: &Publish| {
+            let mut buf = BytesMut::with_capacity(1024);
+            Packet::Publish(pkt.clone()).encode(&mut buf, pkt.encoded_size() as u32).unwrap();
+            // fixed header: type byte and remaining length
+            (pkt.encoded_size(), buf.len() - 1 - var_int_len(pkt.encoded_size()) as usize)
+        };
+        let (estimate, actual) = sizes(&pkt);
+        assert_eq!(estimate, actual);
+
+        pkt.qos = QoS::ExactlyOnce;
+        pkt.packet_id = Some(packet_id(1));
+        pkt.payload = (0..200).collect::<Vec<u8>>().into();
+        pkt.properties = PublishProperties {
+            topic_alias: NonZeroU16::new(1),
+            correlation_data: Some(Bytes::from_static(b"data")),
+            message_expiry_interval: NonZeroU32::new(3600),
+            content_type: Some("text".into()),
+            user_properties: vec![("key".into(), "value".into())],
+            is_utf8_payload: Some(true),
+            response_topic: Some("reply".into()),
+            subscription_ids: Some(vec![
+                NonZeroU32::new(1).unwrap(),
+                NonZeroU32::new(200_000).unwrap(),
+            ]),
+        };
+        let (estimate, actual) = sizes(&pkt);
+        assert_eq!(estimate, actual);
+        assert!(estimate > 200);
+    }
+
     fn assert_encode_packet(packet: &Packet, expected: &[u8]) {
         let mut v = BytesMut::with_capacity(1024);
         packet.encode(&mut v, packet.encoded_size(1024) as u32).unwrap();
@@ -372,6 +455,40 @@ mod tests {
 \x0512345\x00\x00\x05topic\x00\x07message"[..],
         );
 
+        assert_encode_packet(
+            &Packet::Connect(Box::new(Connect {
+                clean_start: false,
+                keep_alive: 60,
+                client_id: ByteString::from_static("12345"),
+                last_will: Some(LastWill {
+                    qos: QoS::ExactlyOnce,
+                    retain: false,
+                    topic: ByteString::from_static("topic"),
+                    message: Bytes::from_static(b"message"),
+                    will_delay_interval_sec: Some(5),
+                    correlation_data: None,
+                    message_expiry_interval: None,
+                    content_type: None,
+                    user_properties: vec![],
+                    is_utf8_payload: None,
+                    response_topic: None,
+                }),
+                username: None,
+                password: None,
+                session_expiry_interval_secs: None,
+                auth_method: None,
+                auth_data: None,
+                request_problem_info: true,
+                request_response_info: false,
+                receive_max: None,
+                topic_alias_max: 0,
+                user_properties: vec![],
+                max_packet_size: None,
+            })),
+            &b"\x10\x28\x00\x04MQTT\x05\x14\x00\x3C\x00\x00\
+\x0512345\x05\x18\x00\x00\x00\x05\x00\x05topic\x00\x07message"[..],
+        );
+
         assert_encode_packet(
             &Packet::Disconnect(Disconnect {
                 reason_code: DisconnectReasonCode::NormalDisconnection,
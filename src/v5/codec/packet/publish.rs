@@ -1,8 +1,8 @@
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryFrom, fmt, num::NonZeroU16, num::NonZeroU32};
 
-use crate::error::{DecodeError, EncodeError};
-use crate::types::QoS;
+use crate::error::{DecodeError, EncodeError, PublishValidationError};
+use crate::types::{QoS, MAX_PACKET_SIZE};
 use crate::utils::{self, write_variable_length, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
@@ -48,6 +48,34 @@ pub struct PublishProperties {
 }
 
 impl Publish {
+    /// Check publish packet against protocol constraints
+    pub fn validate(&self) -> Result<(), PublishValidationError> {
+        if self.topic.is_empty() && self.properties.topic_alias.is_none() {
+            return Err(PublishValidationError::EmptyTopic);
+        }
+        if self.topic.contains(['+', '#']) {
+            return Err(PublishValidationError::TopicWildcard);
+        }
+        if self.qos == QoS::AtMostOnce {
+            if self.packet_id.is_some() {
+                return Err(PublishValidationError::UnexpectedPacketId);
+            }
+            if self.dup {
+                return Err(PublishValidationError::DupWithQoS0);
+            }
+        } else if self.packet_id.is_none() {
+            return Err(PublishValidationError::PacketIdRequired);
+        }
+        Ok(())
+    }
+
+    /// Encoded size of variable header and payload, without encoding the packet
+    ///
+    /// Size is exact, fixed header is not included.
+    pub fn encoded_size(&self) -> usize {
+        EncodeLtd::encoded_size(self, MAX_PACKET_SIZE)
+    }
+
     pub(crate) fn decode(mut src: Bytes, packet_flags: u8) -> Result<Self, DecodeError> {
         let topic = ByteString::decode(&mut src)?;
         let qos = QoS::try_from((packet_flags & 0b0110) >> 1)?;
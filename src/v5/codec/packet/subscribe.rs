@@ -1,9 +1,10 @@
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::num::{NonZeroU16, NonZeroU32};
 
 use super::ack_props;
 use crate::error::{DecodeError, EncodeError};
+use crate::topic::{TopicFilterError, TopicMatcher};
 use crate::types::QoS;
 use crate::utils::{self, write_variable_length, Decode, Encode};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
@@ -127,6 +128,15 @@ impl Subscribe {
     }
 }
 
+impl TryFrom<&Subscribe> for Vec<TopicMatcher> {
+    type Error = TopicFilterError;
+
+    /// Validate all topic filters, fails with first invalid filter
+    fn try_from(pkt: &Subscribe) -> Result<Self, Self::Error> {
+        pkt.topic_filters.iter().map(|(f, _)| TopicMatcher::from_filter(f.clone())).collect()
+    }
+}
+
 impl SubscribeAck {
     pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
@@ -162,6 +172,15 @@ impl Unsubscribe {
     }
 }
 
+impl TryFrom<&Unsubscribe> for Vec<TopicMatcher> {
+    type Error = TopicFilterError;
+
+    /// Validate all topic filters, fails with first invalid filter
+    fn try_from(pkt: &Unsubscribe) -> Result<Self, Self::Error> {
+        pkt.topic_filters.iter().map(|f| TopicMatcher::from_filter(f.clone())).collect()
+    }
+}
+
 impl UnsubscribeAck {
     pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
@@ -355,4 +374,42 @@ mod tests {
         ack.encode(&mut buf, size as u32).unwrap();
         assert_eq!(ack, UnsubscribeAck::decode(&mut buf.freeze()).unwrap());
     }
+
+    #[test]
+    fn test_topic_matchers() {
+        let opts = SubscriptionOptions {
+            qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::AtSubscribe,
+        };
+        let mut sub = Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            id: None,
+            user_properties: Vec::new(),
+            topic_filters: vec![("a/+".into(), opts.clone()), ("b/#".into(), opts.clone())],
+        };
+        let matchers = Vec::<TopicMatcher>::try_from(&sub).unwrap();
+        let filters: Vec<_> = matchers.iter().map(|m| m.filter().as_ref()).collect();
+        assert_eq!(filters, vec!["a/+", "b/#"]);
+
+        sub.topic_filters.insert(1, ("a/b#".into(), opts.clone()));
+        sub.topic_filters.push(("#/c".into(), opts));
+        assert_eq!(
+            Vec::<TopicMatcher>::try_from(&sub).unwrap_err(),
+            TopicFilterError::InvalidFilter("a/b#".into())
+        );
+
+        let mut unsub = Unsubscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Vec::new(),
+            topic_filters: vec!["a/+".into()],
+        };
+        assert_eq!(Vec::<TopicMatcher>::try_from(&unsub).unwrap().len(), 1);
+        unsub.topic_filters.push("".into());
+        assert_eq!(
+            Vec::<TopicMatcher>::try_from(&unsub).unwrap_err(),
+            TopicFilterError::InvalidFilter("".into())
+        );
+    }
 }
@@ -26,6 +26,8 @@ pub enum ControlMessage<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Sink's in-flight queue reached capacity
+    Overload(OverloadMessage),
 }
 
 /// Control message handling result
@@ -82,6 +84,26 @@ impl<E> ControlMessage<E> {
         ControlMessage::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn overload(current_inflight: usize, capacity: usize) -> Self {
+        ControlMessage::Overload(OverloadMessage { current_inflight, capacity })
+    }
+
+    /// Returns name of the control message
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlMessage::Auth(_) => "Auth",
+            ControlMessage::Ping(_) => "Ping",
+            ControlMessage::Disconnect(_) => "Disconnect",
+            ControlMessage::Subscribe(_) => "Subscribe",
+            ControlMessage::Unsubscribe(_) => "Unsubscribe",
+            ControlMessage::Closed(_) => "Closed",
+            ControlMessage::Error(_) => "Error",
+            ControlMessage::ProtocolError(_) => "ProtocolError",
+            ControlMessage::PeerGone(_) => "PeerGone",
+            ControlMessage::Overload(_) => "Overload",
+        }
+    }
+
     /// Disconnects the client by sending DISCONNECT packet
     /// with `NormalDisconnection` reason code.
     pub fn disconnect(&self) -> ControlResult {
@@ -111,6 +133,8 @@ impl Auth {
         &self.0
     }
 
+    /// Respond with auth packet, could be used for multi-step
+    /// authentication exchange
     pub fn ack(self, response: codec::Auth) -> ControlResult {
         ControlResult { packet: Some(codec::Packet::Auth(response)), disconnect: false }
     }
@@ -125,6 +149,28 @@ impl Ping {
     }
 }
 
+#[derive(Debug)]
+pub struct OverloadMessage {
+    current_inflight: usize,
+    capacity: usize,
+}
+
+impl OverloadMessage {
+    /// Returns number of in-flight packets
+    pub fn current_inflight(&self) -> usize {
+        self.current_inflight
+    }
+
+    /// Returns in-flight queue capacity
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
 #[derive(Debug)]
 pub struct Disconnect(pub(crate) codec::Disconnect);
 
@@ -197,6 +243,17 @@ impl Subscribe {
         }
     }
 
+    /// Ack Subscribe packet with reason codes
+    ///
+    /// Codes are applied to topic filters in order, filters without
+    /// a code keep current status.
+    pub fn ack_with(mut self, codes: Vec<codec::SubscribeAckReason>) -> ControlResult {
+        for (status, code) in self.result.status.iter_mut().zip(codes) {
+            *status = code;
+        }
+        self.ack()
+    }
+
     /// Returns reference to subscribe packet
     pub fn packet(&self) -> &codec::Subscribe {
         &self.packet
@@ -547,7 +604,8 @@ impl ProtocolError {
                     error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
                         DisconnectReasonCode::PacketTooLarge
                     }
-                    error::ProtocolError::Unexpected(_, _) => {
+                    error::ProtocolError::Unexpected(_, _)
+                    | error::ProtocolError::UnexpectedAck(_) => {
                         DisconnectReasonCode::ProtocolError
                     }
                     error::ProtocolError::ReceiveMaximumExceeded => {
@@ -559,6 +617,9 @@ impl ProtocolError {
                     error::ProtocolError::UnknownTopicAlias => {
                         DisconnectReasonCode::TopicAliasInvalid
                     }
+                    error::ProtocolError::Encode(error::EncodeError::PacketTooLarge {
+                        ..
+                    }) => DisconnectReasonCode::PacketTooLarge,
                     error::ProtocolError::Encode(_) => {
                         DisconnectReasonCode::ImplementationSpecificError
                     }
@@ -644,3 +705,48 @@ impl PeerGone {
         ControlResult { packet: None, disconnect: true }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU16;
+
+    use super::*;
+
+    #[test]
+    fn test_name() {
+        let packet_id = NonZeroU16::new(1).unwrap();
+        let messages: Vec<(ControlMessage<()>, &str)> = vec![
+            (ControlMessage::auth(codec::Auth::default()), "Auth"),
+            (ControlMessage::ping(), "Ping"),
+            (ControlMessage::remote_disconnect(codec::Disconnect::default()), "Disconnect"),
+            (
+                ControlMessage::subscribe(codec::Subscribe {
+                    packet_id,
+                    id: None,
+                    user_properties: Vec::new(),
+                    topic_filters: Vec::new(),
+                }),
+                "Subscribe",
+            ),
+            (
+                ControlMessage::unsubscribe(codec::Unsubscribe {
+                    packet_id,
+                    user_properties: Vec::new(),
+                    topic_filters: Vec::new(),
+                }),
+                "Unsubscribe",
+            ),
+            (ControlMessage::closed(false), "Closed"),
+            (ControlMessage::error(()), "Error"),
+            (
+                ControlMessage::proto_error(error::ProtocolError::KeepAliveTimeout),
+                "ProtocolError",
+            ),
+            (ControlMessage::peer_gone(None), "PeerGone"),
+            (ControlMessage::overload(16, 16), "Overload"),
+        ];
+        for (msg, name) in messages {
+            assert_eq!(msg.name(), name);
+        }
+    }
+}
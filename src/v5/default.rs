@@ -83,12 +83,35 @@ impl<S, E: fmt::Debug> Service<ControlMessage<E>> for DefaultControlService<S, E
         match pkt {
             ControlMessage::Ping(pkt) => Ready::Ok(pkt.ack()),
             ControlMessage::Disconnect(pkt) => Ready::Ok(pkt.ack()),
-            _ => {
-                log::warn!("MQTT5 Control service is not configured, pkt: {:?}", pkt);
+            ControlMessage::Closed(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::ProtocolError(pkt) => {
+                log::warn!("MQTT5 Protocol error: {}, disconnecting", pkt.get_ref());
+                Ready::Ok(pkt.ack())
+            }
+            ControlMessage::Overload(pkt) => {
+                log::warn!(
+                    "MQTT5 Sink is overloaded, in-flight: {} capacity: {}",
+                    pkt.current_inflight(),
+                    pkt.capacity()
+                );
+                Ready::Ok(pkt.ack())
+            }
+            ControlMessage::Auth(_) => {
+                log::warn!("MQTT5 Control service is not configured for Auth, disconnecting");
                 Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(
-                    super::codec::DisconnectReasonCode::UnspecifiedError,
+                    super::codec::DisconnectReasonCode::NotAuthorized,
                 )))
             }
+            _ => {
+                let reason_code = super::codec::DisconnectReasonCode::UnspecifiedError;
+                log::warn!(
+                    "MQTT5 Control service is not configured for {}, responding with {:?}",
+                    pkt.name(),
+                    reason_code
+                );
+                log::trace!("Unhandled control message: {:?}", pkt);
+                Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(reason_code)))
+            }
         }
     }
 }
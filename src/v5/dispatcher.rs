@@ -1,11 +1,12 @@
 use std::cell::RefCell;
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc, sync::Arc};
 
 use ntex::io::DispatchItem;
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
 use ntex::util::{
-    buffer::BufferService, inflight::InFlightService, join, Either, HashSet, Ready,
+    buffer::BufferService, inflight::InFlightService, join, poll_fn, ByteString, Either,
+    HashSet, Ready,
 };
 
 use crate::error::{MqttError, ProtocolError};
@@ -14,6 +15,7 @@ use super::control::{ControlMessage, ControlResult};
 use super::publish::{Publish, PublishAck};
 use super::shared::{Ack, MqttShared};
 use super::sink::MqttSink;
+use super::store::SubscriptionStore;
 use super::{codec, codec::EncodeLtd, Session};
 
 /// mqtt3 protocol dispatcher
@@ -21,6 +23,7 @@ pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
     max_inflight_size: usize,
+    store: Option<Arc<dyn SubscriptionStore>>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -40,6 +43,9 @@ where
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
         let (max_receive, max_topic_alias) = cfg.params();
+        // session ends with connection, subscriptions are removed on close
+        let purge = cfg.clean_start() || cfg.session_expiry() == 0;
+        let store = store.clone().map(|store| (store, cfg.client_id().clone(), purge));
 
         async move {
             let (publish, control) = fut.await;
@@ -64,6 +70,7 @@ where
                     max_topic_alias,
                     publish,
                     control,
+                    store,
                 ),
             ))
         }
@@ -95,6 +102,7 @@ struct Inner<C> {
     control: C,
     sink: MqttSink,
     info: RefCell<PublishInfo>,
+    store: Option<(Arc<dyn SubscriptionStore>, ByteString, bool)>,
 }
 
 struct PublishInfo {
@@ -107,7 +115,7 @@ where
     E: From<T::Error>,
     T: Service<Publish, Response = PublishAck>,
     PublishAck: TryFrom<T::Error, Error = E>,
-    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
+    C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>> + 'static,
 {
     fn new(
         sink: MqttSink,
@@ -115,21 +123,47 @@ where
         max_topic_alias: u16,
         publish: T,
         control: C,
+        store: Option<(Arc<dyn SubscriptionStore>, ByteString, bool)>,
     ) -> Self {
+        let inner = Rc::new(Inner {
+            control,
+            sink: sink.clone(),
+            info: RefCell::new(PublishInfo {
+                aliases: HashSet::default(),
+                inflight: HashSet::default(),
+            }),
+            store,
+        });
+
+        // notify control service about sink overload
+        let weak = Rc::downgrade(&inner);
+        sink.on_overload(move |current_inflight, capacity| {
+            if let Some(inner) = weak.upgrade() {
+                ntex::rt::spawn(async move {
+                    if poll_fn(|cx| inner.control.poll_ready(cx)).await.is_err() {
+                        log::trace!("Control service failed, drop overload message");
+                        return;
+                    }
+                    let msg = ControlMessage::overload(current_inflight, capacity);
+                    if let Ok(result) = inner.control.call(msg).await {
+                        if let Some(pkt) = result.packet {
+                            inner.sink.send(pkt)
+                        }
+                        if result.disconnect {
+                            inner.sink.drop_sink();
+                        }
+                    }
+                });
+            }
+        });
+
         Self {
             publish,
             max_receive,
             max_topic_alias,
-            sink: sink.clone(),
+            sink,
             shutdown: RefCell::new(None),
-            inner: Rc::new(Inner {
-                control,
-                sink,
-                info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
-                    inflight: HashSet::default(),
-                }),
-            }),
+            inner,
             _t: marker::PhantomData,
         }
     }
@@ -164,6 +198,9 @@ where
         let mut shutdown = self.shutdown.borrow_mut();
         if !shutdown.is_some() {
             self.inner.sink.drop_sink();
+            if let Some((ref store, ref client_id, true)) = self.inner.store {
+                store.remove_client(client_id);
+            }
             *shutdown =
                 Some(Box::pin(self.inner.control.call(ControlMessage::closed(is_error))));
         }
@@ -260,6 +297,16 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(pubrec)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(pubrec)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
             DispatchItem::Item(codec::Packet::PublishComplete(pubcomp)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish2(pubcomp)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -296,9 +343,15 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let filters = if self.inner.store.is_some() {
+                    Filters::Subscribe(pkt.topic_filters.clone())
+                } else {
+                    Filters::None
+                };
                 Either::Right(Either::Right(
                     ControlResponse::new(ControlMessage::subscribe(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .filters(filters),
                 ))
             }
             DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
@@ -318,9 +371,15 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let filters = if self.inner.store.is_some() {
+                    Filters::Unsubscribe(pkt.topic_filters.clone())
+                } else {
+                    Filters::None
+                };
                 Either::Right(Either::Right(
                     ControlResponse::new(ControlMessage::unsubscribe(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .filters(filters),
                 ))
             }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
@@ -439,10 +498,18 @@ pin_project_lite::pin_project! {
         inner: Rc<Inner<C>>,
         error: bool,
         packet_id: u16,
+        filters: Filters,
         _t: marker::PhantomData<E>,
     }
 }
 
+/// Topic filters of subscribe/unsubscribe packet, kept for subscription store
+enum Filters {
+    None,
+    Subscribe(Vec<(ByteString, codec::SubscriptionOptions)>),
+    Unsubscribe(Vec<ByteString>),
+}
+
 impl<C, E> ControlResponse<C, E>
 where
     C: Service<ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
@@ -459,6 +526,7 @@ where
             fut: inner.control.call(pkt),
             inner: inner.clone(),
             packet_id: 0,
+            filters: Filters::None,
             _t: marker::PhantomData,
         }
     }
@@ -467,6 +535,43 @@ where
         self.packet_id = id.get();
         self
     }
+
+    fn filters(mut self, filters: Filters) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Update subscription store with acked filters
+    fn update_store(&self, packet: &Option<codec::Packet>) {
+        let (store, client_id) = if let Some((store, id, _)) = &self.inner.store {
+            (store, id)
+        } else {
+            return;
+        };
+
+        match (&self.filters, packet) {
+            (Filters::Subscribe(filters), Some(codec::Packet::SubscribeAck(ack))) => {
+                for ((filter, opts), status) in filters.iter().zip(ack.status.iter()) {
+                    let qos = match status {
+                        codec::SubscribeAckReason::GrantedQos0 => codec::QoS::AtMostOnce,
+                        codec::SubscribeAckReason::GrantedQos1 => codec::QoS::AtLeastOnce,
+                        codec::SubscribeAckReason::GrantedQos2 => codec::QoS::ExactlyOnce,
+                        _ => continue,
+                    };
+                    let opts = codec::SubscriptionOptions { qos, ..opts.clone() };
+                    store.subscribe(client_id, filter, opts);
+                }
+            }
+            (Filters::Unsubscribe(filters), Some(codec::Packet::UnsubscribeAck(ack))) => {
+                for (filter, status) in filters.iter().zip(ack.status.iter()) {
+                    if *status == codec::UnsubscribeAckReason::Success {
+                        store.unsubscribe(client_id, filter);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -513,10 +618,15 @@ where
                 self.inner.sink.drop_sink();
             }
             Poll::Ready(Ok(None))
-        } else {
-            if result.disconnect {
-                self.inner.sink.drop_sink();
+        } else if result.disconnect {
+            // write response before closing io stream
+            if let Some(pkt) = result.packet {
+                self.inner.sink.send(pkt)
             }
+            self.inner.sink.drop_sink();
+            Poll::Ready(Ok(None))
+        } else {
+            self.update_store(&result.packet);
             Poll::Ready(Ok(result.packet))
         }
     }
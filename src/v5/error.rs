@@ -1,5 +1,5 @@
 use derive_more::{Display, From};
-use ntex::util::Either;
+use ntex::util::{ByteString, Either};
 
 pub use crate::error::*;
 pub use crate::v5::codec;
@@ -50,26 +50,120 @@ pub enum PublishQos1Error {
     #[display(fmt = "Negative ack: {:?}", _0)]
     Fail(codec::PublishAck),
     /// Encoder error
+    #[display(fmt = "Encode error: {}", _0)]
     Encode(EncodeError),
     /// Provided packet id is in use
-    #[display(fmt = "Provided packet id is in use")]
+    #[display(fmt = "Provided packet id is in use: {}", _0)]
     PacketIdInUse(u16),
+    /// Max number of re-transmissions exceeded
+    #[display(fmt = "Max number of re-transmissions exceeded")]
+    MaxRetriesExceeded,
+    /// No in-flight credit is available
+    #[display(fmt = "No in-flight credit is available")]
+    NoCredit,
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
 }
 
+impl std::error::Error for PublishQos1Error {}
+
+impl PublishQos1Error {
+    /// Packet id of failed publish packet, if available
+    pub fn packet_id(&self) -> Option<u16> {
+        match self {
+            PublishQos1Error::Fail(ack) => Some(ack.packet_id.get()),
+            PublishQos1Error::PacketIdInUse(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Display, PartialEq)]
 pub enum PublishQos2Error {
     /// Negative ack from peer
     #[display(fmt = "Negative ack: {:?}", _0)]
     Fail(codec::PublishAck2),
     /// Encoder error
+    #[display(fmt = "Encode error: {}", _0)]
     Encode(EncodeError),
     /// Provided packet id is in use
-    #[display(fmt = "Provided packet id is in use")]
+    #[display(fmt = "Provided packet id is in use: {}", _0)]
     PacketIdInUse(u16),
+    /// Max number of re-transmissions exceeded
+    #[display(fmt = "Max number of re-transmissions exceeded")]
+    MaxRetriesExceeded,
+    /// No in-flight credit is available
+    #[display(fmt = "No in-flight credit is available")]
+    NoCredit,
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
 }
+
+impl std::error::Error for PublishQos2Error {}
+
+/// Publish error of any QoS level
+#[derive(Debug, Display, PartialEq)]
+pub enum SendAnyError {
+    /// QoS 0 publish error
+    #[display(fmt = "{}", _0)]
+    AtMostOnce(SendPacketError),
+    /// QoS 1 publish error
+    #[display(fmt = "{}", _0)]
+    AtLeastOnce(PublishQos1Error),
+    /// QoS 2 publish error
+    #[display(fmt = "{}", _0)]
+    ExactlyOnce(PublishQos2Error),
+}
+
+impl std::error::Error for SendAnyError {}
+
+/// Publish was not acknowledged with `Success` reason code
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishAckError {
+    pub reason_code: codec::PublishAckReason,
+    pub reason_string: Option<ByteString>,
+}
+
+impl std::fmt::Display for PublishAckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Publish is rejected: {:?}", self.reason_code)?;
+        if let Some(ref reason) = self.reason_string {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PublishAckError {}
+
+/// Timeout elapsed before sink became ready
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq)]
+#[display(fmt = "Sink ready timeout")]
+pub struct TimeoutError;
+
+impl std::error::Error for TimeoutError {}
+
+#[derive(Debug, Display, PartialEq)]
+pub enum ResubscribeError {
+    /// Unsubscribe from old topic filter failed
+    #[display(fmt = "Unsubscribe error: {}", _0)]
+    Unsubscribe(SendPacketError),
+    /// Subscribe to new topic filter failed
+    #[display(fmt = "Subscribe error: {}", _0)]
+    Subscribe(SendPacketError),
+}
+
+impl std::error::Error for ResubscribeError {}
+
+impl PublishQos2Error {
+    /// Packet id of failed publish packet, if available
+    pub fn packet_id(&self) -> Option<u16> {
+        match self {
+            PublishQos2Error::Fail(ack) => Some(ack.packet_id.get()),
+            PublishQos2Error::PacketIdInUse(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
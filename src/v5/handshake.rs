@@ -11,6 +11,8 @@ pub struct Handshake {
     pub(super) max_size: u32,
     pub(super) max_receive: u16,
     pub(super) max_topic_alias: u16,
+    pub(super) keep_alive_max: u16,
+    pub(super) keep_alive_tolerance: f32,
 }
 
 impl Handshake {
@@ -22,7 +24,16 @@ impl Handshake {
         max_receive: u16,
         max_topic_alias: u16,
     ) -> Self {
-        Self { io, pkt, shared, max_size, max_receive, max_topic_alias }
+        Self {
+            io,
+            pkt,
+            shared,
+            max_size,
+            max_receive,
+            max_topic_alias,
+            keep_alive_max: 0,
+            keep_alive_tolerance: 1.5,
+        }
     }
 
     #[inline]
@@ -61,13 +72,19 @@ impl Handshake {
             packet.receive_max = Some(NonZeroU16::new(self.max_receive).unwrap());
         }
 
-        let Handshake { io, shared, pkt, .. } = self;
+        let mut keep_alive = self.pkt.keep_alive;
+        if self.keep_alive_max != 0 && (keep_alive == 0 || keep_alive > self.keep_alive_max) {
+            keep_alive = self.keep_alive_max;
+            packet.server_keepalive_sec = Some(keep_alive);
+        }
+
         // [MQTT-3.1.2-22]
-        let keepalive = if pkt.keep_alive != 0 {
-            (pkt.keep_alive >> 1).checked_add(pkt.keep_alive).unwrap_or(u16::MAX)
+        let keepalive = if keep_alive != 0 {
+            (f32::from(keep_alive) * self.keep_alive_tolerance) as u16
         } else {
             30
         };
+        let Handshake { io, shared, .. } = self;
         HandshakeAck { io, shared, keepalive, packet, session: Some(st) }
     }
 
@@ -13,16 +13,23 @@ mod selector;
 mod server;
 mod shared;
 mod sink;
+mod store;
+mod will;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
 pub use self::control::{ControlMessage, ControlResult};
 pub use self::handshake::{Handshake, HandshakeAck};
 pub use self::publish::{Publish, PublishAck};
-pub use self::router::Router;
+pub use self::router::{GroupAckMerge, MissingRouteAction, RouteInfo, Router, RouterStats};
 pub use self::selector::Selector;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::sink::{
+    MqttSink, PublishBuilder, QosAckResult, SubscribeBuilder, UnsubscribeBuilder,
+};
+pub use self::store::{InMemorySubscriptionStore, SubscriptionStore};
+pub use self::will::WillMessageBuilder;
 
+pub use crate::session::SessionSnapshot;
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
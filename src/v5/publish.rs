@@ -1,11 +1,13 @@
-use std::{mem, num::NonZeroU16};
+use std::{convert::TryFrom, future::Future, mem, num::NonZeroU16, str::Utf8Error};
 
 use ntex::router::Path;
+use ntex::time::Millis;
 use ntex::util::{ByteString, Bytes};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
-use super::codec;
+use super::error::{PublishAckError, SendAnyError};
+use super::{codec, sink::MqttSink};
 
 /// Publish message
 pub struct Publish {
@@ -21,6 +23,11 @@ impl Publish {
         Self { topic: Path::new(publish.topic.clone()), publish }
     }
 
+    /// Create copy of publish message
+    pub(super) fn duplicate(&self) -> Self {
+        Self { publish: self.publish.clone(), topic: self.topic.clone() }
+    }
+
     #[inline]
     /// this might be re-delivery of an earlier attempt to send the Packet.
     pub fn dup(&self) -> bool {
@@ -28,6 +35,7 @@ impl Publish {
     }
 
     #[inline]
+    /// the message should be retained by the server, or was sent as retained message.
     pub fn retain(&self) -> bool {
         self.publish.retain
     }
@@ -76,6 +84,97 @@ impl Publish {
         &self.publish.payload
     }
 
+    #[inline]
+    /// Response topic for request-response interaction.
+    ///
+    /// ```rust,no_run
+    /// use ntex::util::Bytes;
+    /// use ntex_mqtt::v5::{MqttSink, Publish, PublishAck};
+    ///
+    /// async fn request(sink: MqttSink, publish: Publish) -> Result<PublishAck, ()> {
+    ///     if let Some(topic) = publish.response_topic() {
+    ///         let mut response = sink.publish(topic.clone(), Bytes::from_static(b"response"));
+    ///         if let Some(data) = publish.correlation_data() {
+    ///             response = response.correlation_data(data.clone());
+    ///         }
+    ///         let _ = response.send_at_most_once();
+    ///     }
+    ///     Ok(publish.ack())
+    /// }
+    /// ```
+    pub fn response_topic(&self) -> Option<&ByteString> {
+        self.publish.properties.response_topic.as_ref()
+    }
+
+    #[inline]
+    /// Correlation data for request-response interaction.
+    pub fn correlation_data(&self) -> Option<&Bytes> {
+        self.publish.properties.correlation_data.as_ref()
+    }
+
+    #[inline]
+    /// Correlation data decoded as big-endian `u64` id.
+    ///
+    /// Returns `None` if correlation data is not set or is not 8 bytes long.
+    pub fn correlation_id(&self) -> Option<u64> {
+        let data = self.publish.properties.correlation_data.as_ref()?;
+        <[u8; 8]>::try_from(data.as_ref()).ok().map(u64::from_be_bytes)
+    }
+
+    #[inline]
+    /// Content type of the application message.
+    pub fn content_type(&self) -> Option<&ByteString> {
+        self.publish.properties.content_type.as_ref()
+    }
+
+    #[inline]
+    /// Message expiry interval in seconds.
+    pub fn message_expiry_interval(&self) -> Option<u32> {
+        self.publish.properties.message_expiry_interval.map(|v| v.get())
+    }
+
+    /// Interpret payload as utf-8 text.
+    ///
+    /// ```rust
+    /// fn handle(publish: &ntex_mqtt::v5::Publish) {
+    ///     match publish.payload_as_str() {
+    ///         Ok(text) => println!("text message: {}", text),
+    ///         Err(e) => println!("non utf-8 payload: {}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn payload_as_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.publish.payload)
+    }
+
+    /// Parse `application/json` encoded payload.
+    ///
+    /// Returns error if content type is set and it is not `application/json`.
+    ///
+    /// ```rust
+    /// #[derive(serde::Deserialize)]
+    /// struct Reading {
+    ///     value: f64,
+    /// }
+    ///
+    /// fn handle(publish: &ntex_mqtt::v5::Publish) {
+    ///     if let Ok(reading) = publish.payload_as_json::<Reading>() {
+    ///         println!("value: {}", reading.value);
+    ///     }
+    /// }
+    /// ```
+    pub fn payload_as_json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        if let Some(ref content_type) = self.publish.properties.content_type {
+            if content_type != "application/json" {
+                return Err(serde::de::Error::custom(format!(
+                    "unexpected content type: {}",
+                    content_type
+                )));
+            }
+        }
+        serde_json::from_slice(&self.publish.payload)
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.publish.payload)
@@ -95,11 +194,139 @@ impl Publish {
         }
     }
 
+    /// Create acknowledgement for this packet with reason code and reason string
+    pub fn ack_with_reason(
+        self,
+        code: codec::PublishAckReason,
+        reason_string: Option<ByteString>,
+    ) -> PublishAck {
+        PublishAck {
+            reason_code: code,
+            properties: codec::UserProperties::default(),
+            reason_string,
+        }
+    }
+
+    /// Create acknowledgement that rejects this packet with `code`
+    pub fn ack_error(self, code: codec::PublishAckReason) -> PublishAck {
+        self.ack_with_reason(code, None)
+    }
+
+    /// Re-publish message to a different sink under `topic`.
+    ///
+    /// Payload, QoS, retain flag and properties are preserved, except
+    /// topic alias and subscription identifiers which are per-connection.
+    /// QoS 1 and 2 messages wait for the ack with 5 seconds timeout.
+    pub fn forward_to(
+        self,
+        sink: &MqttSink,
+        topic: ByteString,
+    ) -> impl Future<Output = Result<(), SendAnyError>> {
+        let codec::Publish { qos, retain, payload, mut properties, .. } = self.publish;
+        properties.topic_alias = None;
+        properties.subscription_ids = None;
+
+        let mut builder = sink.publish(topic, payload).properties(|props| *props = properties);
+        if retain {
+            builder = builder.retain();
+        }
+        let fut = builder.send(qos, Millis(5_000));
+        async move { fut.await.map(|_| ()) }
+    }
+
+    /// Convert to MQTT 3.1.1 publish message, payload is not copied.
+    ///
+    /// Topic, payload, QoS, retain and dup flags and packet id are preserved,
+    /// aliased publish gets topic resolved by router.
+    /// All v5 properties are lost: message expiry, content type, payload format,
+    /// response topic, correlation data, topic alias, subscription identifiers
+    /// and user properties.
+    pub fn into_v3(self) -> crate::v3::Publish {
+        let codec::Publish { dup, retain, qos, packet_id, payload, .. } = self.publish;
+        crate::v3::Publish::new(crate::v3::codec::Publish {
+            dup,
+            retain,
+            qos,
+            // publish topic is empty for aliased publish, resolved topic is kept in path
+            topic: self.topic.get_ref().clone(),
+            packet_id,
+            payload,
+        })
+    }
+
     pub(crate) fn into_inner(self) -> codec::Publish {
         self.publish
     }
 }
 
+/// Successful PUBACK/PUBREC for publish packet
+///
+/// ```rust
+/// use ntex_mqtt::v5::{codec, Publish};
+/// # use std::num::NonZeroU16;
+/// # let publish = Publish::new(codec::Publish {
+/// #     dup: false,
+/// #     retain: false,
+/// #     qos: codec::QoS::AtLeastOnce,
+/// #     topic: "topic".into(),
+/// #     packet_id: NonZeroU16::new(1),
+/// #     payload: Default::default(),
+/// #     properties: Default::default(),
+/// # });
+///
+/// let ack = codec::PublishAck::from(&publish);
+/// assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+/// ```
+///
+/// # Panics
+///
+/// Panics if publish does not have packet id (QoS 0 publish).
+impl From<&Publish> for codec::PublishAck {
+    fn from(publish: &Publish) -> Self {
+        codec::PublishAck {
+            packet_id: publish
+                .id()
+                .expect("PublishAck requires QoS 1/2 publish with packet id"),
+            reason_code: codec::PublishAckReason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        }
+    }
+}
+
+/// Successful PUBREL/PUBCOMP for publish packet
+///
+/// ```rust
+/// use ntex_mqtt::v5::{codec, Publish};
+/// # use std::num::NonZeroU16;
+/// # let publish = Publish::new(codec::Publish {
+/// #     dup: false,
+/// #     retain: false,
+/// #     qos: codec::QoS::ExactlyOnce,
+/// #     topic: "topic".into(),
+/// #     packet_id: NonZeroU16::new(1),
+/// #     payload: Default::default(),
+/// #     properties: Default::default(),
+/// # });
+///
+/// let ack = codec::PublishAck2::from(&publish);
+/// assert_eq!(ack.reason_code, codec::PublishAck2Reason::Success);
+/// ```
+///
+/// # Panics
+///
+/// Panics if publish does not have packet id (QoS 0 publish).
+impl From<&Publish> for codec::PublishAck2 {
+    fn from(publish: &Publish) -> Self {
+        codec::PublishAck2 {
+            packet_id: publish.id().expect("PublishAck2 requires QoS 2 publish with packet id"),
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        }
+    }
+}
+
 impl std::fmt::Debug for Publish {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.publish.fmt(f)
@@ -141,10 +368,46 @@ impl PublishAck {
         self
     }
 
+    /// Add user property
+    #[inline]
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.properties.push((key, value));
+        self
+    }
+
     /// Set ack reason string
     #[inline]
     pub fn reason(mut self, reason: ByteString) -> Self {
         self.reason_string = Some(reason);
         self
     }
+
+    /// Convert to `Err` unless reason code is `Success`
+    ///
+    /// ```rust
+    /// use ntex_mqtt::v5::{error::PublishAckError, Publish, PublishAck};
+    ///
+    /// async fn handler(publish: Publish) -> Result<PublishAck, PublishAckError> {
+    ///     let ack = validate(&publish);
+    ///     ack.into_result()?;
+    ///     Ok(publish.ack())
+    /// }
+    /// # fn validate(_: &Publish) -> PublishAck { unimplemented!() }
+    /// ```
+    pub fn into_result(self) -> Result<(), PublishAckError> {
+        if self.reason_code == codec::PublishAckReason::Success {
+            Ok(())
+        } else {
+            Err(PublishAckError {
+                reason_code: self.reason_code,
+                reason_string: self.reason_string,
+            })
+        }
+    }
+}
+
+impl From<PublishAck> for Result<(), PublishAckError> {
+    fn from(ack: PublishAck) -> Self {
+        ack.into_result()
+    }
 }
@@ -3,22 +3,52 @@ use std::{cell::Cell, cell::RefCell, future::Future, num::NonZeroU16, pin::Pin,
 
 use ntex::router::{IntoPattern, Path, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
-use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::service::{apply, fn_factory_with_config, fn_service};
+use ntex::service::{IntoServiceFactory, Service, ServiceFactory, Transform};
 use ntex::task::LocalWaker;
-use ntex::util::{ByteString, HashMap};
+use ntex::util::{join_all, ByteString, HashMap, Ready};
 
 use super::publish::{Publish, PublishAck};
-use super::Session;
+use super::{codec, Session};
 
 type Handler<S, E> = BoxServiceFactory<Session<S>, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
+type Inspect = Rc<dyn Fn(&str, Option<usize>)>;
+type Middleware<S, E> = Box<dyn Fn(Handler<S, E>) -> Handler<S, E>>;
+
+/// Resource priority and pattern specificity
+type Rank = (u8, u32);
+
+const SYS_PREFIX: &str = "$SYS/";
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
+///
+/// Router is not `Send`, like all ntex services it is created per worker
+/// thread, inside of server factory closure.
 pub struct Router<S, Err> {
-    router: RouterBuilder<usize>,
+    router: RouterBuilder<usize, Rank>,
+    ranks: Vec<Rank>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
+    patterns: Vec<String>,
+    names: Vec<String>,
+    sys: Option<usize>,
+    eager_init: bool,
+    on_missing: MissingRouteAction,
+    inspect: Option<Inspect>,
+    middleware: Vec<Middleware<S, Err>>,
+}
+
+/// Router behavior for publishes that do not match any resource
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MissingRouteAction {
+    /// Use default service
+    UseDefault,
+    /// Ack publish with reason code
+    AckWith(codec::PublishAckReason),
+    /// Disconnect with reason code
+    DisconnectWith(codec::DisconnectReasonCode),
 }
 
 impl<S, Err> Router<S, Err>
@@ -42,34 +72,542 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            ranks: Vec::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
+            patterns: Vec::new(),
+            names: Vec::new(),
+            sys: None,
+            eager_init: false,
+            on_missing: MissingRouteAction::UseDefault,
+            inspect: None,
+            middleware: Vec::new(),
         }
     }
 
+    /// Create mqtt application router with async function as default service.
+    ///
+    /// ```rust
+    /// use ntex_mqtt::v5::{Publish, Router};
+    ///
+    /// struct St;
+    ///
+    /// let router = Router::<St, ()>::with_default_fn(|p: Publish| async move {
+    ///     Ok(p.ack())
+    /// })
+    /// .resource_fn("sensors/+", |p: Publish| async move { Ok(p.ack()) });
+    /// ```
+    pub fn with_default_fn<F, Fut>(f: F) -> Self
+    where
+        F: Fn(Publish) -> Fut + Clone + 'static,
+        Fut: Future<Output = Result<PublishAck, Err>> + 'static,
+    {
+        Router::new(fn_factory_with_config(move |_: Session<S>| {
+            Ready::Ok(fn_service(f.clone()))
+        }))
+    }
+
+    /// Replace default service.
+    ///
+    /// Previously configured default service is dropped.
+    pub fn set_default<F, U>(mut self, service: F) -> Self
+    where
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        self.default = boxed::factory(service.into_factory().map_init_err(Err::from));
+        self
+    }
+
     /// Configure mqtt resource for a specific topic.
-    pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
+    pub fn resource<T, F, U: 'static>(self, address: T, service: F) -> Self
     where
         T: IntoPattern,
         F: IntoServiceFactory<U, Publish, Session<S>>,
         U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err>,
         Err: From<U::InitError>,
     {
-        self.router.path(address, self.handlers.len());
+        self.resource_boxed(
+            address,
+            boxed::factory(service.into_factory().map_init_err(Err::from)),
+        )
+    }
+
+    fn resource_boxed<T: IntoPattern>(mut self, address: T, handler: Handler<S, Err>) -> Self {
+        let patterns = address.patterns();
+        let rank = (0, specificity(&patterns));
+        self.names.push(patterns.join(", "));
+        self.patterns.extend(patterns);
+        self.router.path(address, self.handlers.len()).2 = Some(rank);
+        self.ranks.push(rank);
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Configure mqtt resource for a specific topic with async function.
+    pub fn resource_fn<T, F, Fut>(self, address: T, f: F) -> Self
+    where
+        T: IntoPattern,
+        F: Fn(Publish) -> Fut + Clone + 'static,
+        Fut: Future<Output = Result<PublishAck, Err>> + 'static,
+    {
+        self.resource(
+            address,
+            fn_factory_with_config(move |_: Session<S>| Ready::Ok(fn_service(f.clone()))),
+        )
+    }
+
+    /// Configure mqtt resource for a specific topic with async function,
+    /// function receives session as second argument.
+    pub fn resource_fn_with_session<T, F, Fut>(self, address: T, f: F) -> Self
+    where
+        T: IntoPattern,
+        F: Fn(Publish, Session<S>) -> Fut + Clone + 'static,
+        Fut: Future<Output = Result<PublishAck, Err>> + 'static,
+    {
+        self.resource(
+            address,
+            fn_factory_with_config(move |session: Session<S>| {
+                let f = f.clone();
+                Ready::Ok(fn_service(move |req| f(req, session.clone())))
+            }),
+        )
+    }
+
+    /// Configure mqtt resource for a specific topic with middleware.
+    ///
+    /// Middleware is applied to handler service created for each session.
+    ///
+    /// ```rust,no_run
+    /// use std::task::{Context, Poll};
+    /// use ntex::service::{Service, Transform};
+    /// use ntex::util::Ready;
+    /// use ntex_mqtt::v5::{codec, Publish, PublishAck, Router};
+    ///
+    /// struct Auth;
+    ///
+    /// impl<S> Transform<S> for Auth {
+    ///     type Service = AuthService<S>;
+    ///
+    ///     fn new_transform(&self, service: S) -> Self::Service {
+    ///         AuthService(service)
+    ///     }
+    /// }
+    ///
+    /// struct AuthService<S>(S);
+    ///
+    /// impl<S: Service<Publish, Response = PublishAck>> Service<Publish> for AuthService<S> {
+    ///     type Response = PublishAck;
+    ///     type Error = S::Error;
+    ///     type Future = ntex::util::Either<S::Future, Ready<PublishAck, S::Error>>;
+    ///
+    ///     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    ///         self.0.poll_ready(cx)
+    ///     }
+    ///
+    ///     fn call(&self, req: Publish) -> Self::Future {
+    ///         if req.packet().properties.user_properties.is_empty() {
+    ///             let ack = PublishAck::new(codec::PublishAckReason::NotAuthorized);
+    ///             ntex::util::Either::Right(Ready::Ok(ack))
+    ///         } else {
+    ///             ntex::util::Either::Left(self.0.call(req))
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let router = Router::<(), ()>::new(|p: Publish| Ready::Ok(p.ack()))
+    ///     .resource_with_middleware("private/{tail}*", |p: Publish| Ready::Ok(p.ack()), Auth);
+    /// ```
+    pub fn resource_with_middleware<T, F, U, M>(
+        self,
+        address: T,
+        service: F,
+        middleware: M,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>> + 'static,
+        M: Transform<U::Service> + 'static,
+        M::Service: Service<Publish, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        self.resource(address, apply(middleware, service))
+    }
+
+    /// Apply middleware to all resources and default service.
+    ///
+    /// Middleware is applied in `finish()`, so resources registered after this
+    /// call are wrapped as well. Handler services are wrapped when they get created
+    /// for a session. Middleware registered last is the outermost one.
+    ///
+    /// ```rust,no_run
+    /// use std::task::{Context, Poll};
+    /// use ntex::service::{Service, Transform};
+    /// use ntex::util::Ready;
+    /// use ntex_mqtt::v5::{Publish, PublishAck, Router};
+    ///
+    /// struct Logger;
+    ///
+    /// impl<S> Transform<S> for Logger {
+    ///     type Service = LoggerService<S>;
+    ///
+    ///     fn new_transform(&self, service: S) -> Self::Service {
+    ///         LoggerService(service)
+    ///     }
+    /// }
+    ///
+    /// struct LoggerService<S>(S);
+    ///
+    /// impl<S: Service<Publish, Response = PublishAck>> Service<Publish> for LoggerService<S> {
+    ///     type Response = PublishAck;
+    ///     type Error = S::Error;
+    ///     type Future = S::Future;
+    ///
+    ///     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    ///         self.0.poll_ready(cx)
+    ///     }
+    ///
+    ///     fn call(&self, req: Publish) -> Self::Future {
+    ///         log::info!("Publish to {:?} with {:?}", req.publish_topic(), req.qos());
+    ///         self.0.call(req)
+    ///     }
+    /// }
+    ///
+    /// let router = Router::<(), ()>::new(|p: Publish| Ready::Ok(p.ack()))
+    ///     .resource("sensors/+", |p: Publish| Ready::Ok(p.ack()))
+    ///     .middleware(Logger);
+    /// ```
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Transform<HandlerService<Err>> + 'static,
+        M::Service: Service<Publish, Response = PublishAck, Error = Err> + 'static,
+    {
+        let middleware = Rc::new(middleware);
+        self.middleware
+            .push(Box::new(move |handler| boxed::factory(apply(middleware.clone(), handler))));
+        self
+    }
+
+    /// Configure mqtt resource for a set of topics.
+    ///
+    /// All topics are handled by the same service instance.
+    pub fn resources<T, P, F, U>(mut self, addresses: T, service: F) -> Self
+    where
+        T: IntoIterator<Item = P>,
+        P: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let idx = self.handlers.len();
+        let mut names = Vec::new();
+        for address in addresses {
+            let patterns = address.patterns();
+            let rank = (0, specificity(&patterns));
+            names.extend(patterns);
+            self.router.path(address, idx).2 = Some(rank);
+            self.ranks.push(rank);
+        }
+        self.names.push(names.join(", "));
+        self.patterns.extend(names);
         self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
         self
     }
 
+    /// Configure group of services for a specific topic.
+    ///
+    /// Each publish is dispatched to all services of the group concurrently,
+    /// the first non-success ack is returned to the peer.
+    pub fn resource_group<T, F, U>(self, address: T, services: Vec<F>) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        self.resource_group_with_merge(address, GroupAckMerge::FirstFailure, services)
+    }
+
+    /// Configure group of services for a specific topic with ack merge strategy.
+    ///
+    /// Panics if `services` is empty.
+    pub fn resource_group_with_merge<T, F, U>(
+        self,
+        address: T,
+        merge: GroupAckMerge,
+        services: Vec<F>,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        assert!(!services.is_empty(), "Resource group requires at least one service");
+
+        let factories = services
+            .into_iter()
+            .map(|f| boxed::factory(f.into_factory().map_init_err(Err::from)))
+            .collect();
+        let group = GroupFactory { factories: Rc::new(factories), merge };
+        self.resource_boxed(address, boxed::factory(group))
+    }
+
+    /// Configure mqtt resource for a specific topic with priority.
+    ///
+    /// If multiple resources match the topic, resource with highest
+    /// priority is used. Resources configured with `resource()` method
+    /// have priority 0. Among resources with the same priority the most
+    /// specific resource is used, literal segments win over dynamic
+    /// segments, and dynamic segments win over tail segments.
+    pub fn resource_with_priority<T, F, U>(
+        mut self,
+        address: T,
+        priority: u8,
+        service: F,
+    ) -> Self
+    where
+        T: IntoPattern,
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        let patterns = address.patterns();
+        let rank = (priority, specificity(&patterns));
+        self.names.push(patterns.join(", "));
+        self.patterns.extend(patterns);
+        self.router.path(address, self.handlers.len()).2 = Some(rank);
+        self.ranks.push(rank);
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Configure service for all `$SYS/` topics.
+    ///
+    /// `$SYS/` topics are matched before any other resource.
+    pub fn sys_handler<F, U>(mut self, service: F) -> Self
+    where
+        F: IntoServiceFactory<U, Publish, Session<S>>,
+        U: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = Err> + 'static,
+        Err: From<U::InitError>,
+    {
+        if let Some(idx) = self.sys {
+            self.handlers[idx] = boxed::factory(service.into_factory().map_init_err(Err::from));
+        } else {
+            self.sys = Some(self.handlers.len());
+            self.patterns.push(SYS_PREFIX.to_string());
+            self.names.push(SYS_PREFIX.to_string());
+            self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        }
+        self
+    }
+
+    /// Create all resource services when router service is created.
+    ///
+    /// By default resource services are created on first publish to the resource.
+    pub fn eager_init(mut self) -> Self {
+        self.eager_init = true;
+        self
+    }
+
+    /// Configure action for publishes that do not match any resource.
+    ///
+    /// By default publish is handled by default service.
+    pub fn on_missing(mut self, action: MissingRouteAction) -> Self {
+        self.on_missing = action;
+        self
+    }
+
+    /// Register diagnostic hook, it is called before every dispatch.
+    ///
+    /// Hook receives publish topic and index of matched resource,
+    /// `None` means publish did not match any resource and is handled
+    /// according to `on_missing()` action.
+    ///
+    /// ```rust
+    /// use std::sync::mpsc;
+    /// use ntex::{time::Millis, util::Bytes, util::Ready};
+    /// use ntex_mqtt::v5::{client, Handshake, MqttServer, Publish, Router};
+    /// # #[derive(Debug)]
+    /// # struct MyError;
+    /// # impl std::convert::TryFrom<MyError> for ntex_mqtt::v5::PublishAck {
+    /// #     type Error = MyError;
+    /// #     fn try_from(err: MyError) -> Result<Self, MyError> { Err(err) }
+    /// # }
+    ///
+    /// # #[ntex::main]
+    /// # async fn main() {
+    /// let (tx, rx) = mpsc::channel();
+    /// let srv = ntex::server::test_server(move || {
+    ///     let tx = tx.clone();
+    ///     MqttServer::new(|con: Handshake| Ready::Ok::<_, MyError>(con.ack(())))
+    ///         .publish(
+    ///             Router::with_default_fn(|p: Publish| async move { Ok(p.ack()) })
+    ///                 .resource_fn("sensors/{id}", |p: Publish| async move { Ok(p.ack()) })
+    ///                 .inspect(move |topic, idx| tx.send((topic.to_string(), idx)).unwrap())
+    ///                 .finish(),
+    ///         )
+    ///         .finish()
+    /// });
+    ///
+    /// let client =
+    ///     client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    /// let sink = client.sink();
+    /// ntex::rt::spawn(client.start_default());
+    ///
+    /// for topic in ["sensors/1", "other"] {
+    ///     sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    /// }
+    /// assert_eq!(rx.recv().unwrap(), ("sensors/1".to_string(), Some(0)));
+    /// assert_eq!(rx.recv().unwrap(), ("other".to_string(), None));
+    /// # }
+    /// ```
+    pub fn inspect<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, Option<usize>) + 'static,
+    {
+        self.inspect = Some(Rc::new(f));
+        self
+    }
+
     /// Finish router configuration and create router service factory
     pub fn finish(self) -> RouterFactory<S, Err> {
+        let mut handlers = self.handlers;
+        let mut default = self.default;
+        for middleware in &self.middleware {
+            handlers = handlers.into_iter().map(middleware).collect();
+            default = middleware(default);
+        }
+
         RouterFactory {
             router: self.router.finish(),
-            handlers: Rc::new(self.handlers),
-            default: self.default,
+            ranked: self.ranks.windows(2).any(|r| r[0] != r[1]),
+            handlers: Rc::new(handlers),
+            default,
+            patterns: self.patterns,
+            sys: self.sys,
+            eager_init: self.eager_init,
+            on_missing: self.on_missing,
+            inspect: self.inspect,
+            stats: Rc::new(Stats {
+                counters: self.names.iter().map(|_| Cell::new(0)).collect(),
+                names: self.names,
+                default: Cell::new(0),
+            }),
         }
     }
 }
 
+/// Ack merge strategy of resource group
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GroupAckMerge {
+    /// Publish fails if any service fails, first failed ack is used
+    FirstFailure,
+    /// Publish succeeds if any service succeeds, first successful ack is used
+    AnySuccess,
+}
+
+impl GroupAckMerge {
+    fn merge(self, mut acks: Vec<PublishAck>) -> PublishAck {
+        let success = self == GroupAckMerge::AnySuccess;
+        let idx = acks
+            .iter()
+            .position(|ack| (u8::from(ack.reason_code) < 0x80) == success)
+            .unwrap_or(0);
+        acks.swap_remove(idx)
+    }
+}
+
+struct GroupFactory<S, Err> {
+    factories: Rc<Vec<Handler<S, Err>>>,
+    merge: GroupAckMerge,
+}
+
+impl<S: 'static, Err: 'static> ServiceFactory<Publish, Session<S>> for GroupFactory<S, Err> {
+    type Response = PublishAck;
+    type Error = Err;
+    type InitError = Err;
+    type Service = GroupService<Err>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Service, Err>>>>;
+
+    fn new_service(&self, session: Session<S>) -> Self::Future {
+        let futs: Vec<_> =
+            self.factories.iter().map(|f| f.new_service(session.clone())).collect();
+        let merge = self.merge;
+
+        Box::pin(async move {
+            let mut services = Vec::with_capacity(futs.len());
+            for fut in futs {
+                services.push(fut.await?);
+            }
+            Ok(GroupService { services, merge })
+        })
+    }
+}
+
+struct GroupService<Err> {
+    services: Vec<HandlerService<Err>>,
+    merge: GroupAckMerge,
+}
+
+impl<Err: 'static> Service<Publish> for GroupService<Err> {
+    type Response = PublishAck;
+    type Error = Err;
+    type Future = Pin<Box<dyn Future<Output = Result<PublishAck, Err>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut not_ready = false;
+        for srv in &self.services {
+            if srv.poll_ready(cx)?.is_pending() {
+                not_ready = true;
+            }
+        }
+
+        if not_ready {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        let futs: Vec<_> = self.services.iter().map(|srv| srv.call(req.duplicate())).collect();
+        let merge = self.merge;
+
+        Box::pin(async move {
+            let mut acks = Vec::with_capacity(futs.len());
+            for res in join_all(futs).await {
+                acks.push(res?);
+            }
+            Ok(merge.merge(acks))
+        })
+    }
+}
+
+/// Pattern specificity, number of literal segments, then number of
+/// dynamic segments, patterns without tail segment win
+fn specificity(patterns: &[String]) -> u32 {
+    patterns
+        .iter()
+        .map(|pattern| {
+            let (mut literal, mut dynamic, mut tail) = (0u32, 0u32, false);
+            for segment in pattern.split('/') {
+                if segment.starts_with('{') && segment.ends_with('*') {
+                    tail = true;
+                } else if segment.contains('{') {
+                    dynamic += 1;
+                } else {
+                    literal += 1;
+                }
+            }
+            (literal.min(0xff) << 16) | (dynamic.min(0xff) << 8) | u32::from(!tail)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>, Publish, Session<S>> for Router<S, Err>
 where
     S: 'static,
@@ -81,9 +619,86 @@ where
 }
 
 pub struct RouterFactory<S, Err> {
-    router: ntex::router::Router<usize>,
+    router: ntex::router::Router<usize, Rank>,
+    /// Resources have different ranks
+    ranked: bool,
     handlers: Rc<Vec<Handler<S, Err>>>,
     default: Handler<S, Err>,
+    patterns: Vec<String>,
+    sys: Option<usize>,
+    eager_init: bool,
+    on_missing: MissingRouteAction,
+    inspect: Option<Inspect>,
+    stats: Rc<Stats>,
+}
+
+impl<S, Err> RouterFactory<S, Err> {
+    /// Get registered topic patterns
+    pub fn handler_names(&self) -> Vec<String> {
+        self.patterns.clone()
+    }
+
+    /// Get publish counters of all services created by this factory
+    pub fn stats(&self) -> RouterStats {
+        self.stats.snapshot()
+    }
+
+    /// Get registered routes, factory does not initialize resource services
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.stats
+            .names
+            .iter()
+            .map(|name| RouteInfo { pattern: name.clone(), is_initialized: false })
+            .collect()
+    }
+}
+
+/// Registered route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    /// Resource patterns, comma separated if resource has several patterns
+    pub pattern: String,
+    /// Resource service is created
+    pub is_initialized: bool,
+}
+
+/// Router publish counters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterStats {
+    /// Number of publishes per resource, resource is identified by its patterns
+    pub routes: Vec<(String, u64)>,
+    /// Number of publishes handled by default service
+    pub default_count: u64,
+}
+
+struct Stats {
+    names: Vec<String>,
+    counters: Vec<Cell<u64>>,
+    default: Cell<u64>,
+}
+
+impl Stats {
+    fn inc(&self, idx: usize) {
+        let cnt = &self.counters[idx];
+        cnt.set(cnt.get() + 1);
+    }
+
+    fn snapshot(&self) -> RouterStats {
+        RouterStats {
+            routes: self
+                .names
+                .iter()
+                .zip(self.counters.iter())
+                .map(|(name, cnt)| (name.clone(), cnt.get()))
+                .collect(),
+            default_count: self.default.get(),
+        }
+    }
+
+    fn reset(&self) {
+        self.counters.iter().for_each(|cnt| cnt.set(0));
+        self.default.set(0);
+    }
 }
 
 impl<S, Err> ServiceFactory<Publish, Session<S>> for RouterFactory<S, Err>
@@ -99,16 +714,37 @@ where
 
     fn new_service(&self, session: Session<S>) -> Self::Future {
         let router = self.router.clone();
+        let ranked = self.ranked;
+        let sys = self.sys;
+        let on_missing = self.on_missing;
+        let inspect = self.inspect.clone();
+        let stats = self.stats.clone();
         let factories = self.handlers.clone();
         let default_fut = self.default.new_service(session.clone());
+        let handlers_fut = if self.eager_init {
+            let futs: Vec<_> =
+                factories.iter().map(|f| f.new_service(session.clone())).collect();
+            Some(join_all(futs))
+        } else {
+            None
+        };
 
         Box::pin(async move {
             let default = default_fut.await?;
-            let handlers = (0..factories.len()).map(|_| None).collect();
+            let handlers = if let Some(fut) = handlers_fut {
+                fut.await.into_iter().map(|res| res.map(Some)).collect::<Result<_, _>>()?
+            } else {
+                (0..factories.len()).map(|_| None).collect()
+            };
 
             Ok(RouterService {
                 router,
+                ranked,
                 default,
+                sys,
+                on_missing,
+                inspect,
+                stats,
                 inner: Rc::new(Inner {
                     session,
                     factories,
@@ -124,8 +760,13 @@ where
 
 pub struct RouterService<S, Err> {
     inner: Rc<Inner<S, Err>>,
-    router: ntex::router::Router<usize>,
+    router: ntex::router::Router<usize, Rank>,
+    ranked: bool,
     default: HandlerService<Err>,
+    sys: Option<usize>,
+    on_missing: MissingRouteAction,
+    inspect: Option<Inspect>,
+    stats: Rc<Stats>,
 }
 
 struct Inner<S, Err> {
@@ -137,7 +778,85 @@ struct Inner<S, Err> {
     creating: Cell<bool>,
 }
 
+impl<S, Err> RouterService<S, Err> {
+    /// Get number of registered handlers
+    pub fn handler_count(&self) -> usize {
+        self.inner.factories.len()
+    }
+
+    /// Get number of initialized handlers
+    pub fn initialized_handler_count(&self) -> usize {
+        self.inner.handlers.borrow().iter().filter(|h| h.is_some()).count()
+    }
+
+    /// Get registered routes and initialization state of resource services
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.stats
+            .names
+            .iter()
+            .zip(self.inner.handlers.borrow().iter())
+            .map(|(name, hnd)| RouteInfo {
+                pattern: name.clone(),
+                is_initialized: hnd.is_some(),
+            })
+            .collect()
+    }
+
+    /// Get publish counters
+    ///
+    /// Counters are shared by all services created by the same factory.
+    pub fn stats(&self) -> RouterStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset publish counters
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Remove topic alias from alias table
+    ///
+    /// Returns `true` if alias was registered.
+    pub fn topic_alias_clear(&self, alias: NonZeroU16) -> bool {
+        self.inner.aliases.borrow_mut().remove(&alias).is_some()
+    }
+
+    /// Get number of registered topic aliases
+    pub fn topic_alias_count(&self) -> usize {
+        self.inner.aliases.borrow().len()
+    }
+}
+
 impl<S: 'static, Err: 'static> RouterService<S, Err> {
+    /// Find resource for topic, prefer resource with highest priority
+    /// and then most specific resource
+    fn recognize(&self, topic: &mut Path<ByteString>) -> Option<usize> {
+        if let Some(idx) = self.sys {
+            if topic.path().starts_with(SYS_PREFIX) {
+                return Some(idx);
+            }
+        }
+
+        // all resources have same rank, first match wins
+        if !self.ranked {
+            return self.router.recognize(topic).map(|(idx, _)| *idx);
+        }
+
+        // find highest rank of all matched resources
+        let rank = Cell::new(None);
+        self.router.recognize_checked(topic, |_, r| {
+            let r = r.copied().unwrap_or_default();
+            if rank.get().map(|v| r > v).unwrap_or(true) {
+                rank.set(Some(r));
+            }
+            false
+        });
+        let rank = rank.get()?;
+        self.router
+            .recognize_checked(topic, |_, r| r.copied().unwrap_or_default() == rank)
+            .map(|(idx, _)| *idx)
+    }
+
     fn create_handler(
         &self,
         idx: usize,
@@ -195,15 +914,25 @@ impl<S: 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
 
     fn call(&self, mut req: Publish) -> Self::Future {
         if !req.publish_topic().is_empty() {
-            if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            if let Some(idx) = self.recognize(req.topic_mut()) {
+                log::trace!(
+                    "Router matched topic {:?} to handler index {}, packet id: {:?}",
+                    req.publish_topic(),
+                    idx,
+                    req.id()
+                );
+                self.stats.inc(idx);
+                if let Some(ref inspect) = self.inspect {
+                    inspect(req.publish_topic(), Some(idx));
+                }
                 // save info for topic alias
                 if let Some(alias) = req.packet().properties.topic_alias {
-                    self.inner.aliases.borrow_mut().insert(alias, (*idx, req.topic().clone()));
+                    self.inner.aliases.borrow_mut().insert(alias, (idx, req.topic().clone()));
                 }
-                if let Some(hnd) = &self.inner.handlers.borrow()[*idx] {
+                if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
                     return hnd.call(req);
                 } else {
-                    return self.create_handler(*idx, req);
+                    return self.create_handler(idx, req);
                 }
             }
         }
@@ -211,7 +940,17 @@ impl<S: 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
         else if let Some(ref alias) = req.packet().properties.topic_alias {
             let aliases = self.inner.aliases.borrow();
             if let Some(item) = aliases.get(alias) {
+                log::trace!(
+                    "Router using topic alias {} -> {:?}, packet id: {:?}",
+                    alias,
+                    item.1,
+                    req.id()
+                );
+                self.stats.inc(item.0);
                 *req.topic_mut() = item.1.clone();
+                if let Some(ref inspect) = self.inspect {
+                    inspect(item.1.get_ref(), Some(item.0));
+                }
                 if let Some(hnd) = &self.inner.handlers.borrow()[item.0] {
                     return hnd.call(req);
                 } else {
@@ -221,6 +960,349 @@ impl<S: 'static, Err: 'static> Service<Publish> for RouterService<S, Err> {
                 log::error!("Unknown topic alias: {:?}", alias);
             }
         }
+
+        if let Some(ref inspect) = self.inspect {
+            inspect(req.publish_topic(), None);
+        }
+
+        match self.on_missing {
+            MissingRouteAction::UseDefault => (),
+            MissingRouteAction::AckWith(code) => {
+                log::trace!(
+                    "No route for topic {:?}, ack with {:?}",
+                    req.publish_topic(),
+                    code
+                );
+                return Box::pin(Ready::Ok(PublishAck::new(code)));
+            }
+            MissingRouteAction::DisconnectWith(code) => {
+                log::trace!("No route for topic {:?}, disconnect", req.publish_topic());
+                self.inner.session.sink().close_with_code(code);
+                return Box::pin(Ready::Ok(PublishAck::new(
+                    codec::PublishAckReason::UnspecifiedError,
+                )));
+            }
+        }
+        log::trace!(
+            "Router dispatching to default service for topic {:?}, packet id: {:?}",
+            req.publish_topic(),
+            req.id()
+        );
+        self.stats.default.set(self.stats.default.get() + 1);
         self.default.call(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::{io::Io, testing::IoTest, util::Bytes};
+
+    use super::*;
+    use crate::v5::{codec, shared::MqttShared, MqttSink};
+
+    fn publish(topic: &'static str) -> Publish {
+        publish_with_alias(topic, None)
+    }
+
+    fn publish_with_alias(topic: &'static str, alias: Option<u16>) -> Publish {
+        Publish::new(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: codec::PublishProperties {
+                topic_alias: alias.and_then(NonZeroU16::new),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn session() -> Session<()> {
+        let io = Io::new(IoTest::create().0);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+        Session::new((), MqttSink::new(Rc::new(shared)))
+    }
+
+    #[ntex::test]
+    async fn test_stats() {
+        let session = session();
+
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resources(["topic2", "topic3"], fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .finish();
+        let srv = factory.new_service(session).await.unwrap();
+
+        for topic in ["topic1", "topic2", "topic3", "topic3", "other"] {
+            srv.call(publish(topic)).await.unwrap();
+        }
+        let stats = RouterStats {
+            routes: vec![("topic1".to_string(), 1), ("topic2, topic3".to_string(), 3)],
+            default_count: 1,
+        };
+        assert_eq!(srv.stats(), stats);
+        assert_eq!(factory.stats(), stats);
+
+        srv.reset_stats();
+        assert_eq!(
+            srv.stats(),
+            RouterStats {
+                routes: vec![("topic1".to_string(), 0), ("topic2, topic3".to_string(), 0)],
+                default_count: 0,
+            }
+        );
+    }
+
+    #[ntex::test]
+    async fn test_eager_init() {
+        let inits = Rc::new(Cell::new(0));
+        let factory = |eager| {
+            let inits = inits.clone();
+            let router = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+                .resources(
+                    ["topic1", "topic2"],
+                    fn_factory_with_config(move |_: Session<()>| {
+                        inits.set(inits.get() + 1);
+                        Ready::Ok(fn_service(|p: Publish| Ready::Ok(p.ack())))
+                    }),
+                )
+                .resource("topic3", fn_service(|p: Publish| Ready::Ok(p.ack())));
+            if eager {
+                router.eager_init().finish()
+            } else {
+                router.finish()
+            }
+        };
+
+        let srv = factory(false).new_service(session()).await.unwrap();
+        assert_eq!(srv.initialized_handler_count(), 0);
+        assert_eq!(inits.get(), 0);
+        srv.call(publish("topic1")).await.unwrap();
+        assert_eq!(srv.initialized_handler_count(), 1);
+        assert_eq!(inits.get(), 1);
+
+        inits.set(0);
+        let srv = factory(true).new_service(session()).await.unwrap();
+        assert_eq!(srv.initialized_handler_count(), 2);
+        assert_eq!(inits.get(), 1);
+        srv.call(publish("topic1")).await.unwrap();
+        srv.call(publish("topic3")).await.unwrap();
+        assert_eq!(inits.get(), 1);
+    }
+
+    #[ntex::test]
+    async fn test_on_missing() {
+        let router = || {
+            Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+                .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+        };
+
+        let factory = router()
+            .on_missing(MissingRouteAction::AckWith(codec::PublishAckReason::TopicNameInvalid))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+        let ack = srv.call(publish("topic1")).await.unwrap();
+        assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+        let ack = srv.call(publish("other")).await.unwrap();
+        assert_eq!(ack.reason_code, codec::PublishAckReason::TopicNameInvalid);
+        assert_eq!(srv.stats().default_count, 0);
+
+        let (client, server) = IoTest::create();
+        client.remote_buffer_cap(1024);
+        let io = Io::new(server);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+        let session = Session::new((), MqttSink::new(Rc::new(shared)));
+        let factory = router()
+            .on_missing(MissingRouteAction::DisconnectWith(
+                codec::DisconnectReasonCode::TopicNameInvalid,
+            ))
+            .finish();
+        let srv = factory.new_service(session.clone()).await.unwrap();
+        srv.call(publish("topic1")).await.unwrap();
+        assert!(session.sink().is_open());
+        srv.call(publish("other")).await.unwrap();
+        assert!(!session.sink().is_open());
+    }
+
+    #[ntex::test]
+    async fn test_routes() {
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resources(["topic2", "topic3"], fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{id}", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .finish();
+        let route = |pattern: &str, is_initialized| RouteInfo {
+            pattern: pattern.to_string(),
+            is_initialized,
+        };
+        assert_eq!(
+            factory.routes(),
+            vec![
+                route("topic1", false),
+                route("topic2, topic3", false),
+                route("sensors/{id}", false)
+            ]
+        );
+
+        let srv = factory.new_service(session()).await.unwrap();
+        srv.call(publish("topic3")).await.unwrap();
+        assert_eq!(
+            srv.routes(),
+            vec![
+                route("topic1", false),
+                route("topic2, topic3", true),
+                route("sensors/{id}", false)
+            ]
+        );
+    }
+
+    #[ntex::test]
+    async fn test_inspect() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let log2 = log.clone();
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic2", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .inspect(move |topic, idx| log2.borrow_mut().push((topic.to_string(), idx)))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+
+        srv.call(publish_with_alias("topic2", Some(1))).await.unwrap();
+        srv.call(publish_with_alias("", Some(1))).await.unwrap();
+        srv.call(publish("topic1")).await.unwrap();
+        srv.call(publish("other")).await.unwrap();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                ("topic2".to_string(), Some(1)),
+                ("topic2".to_string(), Some(1)),
+                ("topic1".to_string(), Some(0)),
+                ("other".to_string(), None),
+            ]
+        );
+
+        // hook is called for publishes handled by missing route action
+        log.borrow_mut().clear();
+        let log2 = log.clone();
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .on_missing(MissingRouteAction::AckWith(codec::PublishAckReason::TopicNameInvalid))
+            .inspect(move |topic, idx| log2.borrow_mut().push((topic.to_string(), idx)))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+        srv.call(publish("other")).await.unwrap();
+        assert_eq!(*log.borrow(), vec![("other".to_string(), None)]);
+    }
+
+    struct Tag(&'static str, Rc<RefCell<Vec<String>>>);
+
+    impl<S> Transform<S> for Tag {
+        type Service = TagService<S>;
+
+        fn new_transform(&self, service: S) -> Self::Service {
+            TagService(self.0, self.1.clone(), service)
+        }
+    }
+
+    struct TagService<S>(&'static str, Rc<RefCell<Vec<String>>>, S);
+
+    impl<S: Service<Publish>> Service<Publish> for TagService<S> {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = S::Future;
+
+        fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+            self.2.poll_ready(cx)
+        }
+
+        fn call(&self, req: Publish) -> Self::Future {
+            self.1.borrow_mut().push(format!("{}:{}", self.0, req.publish_topic()));
+            self.2.call(req)
+        }
+    }
+
+    #[ntex::test]
+    async fn test_middleware() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .middleware(Tag("a", log.clone()))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .middleware(Tag("b", log.clone()))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+
+        srv.call(publish("topic1")).await.unwrap();
+        srv.call(publish("other")).await.unwrap();
+        assert_eq!(*log.borrow(), vec!["b:topic1", "a:topic1", "b:other", "a:other"]);
+    }
+
+    #[ntex::test]
+    async fn test_topic_alias_clear() {
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic1", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("topic2", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+
+        // reassign alias
+        srv.call(publish_with_alias("topic1", Some(1))).await.unwrap();
+        srv.call(publish_with_alias("topic2", Some(1))).await.unwrap();
+        assert_eq!(srv.topic_alias_count(), 1);
+        srv.reset_stats();
+
+        srv.call(publish_with_alias("", Some(1))).await.unwrap();
+        assert_eq!(
+            srv.stats().routes,
+            vec![("topic1".to_string(), 0), ("topic2".to_string(), 1)]
+        );
+
+        assert!(srv.topic_alias_clear(NonZeroU16::new(1).unwrap()));
+        assert!(!srv.topic_alias_clear(NonZeroU16::new(1).unwrap()));
+        assert_eq!(srv.topic_alias_count(), 0);
+
+        srv.call(publish_with_alias("", Some(1))).await.unwrap();
+        assert_eq!(srv.stats().routes[1], ("topic2".to_string(), 1));
+        assert_eq!(srv.stats().default_count, 1);
+    }
+
+    #[ntex::test]
+    async fn test_specificity() {
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{tail}*", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{id}/temp", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/room1/temp", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{id}/{kind}", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+
+        for topic in
+            ["sensors/room1/temp", "sensors/room2/temp", "sensors/room2/hum", "sensors/1"]
+        {
+            srv.call(publish(topic)).await.unwrap();
+        }
+        let counts: Vec<_> = srv.stats().routes.into_iter().map(|(_, cnt)| cnt).collect();
+        assert_eq!(counts, vec![1, 1, 1, 1]);
+
+        srv.reset_stats();
+        srv.call(publish("sensors/room1/temp")).await.unwrap();
+        let counts: Vec<_> = srv.stats().routes.into_iter().map(|(_, cnt)| cnt).collect();
+        assert_eq!(counts, vec![0, 0, 1, 0]);
+        assert!(srv.ranked);
+
+        // resources with same rank, single lookup pass
+        let factory = Router::<(), ()>::new(fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{id}/temp", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .resource("sensors/{id}/hum", fn_service(|p: Publish| Ready::Ok(p.ack())))
+            .finish();
+        let srv = factory.new_service(session()).await.unwrap();
+        assert!(!srv.ranked);
+
+        for topic in ["sensors/room1/temp", "sensors/room1/hum", "sensors/room1/hum"] {
+            srv.call(publish(topic)).await.unwrap();
+        }
+        let counts: Vec<_> = srv.stats().routes.into_iter().map(|(_, cnt)| cnt).collect();
+        assert_eq!(counts, vec![1, 2]);
+    }
+}
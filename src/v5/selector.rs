@@ -8,6 +8,7 @@ use ntex::time::{Deadline, Millis, Seconds};
 use ntex::util::{select, Either};
 
 use crate::error::{MqttError, ProtocolError};
+use crate::proxy_protocol;
 
 use super::control::{ControlMessage, ControlResult};
 use super::handshake::{Handshake, HandshakeAck};
@@ -30,6 +31,7 @@ pub struct Selector<Err, InitErr> {
     servers: Vec<ServerFactory<Err, InitErr>>,
     max_size: u32,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Err, InitErr)>,
 }
@@ -41,6 +43,7 @@ impl<Err, InitErr> Selector<Err, InitErr> {
             servers: Vec::new(),
             max_size: 0,
             handshake_timeout: Millis(10000),
+            proxy_protocol: false,
             pool: Default::default(),
             _t: marker::PhantomData,
         }
@@ -71,6 +74,9 @@ where
     }
 
     /// Add server variant
+    ///
+    /// PROXY protocol header is expected on new connections if any variant
+    /// has it enabled, see `MqttServer::proxy_protocol()`.
     pub fn variant<F, R, St, C, Cn, P>(
         mut self,
         check: F,
@@ -99,6 +105,7 @@ where
         PublishAck: TryFrom<P::Error, Error = C::Error>,
     {
         server.pool = self.pool.clone();
+        self.proxy_protocol |= server.proxy_protocol;
         self.servers.push(boxed::factory(server.finish_selector(check)));
         self
     }
@@ -113,6 +120,7 @@ where
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
         let pool = self.pool.clone();
 
         async move {
@@ -120,7 +128,13 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService { max_size, handshake_timeout, pool, servers: Rc::new(servers) })
+            Ok(SelectorService {
+                max_size,
+                handshake_timeout,
+                proxy_protocol,
+                pool,
+                servers: Rc::new(servers),
+            })
         }
     }
 }
@@ -178,6 +192,7 @@ pub struct SelectorService<Err> {
     servers: Rc<Vec<Server<Err>>>,
     max_size: u32,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -249,11 +264,17 @@ where
             0,
             self.pool.clone(),
         ));
+        let proxy_protocol = self.proxy_protocol;
 
         let mut timeout = Deadline::new(self.handshake_timeout);
         Box::pin(async move {
             // read first packet
             let result = select(&mut timeout, async {
+                // strip proxy protocol header
+                if proxy_protocol {
+                    shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+                }
+
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
@@ -326,10 +347,16 @@ where
             0,
             self.pool.clone(),
         ));
+        let proxy_protocol = self.proxy_protocol;
 
         Box::pin(async move {
             // read first packet
             let result = select(&mut timeout, async {
+                // strip proxy protocol header
+                if proxy_protocol {
+                    shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+                }
+
                 io.recv(&shared.codec)
                     .await
                     .map_err(|err| {
@@ -1,5 +1,6 @@
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
+use std::{convert::TryFrom, fmt, future::Future, marker::PhantomData, pin::Pin};
+use std::{rc::Rc, sync::Arc};
 
 use ntex::io::{DispatchItem, IoBoxed};
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
@@ -7,7 +8,8 @@ use ntex::time::{timeout_checked, Millis, Seconds};
 use ntex::util::{select, Either};
 
 use crate::error::{MqttError, ProtocolError};
-use crate::{io::Dispatcher, service, types::QoS};
+use crate::types::QoS;
+use crate::{io::Dispatcher, proxy_protocol, service, session::ConnectInfo};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
@@ -15,6 +17,7 @@ use super::handshake::{Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
 use super::selector::SelectItem;
 use super::shared::{MqttShared, MqttSinkPool};
+use super::store::SubscriptionStore;
 use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
 
 /// Mqtt Server
@@ -29,6 +32,10 @@ pub struct MqttServer<St, C, Cn, P> {
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    keep_alive_max: u16,
+    keep_alive_tolerance: f32,
+    subscription_store: Option<Arc<dyn SubscriptionStore>>,
+    pub(super) proxy_protocol: bool,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -55,6 +62,10 @@ where
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
             max_topic_alias: 32,
+            keep_alive_max: 0,
+            keep_alive_tolerance: 1.5,
+            subscription_store: None,
+            proxy_protocol: false,
             pool: Rc::new(MqttSinkPool::default()),
             _t: PhantomData,
         }
@@ -79,6 +90,17 @@ where
         self
     }
 
+    /// Expect PROXY protocol v1/v2 header on new connections.
+    ///
+    /// Header is stripped before mqtt handshake, client address from header is
+    /// returned by `Session::peer_addr()`. Connections without header are rejected.
+    /// If server is used as `Selector` variant, selector strips the header for all
+    /// variants. By default PROXY protocol is disabled.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -118,6 +140,32 @@ where
         self
     }
 
+    /// Set keep-alive tolerance multiplier.
+    ///
+    /// Connection is closed if client does not send any packet within
+    /// keep-alive interval multiplied by tolerance. By default tolerance is
+    /// set to 1.5. Panics if multiplier is less than 1.0
+    pub fn keep_alive_tolerance(mut self, multiplier: f32) -> Self {
+        if multiplier.is_nan() || multiplier < 1.0 {
+            panic!("Keep-alive tolerance must be greater or equal to 1.0")
+        }
+        self.keep_alive_tolerance = multiplier;
+        self
+    }
+
+    /// Set max keep-alive interval in seconds.
+    ///
+    /// If client requests greater keep-alive interval, server sets
+    /// `server_keepalive_sec` property of `ConnectAck` to max value.
+    /// By default keep-alive interval is not limited. Panics if value is `0`.
+    pub fn keep_alive_max(mut self, secs: u16) -> Self {
+        if secs == 0 {
+            panic!("Max keep-alive must be greater than 0")
+        }
+        self.keep_alive_max = secs;
+        self
+    }
+
     /// Set server max qos setting.
     ///
     /// By default max qos is not set`
@@ -134,6 +182,15 @@ where
         self
     }
 
+    /// Set subscriptions storage.
+    ///
+    /// Subscriptions acked by control service are added to the storage,
+    /// successfully unsubscribed filters are removed from it.
+    pub fn subscription_store<T: SubscriptionStore + 'static>(mut self, store: Arc<T>) -> Self {
+        self.subscription_store = Some(store);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -152,10 +209,14 @@ where
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
+            keep_alive_max: self.keep_alive_max,
+            keep_alive_tolerance: self.keep_alive_tolerance,
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscription_store: self.subscription_store,
+            proxy_protocol: self.proxy_protocol,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -177,10 +238,14 @@ where
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
+            keep_alive_max: self.keep_alive_max,
+            keep_alive_tolerance: self.keep_alive_tolerance,
             max_qos: self.max_qos,
             max_inflight_size: self.max_inflight_size,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            subscription_store: self.subscription_store,
+            proxy_protocol: self.proxy_protocol,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -228,12 +293,20 @@ where
                 max_size: self.max_size,
                 max_receive: self.max_receive,
                 max_topic_alias: self.max_topic_alias,
+                keep_alive_max: self.keep_alive_max,
+                keep_alive_tolerance: self.keep_alive_tolerance,
                 max_qos: self.max_qos,
                 handshake_timeout: self.handshake_timeout.into(),
+                proxy_protocol: self.proxy_protocol,
                 pool: self.pool,
                 _t: PhantomData,
             },
-            factory(self.srv_publish, self.srv_control, self.max_inflight_size),
+            factory(
+                self.srv_publish,
+                self.srv_control,
+                self.max_inflight_size,
+                self.subscription_store,
+            ),
             self.disconnect_timeout,
         )
     }
@@ -259,10 +332,13 @@ where
                 self.srv_publish,
                 self.srv_control,
                 self.max_inflight_size,
+                self.subscription_store,
             )),
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
+            keep_alive_max: self.keep_alive_max,
+            keep_alive_tolerance: self.keep_alive_tolerance,
             max_qos: self.max_qos,
             disconnect_timeout: self.disconnect_timeout,
             _t: PhantomData,
@@ -275,8 +351,11 @@ struct HandshakeFactory<St, H> {
     max_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
+    keep_alive_max: u16,
+    keep_alive_tolerance: f32,
     max_qos: Option<QoS>,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -298,9 +377,12 @@ where
         let max_size = self.max_size;
         let max_receive = self.max_receive;
         let max_topic_alias = self.max_topic_alias;
+        let keep_alive_max = self.keep_alive_max;
+        let keep_alive_tolerance = self.keep_alive_tolerance;
         let max_qos = self.max_qos;
         let pool = self.pool.clone();
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
 
         Box::pin(async move {
             let service = fut.await?;
@@ -308,8 +390,11 @@ where
                 max_size,
                 max_receive,
                 max_topic_alias,
+                keep_alive_max,
+                keep_alive_tolerance,
                 max_qos,
                 handshake_timeout,
+                proxy_protocol,
                 pool,
                 service: Rc::new(service),
                 _t: PhantomData,
@@ -323,8 +408,11 @@ struct HandshakeService<St, H> {
     max_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
+    keep_alive_max: u16,
+    keep_alive_tolerance: f32,
     max_qos: Option<QoS>,
     handshake_timeout: Millis,
+    proxy_protocol: bool,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -356,10 +444,18 @@ where
         let max_size = self.max_size;
         let mut max_receive = self.max_receive;
         let mut max_topic_alias = self.max_topic_alias;
+        let keep_alive_max = self.keep_alive_max;
+        let keep_alive_tolerance = self.keep_alive_tolerance;
         let max_qos = self.max_qos;
         let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
 
         let f = async move {
+            // strip proxy protocol header
+            if proxy_protocol {
+                shared.proxy_addr.set(proxy_protocol::strip_header(&io).await?);
+            }
+
             // read first packet
             let packet = io
                 .recv(&shared.codec)
@@ -380,21 +476,29 @@ where
                         shared.codec.set_max_outbound_size(size.get());
                     }
                     shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                    shared.topic_alias_max.set(connect.topic_alias_max);
 
                     let keep_alive = connect.keep_alive;
+                    let mut info = ConnectInfo {
+                        client_id: connect.client_id.clone(),
+                        clean_start: connect.clean_start,
+                        keep_alive,
+                        session_expiry: connect.session_expiry_interval_secs.unwrap_or(0),
+                    };
+
+                    let mut hnd = Handshake::new(
+                        connect,
+                        io,
+                        shared,
+                        max_size,
+                        max_receive,
+                        max_topic_alias,
+                    );
+                    hnd.keep_alive_max = keep_alive_max;
+                    hnd.keep_alive_tolerance = keep_alive_tolerance;
 
                     // authenticate mqtt connection
-                    let mut ack = service
-                        .call(Handshake::new(
-                            connect,
-                            io,
-                            shared,
-                            max_size,
-                            max_receive,
-                            max_topic_alias,
-                        ))
-                        .await
-                        .map_err(MqttError::Service)?;
+                    let mut ack = service.call(hnd).await.map_err(MqttError::Service)?;
 
                     match ack.session {
                         Some(session) => {
@@ -421,6 +525,10 @@ where
                                 ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                             }
 
+                            if let Some(ref id) = ack.packet.assigned_client_id {
+                                info.client_id = id.clone();
+                            }
+
                             ack.io
                                 .send(
                                     mqtt::Packet::ConnectAck(Box::new(ack.packet)),
@@ -436,6 +544,7 @@ where
                                     MqttSink::new(shared),
                                     max_receive,
                                     max_topic_alias,
+                                    info,
                                 ),
                                 Seconds(ack.keepalive),
                             ))
@@ -483,6 +592,8 @@ pub(crate) struct ServerSelector<St, C, T, F, R> {
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    keep_alive_max: u16,
+    keep_alive_tolerance: f32,
     _t: PhantomData<(St, R)>,
 }
 
@@ -515,6 +626,8 @@ where
         let max_receive = self.max_receive;
         let max_qos = self.max_qos;
         let max_topic_alias = self.max_topic_alias;
+        let keep_alive_max = self.keep_alive_max;
+        let keep_alive_tolerance = self.keep_alive_tolerance;
         let disconnect_timeout = self.disconnect_timeout;
 
         // create connect service and then create service impl
@@ -526,6 +639,8 @@ where
                 max_receive,
                 max_qos,
                 max_topic_alias,
+                keep_alive_max,
+                keep_alive_tolerance,
                 disconnect_timeout,
                 connect: Rc::new(fut.await?),
                 _t: PhantomData,
@@ -543,6 +658,8 @@ pub(crate) struct ServerSelectorImpl<St, C, T, F, R> {
     max_qos: Option<QoS>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    keep_alive_max: u16,
+    keep_alive_tolerance: f32,
     _t: PhantomData<(St, R)>,
 }
 
@@ -587,6 +704,8 @@ where
         let max_size = self.max_size;
         let mut max_receive = self.max_receive;
         let mut max_topic_alias = self.max_topic_alias;
+        let keep_alive_max = self.keep_alive_max;
+        let keep_alive_tolerance = self.keep_alive_tolerance;
 
         Box::pin(async move {
             let (mut hnd, mut delay) = req;
@@ -606,11 +725,20 @@ where
                 hnd.shared
                     .cap
                     .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                hnd.shared.topic_alias_max.set(hnd.packet().topic_alias_max);
 
                 let keep_alive = hnd.packet().keep_alive;
+                let mut info = ConnectInfo {
+                    client_id: hnd.packet().client_id.clone(),
+                    clean_start: hnd.packet().clean_start,
+                    keep_alive,
+                    session_expiry: hnd.packet().session_expiry_interval_secs.unwrap_or(0),
+                };
                 hnd.max_size = max_size;
                 hnd.max_receive = max_receive;
                 hnd.max_topic_alias = max_topic_alias;
+                hnd.keep_alive_max = keep_alive_max;
+                hnd.keep_alive_tolerance = keep_alive_tolerance;
 
                 // authenticate mqtt connection
                 let mut ack = match select(connect.call(hnd), &mut delay).await {
@@ -646,6 +774,10 @@ where
                             ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                         }
 
+                        if let Some(ref id) = ack.packet.assigned_client_id {
+                            info.client_id = id.clone();
+                        }
+
                         ack.io
                             .send(mqtt::Packet::ConnectAck(Box::new(ack.packet)), &shared.codec)
                             .await?;
@@ -655,6 +787,7 @@ where
                             MqttSink::new(shared.clone()),
                             max_receive,
                             max_topic_alias,
+                            info,
                         );
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
@@ -1,13 +1,20 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::cell::{Cell, RefCell, RefMut};
+use std::time::{Duration, Instant};
+use std::{collections::VecDeque, fmt, net::SocketAddr, num::NonZeroU16, rc::Rc};
 
-use ntex::channel::pool;
+use ntex::channel::{oneshot, pool};
 use ntex::codec::{Decoder, Encoder};
 use ntex::io::IoRef;
-use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
+use ntex::task::LocalWaker;
+use ntex::time::{sleep, Millis};
+use ntex::util::{ByteString, BytesMut, HashMap, HashSet, PoolId, PoolRef};
 
 use super::codec;
 use crate::{error, types::packet_type};
 
+/// Max outbound publish rate limit, messages per second
+const MAX_RATE_LIMIT: u32 = 1_000_000_000;
+
 pub struct MqttShared {
     pub(super) io: IoRef,
     pub(super) cap: Cell<usize>,
@@ -15,12 +22,76 @@ pub struct MqttShared {
     pub(super) inflight_idx: Cell<u16>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) codec: codec::Codec,
+    pub(super) topic_alias_max: Cell<u16>,
+    topic_aliases: RefCell<Option<TopicAliasMap>>,
+    pub(super) closing: Cell<bool>,
+    /// Overload is reported, reset once all credit waiters are woken up
+    overloaded: Cell<bool>,
+    /// Overload notification, set by server dispatcher
+    pub(super) on_overload: RefCell<Option<Box<dyn Fn(usize, usize)>>>,
+    pub(super) drain: LocalWaker,
+    pub(super) subscriptions: RefCell<Vec<(ByteString, codec::SubscriptionOptions)>>,
+    /// Keep copies of un-acked publish packets, required for session snapshot
+    pub(super) track_packets: Cell<bool>,
+    /// Outbound publish rate limit, messages per second (0 - disabled)
+    rate_limit: Cell<u32>,
+    /// Token bucket state, available tokens and last refill time
+    rate_bucket: Cell<(u32, Instant)>,
+    /// Server's connect ack, client connections only
+    pub(super) server_properties: Option<Box<codec::ConnectAck>>,
+    /// Client address from PROXY protocol header
+    pub(super) proxy_addr: Cell<Option<SocketAddr>>,
+    /// Encoded outbound bytes, handshake packets are not included
+    bytes_sent: Cell<u64>,
+    /// Decoded inbound bytes, handshake packets are not included
+    bytes_received: Cell<u64>,
 }
 
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    /// Un-acked QoS-1 and QoS-2 (before PUBREC) publish packets,
+    /// kept for session snapshot if packet tracking is enabled
+    pub(super) inflight_packets: HashMap<u16, codec::Publish>,
+    /// Ids of retransmitted QoS-1 publish packets
+    pub(super) retransmitted: HashSet<u16>,
+    /// Ids of released QoS-2 publish packets waiting for PUBCOMP,
+    /// kept for session snapshot if packet tracking is enabled
+    pub(super) released: HashSet<u16>,
+    /// Restored session packets waiting for credit
+    pub(super) restored: VecDeque<Restored>,
+    /// Reserved PUBCOMP channels of QoS-2 publishes waiting for PUBREC
+    pub(super) pubcomp: HashMap<u16, pool::Sender<Ack>>,
+    /// Acked retransmitted QoS-1 packets and released packet ids,
+    /// peer could still send ack for them, such ack is ignored once
+    pub(super) dup_acks: HashSet<u16>,
+    /// Ping round-trip waiters, notified with PINGRESP arrival time,
+    /// `None` for keep-alive pings
+    pub(super) ping_waiters: VecDeque<Option<oneshot::Sender<Instant>>>,
+}
+
+impl MqttSharedQueues {
+    /// Ids of un-acked publish packets in send order
+    pub(super) fn inflight_packet_ids(&self) -> Vec<u16> {
+        let mut ids: Vec<_> = self.inflight_packets.keys().copied().collect();
+        ids.sort_by_key(|id| self.inflight_order.iter().position(|i| i == id));
+        ids
+    }
+}
+
+/// Restored session packet
+pub(super) enum Restored {
+    /// Released QoS-2 packet id, waiting for PUBCOMP
+    Release(NonZeroU16),
+    /// Un-acked publish packet
+    Publish(Box<codec::Publish>),
+}
+
+/// Outbound topic aliases
+struct TopicAliasMap {
+    max: u16,
+    aliases: HashMap<ByteString, NonZeroU16>,
 }
 
 pub(super) struct MqttSinkPool {
@@ -55,30 +126,228 @@ impl MqttShared {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
                 waiters: VecDeque::new(),
+                ping_waiters: VecDeque::new(),
+                inflight_packets: HashMap::default(),
+                pubcomp: HashMap::default(),
+                dup_acks: HashSet::default(),
+                retransmitted: HashSet::default(),
+                released: HashSet::default(),
+                restored: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
+            topic_alias_max: Cell::new(0),
+            topic_aliases: RefCell::new(None),
+            closing: Cell::new(false),
+            overloaded: Cell::new(false),
+            on_overload: RefCell::new(None),
+            drain: LocalWaker::new(),
+            subscriptions: RefCell::new(Vec::new()),
+            track_packets: Cell::new(false),
+            server_properties: None,
+            proxy_addr: Cell::new(None),
+            bytes_sent: Cell::new(0),
+            bytes_received: Cell::new(0),
+            rate_limit: Cell::new(0),
+            rate_bucket: Cell::new((0, Instant::now())),
         }
     }
 
+    /// Run closure with mutable access to queues
+    ///
+    /// Queues must not be accessed from within the closure, nested borrow panics.
+    /// If the closure panics, the panic is propagated and the borrow is released
+    /// during unwinding.
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
-        let mut queues = self.queues.borrow_mut();
-        f(&mut queues)
+        f(&mut self.queues_mut())
+    }
+
+    /// Mutable borrow guard for queues, allows several operations under one borrow
+    ///
+    /// Guard must be dropped before any other queues access, including `with_queues()`.
+    pub(super) fn queues_mut(&self) -> RefMut<'_, MqttSharedQueues> {
+        self.queues.borrow_mut()
+    }
+
+    pub(super) fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.get()
+    }
+
+    pub(super) fn bytes_received(&self) -> u64 {
+        self.bytes_received.get()
+    }
+
+    /// Account bytes written to io write buffer bypassing `Encoder` impl
+    pub(super) fn add_bytes_sent(&self, size: usize) {
+        self.bytes_sent.set(self.bytes_sent.get().saturating_add(size as u64));
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        self.cap.get().saturating_sub(self.queues.borrow().inflight.len()) > 0
+    }
+
+    /// Wait for available credit, overload is reported if sink was not overloaded
+    pub(super) fn wait_credit(&self) -> pool::Receiver<()> {
+        let (tx, rx) = self.pool.waiters.channel();
+        let inflight = self.with_queues(|q| {
+            q.waiters.push_back(tx);
+            q.inflight.len()
+        });
+        if !self.overloaded.replace(true) {
+            if let Some(ref f) = *self.on_overload.borrow() {
+                f(inflight, self.cap.get());
+            }
+        }
+        rx
+    }
+
+    /// Wake up next credit waiter
+    pub(super) fn wake_waiter(&self, queues: &mut MqttSharedQueues) {
+        while let Some(tx) = queues.waiters.pop_front() {
+            if tx.send(()).is_ok() {
+                break;
+            }
+        }
+        if queues.waiters.is_empty() {
+            self.overloaded.set(false);
+        }
+    }
+
+    /// Set outbound publish rate limit, bucket starts full
+    pub(super) fn set_rate_limit(&self, messages_per_second: u32) {
+        // token period must not be shorter than 1ns
+        let messages_per_second = messages_per_second.min(MAX_RATE_LIMIT);
+        self.rate_limit.set(messages_per_second);
+        self.rate_bucket.set((messages_per_second, Instant::now()));
+    }
+
+    pub(super) fn is_rate_limited(&self) -> bool {
+        self.rate_limit.get() != 0
+    }
+
+    /// Take token from rate limit bucket
+    ///
+    /// Returns time to wait for next token if bucket is empty.
+    pub(super) fn take_rate_token(&self) -> Option<Duration> {
+        self.take_rate_tokens(1)
+    }
+
+    /// Take `n` tokens from rate limit bucket, nothing is taken if bucket
+    /// does not have enough tokens
+    ///
+    /// Returns time to wait for missing tokens.
+    pub(super) fn take_rate_tokens(&self, n: u32) -> Option<Duration> {
+        let rate = self.rate_limit.get();
+        if rate == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let (mut tokens, mut last) = self.rate_bucket.get();
+        let period = Duration::from_secs(1) / rate;
+        let refill = (now.duration_since(last).as_nanos() / period.as_nanos()) as u32;
+        if refill > 0 {
+            tokens = tokens.saturating_add(refill);
+            if tokens >= rate {
+                tokens = rate;
+                last = now;
+            } else {
+                last += period * refill;
+            }
+        }
+
+        if tokens >= n {
+            self.rate_bucket.set((tokens - n, last));
+            None
+        } else {
+            self.rate_bucket.set((tokens, last));
+            Some((period * (n - tokens)).saturating_sub(now.duration_since(last)))
+        }
+    }
+
+    /// Wait until rate limit bucket has available token
+    pub(super) async fn wait_rate_token(&self) {
+        while let Some(delay) = self.take_rate_token() {
+            sleep(Millis::from(delay).max(Millis(1))).await;
+        }
+    }
+
+    /// Remove in-flight packet, ack order slot gets marked as errored
+    ///
+    /// Late ack from peer for released packet id is ignored.
+    pub(super) fn remove_inflight(&self, idx: u16) -> bool {
+        let mut queues = self.queues.borrow_mut();
+        if queues.inflight.remove(&idx).is_some() {
+            queues.dup_acks.insert(idx);
+            queues.inflight_packets.remove(&idx);
+            queues.retransmitted.remove(&idx);
+            queues.released.remove(&idx);
+            queues.pubcomp.remove(&idx);
+            if let Some(item) = queues.inflight_order.iter_mut().find(|i| **i == idx) {
+                *item = 0;
+            }
+            self.drain.wake();
+
+            // wake up queued request (receive max limit)
+            self.wake_waiter(&mut queues);
+            true
+        } else {
+            false
+        }
     }
 
-    pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
+    /// Enable outbound topic aliases, max is limited by peer's topic alias maximum
+    pub(super) fn enable_topic_aliases(&self, max: u16) {
+        let max = max.min(self.topic_alias_max.get());
+        *self.topic_aliases.borrow_mut() = if max > 0 {
+            Some(TopicAliasMap { max, aliases: HashMap::default() })
         } else {
-            self.inflight_idx.set(idx);
-            idx
+            None
+        };
+    }
+
+    /// Assign topic alias to publish packet
+    pub(super) fn set_topic_alias(&self, packet: &mut codec::Publish) {
+        if packet.properties.topic_alias.is_some() || packet.topic.is_empty() {
+            return;
         }
+
+        if let Some(ref mut map) = *self.topic_aliases.borrow_mut() {
+            if let Some(alias) = map.aliases.get(&packet.topic) {
+                // peer knows topic, send alias only
+                packet.properties.topic_alias = Some(*alias);
+                packet.topic = ByteString::new();
+            } else if map.aliases.len() < map.max as usize {
+                let alias = NonZeroU16::new(map.aliases.len() as u16 + 1).unwrap();
+                map.aliases.insert(packet.topic.clone(), alias);
+                packet.properties.topic_alias = Some(alias);
+            }
+        }
+    }
+
+    /// Allocate next free packet id, packet id 0 is skipped
+    pub(super) fn next_id(&self) -> Result<NonZeroU16, error::EncodeError> {
+        let queues = self.queues.borrow();
+        for _ in 0..u16::MAX {
+            let idx = NonZeroU16::new(self.inflight_idx.get().wrapping_add(1))
+                .unwrap_or_else(|| NonZeroU16::new(1).unwrap());
+            self.inflight_idx.set(idx.get());
+            if !queues.inflight.contains_key(&idx.get()) {
+                return Ok(idx);
+            }
+        }
+        Err(error::EncodeError::PacketIdsExhausted)
+    }
+}
+
+impl fmt::Debug for MqttShared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("MqttShared");
+        f.field("cap", &self.cap.get()).field("closed", &self.io.is_closed());
+        match self.queues.try_borrow() {
+            Ok(q) => f.field("inflight", &q.inflight.len()),
+            Err(_) => f.field("inflight", &format_args!("<borrowed>")),
+        };
+        f.finish()
     }
 }
 
@@ -88,7 +357,10 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        let len = dst.len();
+        self.codec.encode(item, dst)?;
+        self.add_bytes_sent(dst.len() - len);
+        Ok(())
     }
 }
 
@@ -98,7 +370,12 @@ impl Decoder for MqttShared {
 
     #[inline]
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.codec.decode(src)
+        // codec consumes fixed header before packet body is available
+        let len = src.len();
+        let result = self.codec.decode(src);
+        let size = (len - src.len()) as u64;
+        self.bytes_received.set(self.bytes_received.get().saturating_add(size));
+        result
     }
 }
 
@@ -189,3 +466,91 @@ impl AckType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::{io::Io, testing::IoTest};
+
+    use super::*;
+
+    #[ntex::test]
+    async fn test_next_id() {
+        let io = Io::new(IoTest::create().0);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+
+        assert_eq!(shared.next_id().unwrap().get(), 1);
+
+        // wrap around skips 0
+        shared.inflight_idx.set(u16::MAX);
+        assert_eq!(shared.next_id().unwrap().get(), 1);
+
+        // skip in-flight ids
+        let (tx, _rx) = shared.pool.queue.channel();
+        shared.with_queues(|q| q.inflight.insert(2, (tx, AckType::Publish)));
+        assert_eq!(shared.next_id().unwrap().get(), 3);
+
+        // all ids are in use
+        shared.with_queues(|q| {
+            for idx in 1..=u16::MAX {
+                let (tx, _) = shared.pool.queue.channel();
+                q.inflight.insert(idx, (tx, AckType::Publish));
+            }
+        });
+        assert_eq!(shared.next_id(), Err(error::EncodeError::PacketIdsExhausted));
+    }
+
+    #[ntex::test]
+    async fn test_rate_limit_max() {
+        let io = Io::new(IoTest::create().0);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+
+        shared.set_rate_limit(u32::MAX);
+        assert_eq!(shared.rate_limit.get(), MAX_RATE_LIMIT);
+        assert_eq!(shared.take_rate_token(), None);
+    }
+
+    #[ntex::test]
+    async fn test_rate_limit_tokens() {
+        let io = Io::new(IoTest::create().0);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+
+        shared.set_rate_limit(10);
+        assert!(shared.take_rate_tokens(11).is_some());
+        assert_eq!(shared.take_rate_tokens(8), None);
+        // not enough tokens, bucket is not changed
+        assert!(shared.take_rate_tokens(3).is_some());
+        assert_eq!(shared.take_rate_tokens(2), None);
+        assert!(shared.take_rate_token().is_some());
+    }
+
+    #[ntex::test]
+    async fn test_queues_borrow() {
+        let io = Io::new(IoTest::create().0);
+        let shared = MqttShared::new(io.get_ref(), codec::Codec::default(), 16, Rc::default());
+
+        // guard allows several operations under one borrow
+        {
+            let mut queues = shared.queues_mut();
+            let (tx, _rx) = shared.pool.queue.channel();
+            queues.inflight.insert(1, (tx, AckType::Publish));
+            queues.inflight_order.push_back(1);
+            assert!(shared.queues.try_borrow_mut().is_err());
+            assert!(format!("{:?}", shared).contains("inflight: <borrowed>"));
+        }
+        assert_eq!(shared.with_queues(|q| q.inflight.len()), 1);
+
+        // panic in closure is propagated, borrow is released
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            shared.with_queues(|_| panic!("test"))
+        }));
+        assert!(res.is_err());
+        assert!(shared.queues.try_borrow_mut().is_ok());
+
+        // nested access panics
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            shared.with_queues(|_| shared.with_queues(|_| ()))
+        }));
+        assert!(res.is_err());
+        assert!(shared.queues.try_borrow_mut().is_ok());
+    }
+}
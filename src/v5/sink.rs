@@ -1,12 +1,25 @@
+use std::collections::hash_map::RandomState;
 use std::future::{ready, Future};
-use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
-
-use ntex::time::{timeout, Millis};
-use ntex::util::{poll_fn, ByteString, Bytes, Either, Ready};
-
-use super::codec;
-use super::error::{ProtocolError, PublishQos1Error, PublishQos2Error, SendPacketError};
-use super::shared::{Ack, AckType, MqttShared};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+use std::{fmt, mem, net::SocketAddr, num::NonZeroU16, num::NonZeroU32, rc::Rc, task::Poll};
+
+use ntex::channel::{oneshot, pool, Canceled};
+use ntex::codec::Encoder;
+use ntex::io::types::PeerAddr;
+use ntex::time::{sleep, Millis};
+use ntex::util::{join_all, poll_fn, ByteString, Bytes, Either, Ready};
+use serde::Serialize;
+use serde_json::Error as JsonError;
+
+use super::error::{
+    EncodeError, ProtocolError, PublishQos1Error, PublishQos2Error, ResubscribeError,
+    SendAnyError, SendPacketError, TimeoutError,
+};
+use super::shared::{Ack, AckType, MqttShared, Restored};
+use super::{codec, publish::Publish};
+use crate::session::SessionSnapshot;
+use crate::topic::{validate_topic_filter, TopicFilterError};
 use crate::types::QoS;
 
 pub struct MqttSink(Rc<MqttShared>);
@@ -17,11 +30,65 @@ impl Clone for MqttSink {
     }
 }
 
+impl PartialEq for MqttSink {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MqttSink {}
+
+impl Hash for MqttSink {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state)
+    }
+}
+
 impl MqttSink {
     pub(super) fn new(state: Rc<MqttShared>) -> Self {
         MqttSink(state)
     }
 
+    /// Get connection identifier
+    ///
+    /// Identifier is stable for the lifetime of the connection and is shared
+    /// by all clones of the sink. Value is only meaningful within current
+    /// process and must not be persisted.
+    pub fn connection_id(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    /// Peer address, address from PROXY protocol header takes precedence
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0
+            .proxy_addr
+            .get()
+            .or_else(|| self.0.io.query::<PeerAddr>().get().map(PeerAddr::into_inner))
+    }
+
+    /// Number of bytes sent to peer, saturates at `u64::MAX`
+    ///
+    /// Counts all encoded packets after handshake, including acks
+    /// and pings sent by dispatcher.
+    pub fn bytes_sent(&self) -> u64 {
+        self.0.bytes_sent()
+    }
+
+    /// Number of bytes received from peer, saturates at `u64::MAX`
+    ///
+    /// Counts all decoded packets after handshake.
+    pub fn bytes_received(&self) -> u64 {
+        self.0.bytes_received()
+    }
+
+    /// Get server's `ConnectAck` packet
+    ///
+    /// Contains connection parameters negotiated by server, like receive maximum,
+    /// max packet size or topic alias maximum. Available for client connections only.
+    pub fn server_properties(&self) -> Option<&codec::ConnectAck> {
+        self.0.server_properties.as_deref()
+    }
+
     /// Check connection status
     pub fn is_open(&self) -> bool {
         !self.0.io.is_closed()
@@ -30,7 +97,71 @@ impl MqttSink {
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
         let cap = self.0.cap.get();
-        cap - self.0.with_queues(|q| q.inflight.len())
+        cap.saturating_sub(self.0.with_queues(|q| q.inflight.len()))
+    }
+
+    /// Get max number of in-flight packets
+    pub fn max_inflight(&self) -> usize {
+        self.0.cap.get()
+    }
+
+    /// Call function with reference to connection's codec
+    ///
+    /// Codec limits could be changed at runtime, setters use interior mutability.
+    pub fn with_codec<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&codec::Codec) -> R,
+    {
+        f(&self.0.codec)
+    }
+
+    /// Get number of in-flight packets
+    pub fn inflight_count(&self) -> usize {
+        if self.0.io.is_closed() {
+            0
+        } else {
+            self.0.with_queues(|q| q.inflight.len())
+        }
+    }
+
+    /// Get sorted list of in-flight packet ids
+    pub fn inflight_packet_ids(&self) -> Vec<u16> {
+        let mut ids: Vec<_> = self.0.with_queues(|q| q.inflight.keys().copied().collect());
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Get in-flight packet ids in the order acks are expected
+    pub fn inflight_order(&self) -> Vec<u16> {
+        self.0.with_queues(|q| q.inflight_order.iter().copied().filter(|id| *id != 0).collect())
+    }
+
+    /// Remove in-flight packet
+    ///
+    /// Pending request for the packet fails with `Disconnected` error,
+    /// late ack from peer is ignored. Returns `true` if packet was in-flight.
+    pub fn cancel_inflight(&self, packet_id: NonZeroU16) -> bool {
+        self.0.remove_inflight(packet_id.get())
+    }
+
+    /// Limit rate of outgoing publish packets
+    ///
+    /// Token bucket with capacity of `messages_per_second` is used.
+    /// QoS 1 and QoS 2 publishes wait for available token, QoS 0 publish
+    /// fails with `SendPacketError::RateLimited` if bucket is empty, see also
+    /// [`MqttSink::publish_batch`].
+    /// Zero disables rate limiting, values above `1_000_000_000` are clamped.
+    pub fn rate_limit(&self, messages_per_second: u32) {
+        self.0.set_rate_limit(messages_per_second);
+    }
+
+    /// Get number of publish/subscribe requests waiting for credit
+    pub fn waiter_count(&self) -> usize {
+        if self.0.io.is_closed() {
+            0
+        } else {
+            self.0.with_queues(|q| q.waiters.len())
+        }
     }
 
     /// Get notification when packet could be send to the peer.
@@ -38,56 +169,371 @@ impl MqttSink {
     /// Result indicates if connection is alive
     pub fn ready(&self) -> impl Future<Output = bool> {
         if !self.0.io.is_closed() {
-            self.0
-                .with_queues(|q| {
-                    if q.inflight.len() >= self.0.cap.get() {
-                        let (tx, rx) = self.0.pool.waiters.channel();
-                        q.waiters.push_back(tx);
-                        return Some(rx);
-                    }
-                    None
-                })
-                .map(|rx| Either::Right(async move { rx.await.is_ok() }))
-                .unwrap_or_else(|| Either::Left(ready(true)))
+            if self.0.has_credit() {
+                Either::Left(ready(true))
+            } else {
+                let rx = self.0.wait_credit();
+                Either::Right(async move { rx.await.is_ok() })
+            }
         } else {
             Either::Left(ready(false))
         }
     }
 
+    /// Get notification when packet could be send to the peer, with timeout.
+    ///
+    /// Returns `Err(TimeoutError)` if timeout elapses before packet could be sent.
+    pub fn ready_timeout(
+        &self,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<bool, TimeoutError>> {
+        let sink = self.clone();
+
+        async move {
+            let _guard = WaitersGuard(sink.0.clone());
+            let result = ntex::time::timeout(timeout, sink.ready()).await;
+            result.map_err(|_| TimeoutError)
+        }
+    }
+
+    /// Keep copies of un-acked publish packets
+    ///
+    /// Copies are required for `snapshot()` and `drain_inflight_on_reconnect()`,
+    /// tracking is disabled by default. Only packets sent after tracking
+    /// is enabled are tracked.
+    pub fn track_inflight_packets(&self, enabled: bool) {
+        self.0.track_packets.set(enabled);
+    }
+
+    /// Get snapshot of session state
+    ///
+    /// Snapshot contains un-acked QoS-1 and QoS-2 publish packets, released
+    /// QoS-2 packet ids and active subscriptions. Publish packets are captured
+    /// only if packet tracking is enabled, see `track_inflight_packets()`.
+    /// Snapshot could be taken after connection is closed.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let (inflight, released) = self.0.with_queues(|q| {
+            let ids = q.inflight_packet_ids();
+            let mut inflight: Vec<_> =
+                ids.iter().filter_map(|id| q.inflight_packets.get(id).cloned()).collect();
+            let mut released: Vec<_> =
+                q.inflight_order.iter().copied().filter(|id| q.released.contains(id)).collect();
+
+            // restored packets waiting for credit
+            for item in &q.restored {
+                match item {
+                    Restored::Release(idx) => released.push(idx.get()),
+                    Restored::Publish(pkt) => inflight.push((**pkt).clone()),
+                }
+            }
+            (inflight, released)
+        });
+        SessionSnapshot {
+            inflight,
+            released,
+            subscriptions: self.0.subscriptions.borrow().clone(),
+        }
+    }
+
+    /// Restore session state from snapshot
+    ///
+    /// Released QoS-2 packets get re-released, un-acked publish packets get
+    /// re-sent with `dup` flag set. Nobody waits for acks of restored packets,
+    /// sink completes QoS-2 exchange on its own. Packets that do not fit
+    /// into peer's receive maximum are sent once credit is available.
+    pub fn restore(&self, snapshot: SessionSnapshot) {
+        *self.0.subscriptions.borrow_mut() = snapshot.subscriptions;
+
+        // new packet ids must not collide with restored ones
+        let max_id = snapshot
+            .released
+            .iter()
+            .copied()
+            .chain(snapshot.inflight.iter().filter_map(|p| p.packet_id.map(|id| id.get())))
+            .max()
+            .unwrap_or(0);
+        if max_id > self.0.inflight_idx.get() {
+            self.0.inflight_idx.set(max_id);
+        }
+
+        let items = snapshot
+            .released
+            .into_iter()
+            .filter_map(NonZeroU16::new)
+            .map(Restored::Release)
+            .chain(snapshot.inflight.into_iter().map(|p| Restored::Publish(Box::new(p))));
+        let waiting = self.0.with_queues(|q| {
+            let waiting = !q.restored.is_empty();
+            q.restored.extend(items);
+            waiting
+        });
+
+        // queue is drained by already spawned task
+        if waiting {
+            return;
+        }
+        if !self.restore_pending() {
+            let sink = self.clone();
+            ntex::rt::spawn(async move {
+                while !sink.0.io.is_closed() {
+                    if sink.0.wait_credit().await.is_err() || sink.restore_pending() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Send restored packets while credit is available, returns `true` if all are sent
+    fn restore_pending(&self) -> bool {
+        while !self.0.io.is_closed() && self.0.has_credit() {
+            let item = if let Some(item) = self.0.with_queues(|q| q.restored.pop_front()) {
+                item
+            } else {
+                return true;
+            };
+
+            match item {
+                Restored::Release(packet_id) => self.restore_release(packet_id),
+                Restored::Publish(pkt) => self.restore_publish(*pkt),
+            }
+        }
+        self.0.with_queues(|q| q.restored.is_empty())
+    }
+
+    fn restore_release(&self, packet_id: NonZeroU16) {
+        let idx = packet_id.get();
+        let added = self.0.with_queues(|q| {
+            if q.inflight.contains_key(&idx) {
+                return false;
+            }
+            let (tx, _) = self.0.pool.queue.channel();
+            q.inflight.insert(idx, (tx, AckType::Publish2));
+            q.inflight_order.push_back(idx);
+            if self.0.track_packets.get() {
+                q.released.insert(idx);
+            }
+            true
+        });
+
+        if added {
+            log::trace!("Restore publish release (QoS2) {}", idx);
+            let pkt = codec::PublishAck2 {
+                packet_id,
+                reason_code: codec::PublishAck2Reason::Success,
+                properties: codec::UserProperties::default(),
+                reason_string: None,
+            };
+            let _ = self.0.io.encode(codec::Packet::PublishRelease(pkt), &*self.0);
+        } else {
+            log::warn!("Packet id {} is in use, skip restored publish release", idx);
+        }
+    }
+
+    fn restore_publish(&self, mut pkt: codec::Publish) {
+        let idx = if let Some(idx) = pkt.packet_id { idx.get() } else { return };
+
+        let added = self.0.with_queues(|q| {
+            if q.inflight.contains_key(&idx) {
+                return false;
+            }
+            // nobody waits for ack of restored packet
+            let (tx, _) = self.0.pool.queue.channel();
+            q.inflight.insert(idx, (tx, AckType::Publish));
+            q.inflight_order.push_back(idx);
+            if pkt.qos == QoS::ExactlyOnce {
+                let (tx, _) = self.0.pool.queue.channel();
+                q.pubcomp.insert(idx, tx);
+            } else {
+                q.retransmitted.insert(idx);
+            }
+            if self.0.track_packets.get() {
+                q.inflight_packets.insert(idx, pkt.clone());
+            }
+            true
+        });
+
+        if added {
+            pkt.dup = true;
+            log::trace!("Restore publish {:#?}", pkt);
+            let _ = self.0.io.encode(codec::Packet::Publish(pkt), &*self.0);
+        } else {
+            log::warn!("Packet id {} is in use, skip restored publish packet", idx);
+        }
+    }
+
+    /// Move un-acked QoS-1 publish packets to new connection
+    ///
+    /// Requires `track_inflight_packets(true)`, without it sink does not keep
+    /// sent packets and nothing gets moved.
+    ///
+    /// Packets are removed from this sink and re-sent to `new_sink` with `dup` flag
+    /// set and original packet ids, in original send order. Pending requests on this
+    /// sink fail with `Disconnected` error. Returned futures resolve with peer's ack,
+    /// they wait for ack indefinitely and must be polled for packets to be sent.
+    ///
+    /// Useful for persistent sessions, new connection must not start clean session.
+    pub fn drain_inflight_on_reconnect(
+        &self,
+        new_sink: MqttSink,
+    ) -> Vec<impl Future<Output = Result<codec::PublishAck, PublishQos1Error>>> {
+        let packets: Vec<_> = self.0.with_queues(|q| {
+            if !q.inflight.is_empty() && q.inflight_packets.is_empty() {
+                log::warn!(
+                    "{} in-flight packets are not tracked, enable `track_inflight_packets`",
+                    q.inflight.len()
+                );
+            }
+            let mut ids = q.inflight_packet_ids();
+            ids.retain(|id| q.inflight_packets[id].qos == QoS::AtLeastOnce);
+            ids.iter().filter_map(|id| q.inflight_packets.remove(id)).collect()
+        });
+
+        packets
+            .into_iter()
+            .map(|pkt| {
+                if let Some(idx) = pkt.packet_id {
+                    self.0.remove_inflight(idx.get());
+                }
+                log::trace!("Re-send publish (QoS1) {:?} on reconnect", pkt.packet_id);
+                new_sink.publish_builder(pkt).dup(true).send_at_least_once(None)
+            })
+            .collect()
+    }
+
+    /// Wait until write buffer is flushed to the peer
+    ///
+    /// `close()` flushes write buffer on its own, `flush()` is useful
+    /// for fire-and-forget publishes that need to reach the socket before
+    /// sink does more work.
+    ///
+    /// Write buffer is polled every millisecond, sink holds `IoRef` only and
+    /// cannot register flush waker without taking over dispatcher's one.
+    /// Only top level filter's buffer is checked, data buffered by lower
+    /// filters (i.e. TLS) could still be pending when future resolves.
+    pub async fn flush(&self) -> Result<(), SendPacketError> {
+        loop {
+            if self.0.io.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            let len = self
+                .0
+                .io
+                .with_write_buf(|buf| buf.len())
+                .map_err(|_| SendPacketError::Disconnected)?;
+            if len == 0 {
+                return Ok(());
+            }
+            sleep(Millis(1)).await;
+        }
+    }
+
     /// Close mqtt connection with default Disconnect message
     pub fn close(&self) {
         if self.is_open() {
             let _ = self
                 .0
                 .io
-                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &self.0.codec);
+                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &*self.0);
             self.0.io.close();
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
             q.waiters.clear();
+            q.ping_waiters.clear();
         });
     }
 
     /// Close mqtt connection
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
         if self.is_open() {
-            let _ = self.0.io.encode(codec::Packet::Disconnect(pkt), &self.0.codec);
+            let _ = self.0.io.encode(codec::Packet::Disconnect(pkt), &*self.0);
             self.0.io.close();
         }
         self.0.with_queues(|q| {
             q.inflight.clear();
             q.waiters.clear();
+            q.ping_waiters.clear();
         });
     }
 
+    /// Close mqtt connection with specified reason code
+    pub fn close_with_code(&self, code: codec::DisconnectReasonCode) {
+        self.close_with_reason(codec::Disconnect::new(code))
+    }
+
+    /// Enable automatic topic alias assignment for publish packets
+    ///
+    /// First publish to a topic assigns new alias, subsequent publishes
+    /// to the same topic send alias only. Number of aliases is limited
+    /// by peer's topic alias maximum.
+    pub fn enable_topic_aliases(&self, max: u16) {
+        self.0.enable_topic_aliases(max)
+    }
+
+    /// Gracefully close mqtt connection
+    ///
+    /// Sink stops accepting new publish packets and waits until all in-flight
+    /// packets get acked or timeout elapses, then closes connection.
+    pub fn close_graceful(self, timeout: Millis) -> impl Future<Output = ()> {
+        self.0.closing.set(true);
+
+        async move {
+            let shared = self.0.clone();
+            let _ = ntex::time::timeout(
+                timeout,
+                poll_fn(|cx| {
+                    if shared.io.is_closed()
+                        || shared.with_queues(|q| q.inflight.is_empty() && q.waiters.is_empty())
+                    {
+                        Poll::Ready(())
+                    } else {
+                        shared.drain.register(cx.waker());
+                        Poll::Pending
+                    }
+                }),
+            )
+            .await;
+            self.close();
+        }
+    }
+
     pub(super) fn send(&self, pkt: codec::Packet) {
-        let _ = self.0.io.encode(pkt, &self.0.codec);
+        let _ = self.0.io.encode(pkt, &*self.0);
     }
 
     /// Send ping
     pub(super) fn ping(&self) -> bool {
-        self.0.io.encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+        self.send_ping(None)
+    }
+
+    /// Send ping, waiter slot is reserved so responses match requests in order
+    fn send_ping(&self, tx: Option<oneshot::Sender<Instant>>) -> bool {
+        if self.0.io.encode(codec::Packet::PingRequest, &*self.0).is_ok() {
+            self.0.with_queues(|q| q.ping_waiters.push_back(tx));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Send ping and measure round-trip time
+    ///
+    /// Ping responses are matched to requests in order, including keep-alive pings.
+    /// Returns `None` if connection gets closed before response is received.
+    /// Client connections only.
+    pub fn ping_rtt(&self) -> impl Future<Output = Option<Duration>> {
+        let start = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        let rx =
+            if !self.0.io.is_closed() && self.send_ping(Some(tx)) { Some(rx) } else { None };
+        async move { rx?.await.ok().map(|end| end - start) }
+    }
+
+    pub(super) fn pkt_ping_response(&self) {
+        if let Some(Some(tx)) = self.0.with_queues(|q| q.ping_waiters.pop_front()) {
+            let _ = tx.send(Instant::now());
+        }
     }
 
     /// Close mqtt connection, dont send disconnect message
@@ -95,11 +541,26 @@ impl MqttSink {
         self.0.with_queues(|q| {
             q.waiters.clear();
             q.inflight.clear();
+            q.pubcomp.clear();
+            q.dup_acks.clear();
+            q.ping_waiters.clear();
         });
         self.0.io.close();
     }
 
+    /// Set overload notification, called with number of in-flight packets and capacity
+    pub(super) fn on_overload(&self, f: impl Fn(usize, usize) + 'static) {
+        *self.0.on_overload.borrow_mut() = Some(Box::new(f));
+    }
+
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
+        // late ack of retransmitted or released packet
+        let id = pkt.packet_id();
+        if self.0.with_queues(|q| !q.inflight.contains_key(&id) && q.dup_acks.remove(&id)) {
+            log::trace!("Late ack for retransmitted or released packet: {}", id);
+            return Ok(());
+        }
+
         self.0.with_queues(|queues| loop {
             // check ack order
             if let Some(idx) = queues.inflight_order.pop_front() {
@@ -119,6 +580,13 @@ impl MqttSink {
                     log::trace!("Ack packet with id: {}", pkt.packet_id());
                     let idx = pkt.packet_id();
                     if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                        queues.inflight_packets.remove(&idx);
+                        queues.released.remove(&idx);
+                        if queues.retransmitted.remove(&idx) {
+                            queues.dup_acks.insert(idx);
+                        } else {
+                            queues.dup_acks.remove(&idx);
+                        }
                         // cleanup ack queue
                         if !pkt.is_match(tp) {
                             log::trace!("MQTT protocol error, unexpeted packet");
@@ -127,33 +595,201 @@ impl MqttSink {
                                 tp.name(),
                             ));
                         }
-                        let _ = tx.send(pkt);
+                        let acked = tx.send(pkt).is_ok();
+
+                        // qos2 publish, keep packet id in-flight until PUBCOMP
+                        if let Some(tx) = queues.pubcomp.remove(&idx) {
+                            queues.inflight.insert(idx, (tx, AckType::Publish2));
+                            queues.inflight_order.push_back(idx);
+                            if self.0.track_packets.get() {
+                                queues.released.insert(idx);
+                            }
 
-                        // wake up queued request (receive max limit)
-                        while let Some(tx) = queues.waiters.pop_front() {
-                            if tx.send(()).is_ok() {
-                                break;
+                            // nobody waits for PUBREC (restored packet), release it here
+                            if !acked {
+                                if let Some(packet_id) = NonZeroU16::new(idx) {
+                                    let pkt = codec::PublishAck2 {
+                                        packet_id,
+                                        reason_code: codec::PublishAck2Reason::Success,
+                                        properties: codec::UserProperties::default(),
+                                        reason_string: None,
+                                    };
+                                    let _ = self
+                                        .0
+                                        .io
+                                        .encode(codec::Packet::PublishRelease(pkt), &*self.0);
+                                }
                             }
+                            return Ok(());
                         }
+                        self.0.drain.wake();
+
+                        // wake up queued request (receive max limit)
+                        self.0.wake_waiter(queues);
                         return Ok(());
                     } else {
                         log::error!("In-flight state inconsistency")
                     }
                 }
             } else {
-                log::trace!("Unexpected PublishAck packet");
+                if !queues.inflight.is_empty() {
+                    log::warn!("In-flight state inconsistency, ack order queue is empty");
+                }
+                log::trace!("Unexpected ack packet: {:?}", pkt.packet_id());
+                return Err(ProtocolError::UnexpectedAck(pkt.packet_id()));
             }
-            return Ok(());
         })
     }
 
     /// Create publish packet builder
+    ///
+    /// ```rust,no_run
+    /// # use ntex::util::Bytes;
+    /// # async fn publish(sink: ntex_mqtt::v5::MqttSink) {
+    /// sink.publish("my/topic", Bytes::from_static(b"data")).send_at_most_once().unwrap();
+    /// # }
+    /// ```
     pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
     where
-        ByteString: From<U>,
+        U: Into<ByteString>,
+    {
+        self.publish_builder(codec::Publish {
+            payload,
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+            qos: QoS::AtMostOnce,
+            packet_id: None,
+            properties: codec::PublishProperties::default(),
+        })
+    }
+
+    /// Create publish packet builder with utf-8 text payload
+    ///
+    /// Payload format indicator is set to utf-8.
+    pub fn publish_str<U>(&self, topic: U, text: &str) -> PublishBuilder
+    where
+        U: Into<ByteString>,
+    {
+        self.publish(topic, Bytes::copy_from_slice(text.as_bytes()))
+            .properties(|props| props.is_utf8_payload = Some(true))
+    }
+
+    /// Create publish packet builder with json encoded payload
+    ///
+    /// Content type is set to `application/json`.
+    pub fn publish_json<U, T>(&self, topic: U, value: &T) -> Result<PublishBuilder, JsonError>
+    where
+        U: Into<ByteString>,
+        T: Serialize,
+    {
+        let payload = serde_json::to_string(value)?;
+        Ok(self
+            .publish_str(topic, &payload)
+            .content_type(ByteString::from_static("application/json")))
+    }
+
+    /// Create publish packet builder with message expiry interval
+    ///
+    /// Zero `expiry_secs` means message does not expire.
+    pub fn publish_with_expiry<U>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        expiry_secs: u32,
+    ) -> PublishBuilder
+    where
+        U: Into<ByteString>,
     {
+        let mut builder = self.publish(topic, payload);
+        builder.packet.properties.message_expiry_interval = NonZeroU32::new(expiry_secs);
+        builder
+    }
+
+    /// Create publish packet builder for a response to `incoming` request
+    ///
+    /// Response is sent to request's response topic, correlation data is copied.
+    /// Returns `None` if request has no response topic.
+    ///
+    /// ```rust,no_run
+    /// # use ntex::util::Bytes;
+    /// # use ntex_mqtt::v5::{MqttSink, Publish, PublishAck};
+    /// async fn request(sink: MqttSink, req: Publish) -> Result<PublishAck, ()> {
+    ///     if let Some(builder) = sink.publish_response(&req, Bytes::from_static(b"pong")) {
+    ///         builder.send_at_most_once().map_err(|_| ())?;
+    ///     }
+    ///     Ok(req.ack())
+    /// }
+    /// ```
+    pub fn publish_response(
+        &self,
+        incoming: &Publish,
+        payload: Bytes,
+    ) -> Option<PublishBuilder> {
+        let topic = incoming.response_topic()?.clone();
+        let mut builder = self.publish(topic, payload);
+        builder.packet.properties.correlation_data = incoming.correlation_data().cloned();
+        Some(builder)
+    }
+
+    /// Send pre-built QoS-0 publish packet
+    ///
+    /// Packet is encoded as is. QoS-1/2 packets are rejected with
+    /// `SendPacketError::UnsupportedQoS`, they require in-flight tracking,
+    /// use `publish_raw_qos1()` instead.
+    pub fn publish_raw(&self, pkt: codec::Publish) -> Result<(), SendPacketError> {
+        if pkt.qos != QoS::AtMostOnce {
+            return Err(SendPacketError::UnsupportedQoS(pkt.qos));
+        }
+        self.publish_builder(pkt).send_at_most_once()
+    }
+
+    /// Send pre-built publish packet with QoS 1
+    ///
+    /// Packet must have packet id.
+    pub fn publish_raw_qos1(
+        &self,
+        pkt: codec::Publish,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        if pkt.packet_id.is_none() {
+            Either::Left(Ready::Err(PublishQos1Error::Encode(EncodeError::PacketIdRequired)))
+        } else {
+            Either::Right(self.publish_builder(pkt).send_at_least_once(timeout))
+        }
+    }
+
+    fn publish_builder(&self, packet: codec::Publish) -> PublishBuilder {
         PublishBuilder {
-            packet: codec::Publish {
+            packet,
+            shared: self.0.clone(),
+            retransmit: Retransmit::default(),
+            sent: false,
+        }
+    }
+
+    /// Send multiple publish packets with QoS 0
+    ///
+    /// All packets get encoded into the write buffer at once, so transport
+    /// could flush them together. If any of packets is invalid or fails
+    /// to encode, none of the packets get sent.
+    ///
+    /// Each packet takes one token from rate limit bucket, batch fails with
+    /// `SendPacketError::RateLimited` if bucket does not have enough tokens
+    /// for all packets. Batch larger than the rate limit is never sent.
+    pub fn publish_batch<I, U>(&self, iter: I) -> Result<(), SendPacketError>
+    where
+        I: IntoIterator<Item = (U, Bytes)>,
+        U: Into<ByteString>,
+    {
+        if self.0.io.is_closed() || self.0.closing.get() {
+            log::error!("Mqtt sink is disconnected");
+            return Err(SendPacketError::Disconnected);
+        }
+
+        let mut packets = Vec::new();
+        for (topic, payload) in iter {
+            let packet = codec::Publish {
                 payload,
                 dup: false,
                 retain: false,
@@ -161,9 +797,54 @@ impl MqttSink {
                 qos: QoS::AtMostOnce,
                 packet_id: None,
                 properties: codec::PublishProperties::default(),
-            },
-            shared: self.0.clone(),
+            };
+            if let Err(e) = packet.validate() {
+                return Err(SendPacketError::Encode(EncodeError::InvalidPublish(e)));
+            }
+            self.0.codec.outbound_size(&packet).map_err(SendPacketError::Encode)?;
+            packets.push(packet);
+        }
+
+        let count = packets.len().min(u32::MAX as usize) as u32;
+        if self.0.take_rate_tokens(count).is_some() {
+            log::trace!("Publish batch (QoS-0) of {} packets is rate limited", count);
+            return Err(SendPacketError::RateLimited);
         }
+
+        self.0
+            .io
+            .with_write_buf(|buf| {
+                let len = buf.len();
+                for packet in packets {
+                    log::trace!("Publish (QoS-0) to {:?}", packet.topic);
+
+                    if let Err(err) =
+                        self.0.codec.encode_vec(codec::Packet::Publish(packet), buf)
+                    {
+                        buf.truncate(len);
+                        return Err(SendPacketError::Encode(err));
+                    }
+                }
+                self.0.add_bytes_sent(buf.len() - len);
+                Ok(())
+            })
+            .map_err(|_| SendPacketError::Disconnected)?
+    }
+
+    /// Send multiple publish packets with QoS 2
+    ///
+    /// All packets are sent concurrently, packets above receive maximum
+    /// wait for credit. Results are returned in the same order as packets.
+    pub fn publish_many_exactly_once<I>(
+        &self,
+        iter: I,
+        timeout: Millis,
+    ) -> impl Future<Output = Vec<Result<codec::PublishAck2, PublishQos2Error>>>
+    where
+        I: IntoIterator<Item = PublishBuilder>,
+    {
+        let futs: Vec<_> = iter.into_iter().map(|p| p.send_exactly_once(timeout)).collect();
+        join_all(futs)
     }
 
     /// Create subscribe packet builder
@@ -177,6 +858,7 @@ impl MqttSink {
                 topic_filters: Vec::new(),
             },
             shared: self.0.clone(),
+            timeout: None,
         }
     }
 
@@ -190,19 +872,69 @@ impl MqttSink {
                 topic_filters: Vec::new(),
             },
             shared: self.0.clone(),
+            timeout: None,
+        }
+    }
+
+    /// Replace subscription
+    ///
+    /// Subscribe packet for new topic filter is sent after unsubscribe ack
+    /// for old topic filter is received.
+    pub fn resubscribe(
+        &self,
+        old: ByteString,
+        new: ByteString,
+        opts: codec::SubscriptionOptions,
+    ) -> impl Future<Output = Result<(codec::UnsubscribeAck, codec::SubscribeAck), ResubscribeError>>
+    {
+        let unsubscribe = self.unsubscribe().topic_filter(old);
+        let subscribe = self.subscribe(None).topic_filter(new, opts);
+
+        async move {
+            let unsub_ack = unsubscribe.send().await.map_err(ResubscribeError::Unsubscribe)?;
+            let sub_ack = subscribe.send().await.map_err(ResubscribeError::Subscribe)?;
+            Ok((unsub_ack, sub_ack))
         }
     }
 }
 
 impl fmt::Debug for MqttSink {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("MqttSink").finish()
+        fmt.debug_tuple("MqttSink").field(&self.0).finish()
     }
 }
 
+/// Ack of publish with QoS selected at runtime
+#[derive(Debug, PartialEq)]
+pub enum QosAckResult {
+    /// QoS 0 publish is sent
+    AtMostOnce,
+    /// QoS 1 publish is acked
+    AtLeastOnce(codec::PublishAck),
+    /// QoS 2 publish is completed
+    ExactlyOnce(codec::PublishAck2),
+}
+
+#[derive(Clone)]
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
+    retransmit: Retransmit,
+    sent: bool,
+}
+
+impl Drop for PublishBuilder {
+    fn drop(&mut self) {
+        if !self.sent {
+            log::warn!("PublishBuilder dropped without sending");
+        }
+    }
+}
+
+impl fmt::Debug for PublishBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PublishBuilder").field("packet", &self.packet).finish()
+    }
 }
 
 impl PublishBuilder {
@@ -212,9 +944,27 @@ impl PublishBuilder {
     /// Packet id management should not be mixed, it should be auto-generated
     /// or set by user. Otherwise collisions could occure.
     ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        let id = NonZeroU16::new(id).expect("id 0 is not allowed");
+    /// Packet id could not be 0, such id is rejected at compile time.
+    ///
+    /// ```rust
+    /// use std::num::NonZeroU16;
+    /// # use ntex::util::Bytes;
+    ///
+    /// # fn test(sink: &ntex_mqtt::v5::MqttSink) {
+    /// assert!(NonZeroU16::new(0).is_none());
+    ///
+    /// let id = NonZeroU16::new(1).unwrap();
+    /// let builder = sink.publish("topic", Bytes::new()).packet_id(id);
+    /// # }
+    /// ```
+    ///
+    /// ```compile_fail
+    /// # use ntex::util::Bytes;
+    /// # fn test(sink: &ntex_mqtt::v5::MqttSink) {
+    /// let builder = sink.publish("topic", Bytes::new()).packet_id(0);
+    /// # }
+    /// ```
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
         self.packet.packet_id = Some(id);
         self
     }
@@ -231,7 +981,57 @@ impl PublishBuilder {
         self
     }
 
-    /// Set publish packet properties
+    /// Set publish topic
+    ///
+    /// Could be used with cloned builder for publishing same message
+    /// to multiple topics, template builder should be dropped with `discard()`.
+    ///
+    /// ```rust
+    /// # use ntex::util::Bytes;
+    /// # async fn test(sink: &ntex_mqtt::v5::MqttSink) {
+    /// let base = sink.publish("base", Bytes::from_static(b"data")).retain();
+    /// for topic in ["topic1", "topic2"] {
+    ///     let _ = base.clone().topic(topic).send_at_most_once();
+    /// }
+    /// base.discard();
+    /// # }
+    /// ```
+    pub fn topic<U>(mut self, topic: U) -> Self
+    where
+        U: Into<ByteString>,
+    {
+        self.packet.topic = topic.into();
+        self
+    }
+
+    /// Set max number of re-transmissions for QoS 1 and QoS 2 packets.
+    ///
+    /// Publish gets re-sent on each ack timeout, if peer does not respond
+    /// after `n` re-transmissions, send operation fails with `MaxRetriesExceeded` error.
+    /// By default packet is re-sent until connection is closed.
+    pub fn max_retries(mut self, n: u32) -> Self {
+        self.retransmit.max_retries = Some(n);
+        self
+    }
+
+    /// Set exponential back-off for QoS 1 and QoS 2 re-transmissions.
+    ///
+    /// Before each re-transmission sink waits for a delay that starts at `initial`
+    /// and doubles on each retry, capped at `max`. Each delay is randomly reduced
+    /// by up to `jitter` fraction of its value.
+    ///
+    /// panics if jitter is not in `[0.0, 1.0]` range
+    pub fn retransmit_backoff(mut self, initial: Millis, max: Millis, jitter: f32) -> Self {
+        if !(0.0..=1.0).contains(&jitter) {
+            panic!("jitter must be in [0.0, 1.0] range");
+        }
+        self.retransmit.backoff = Some(Backoff { initial, max, jitter });
+        self
+    }
+
+    /// Update publish packet properties
+    ///
+    /// Could be called multiple times, closure receives current properties.
     pub fn properties<F>(mut self, f: F) -> Self
     where
         F: FnOnce(&mut codec::PublishProperties),
@@ -240,7 +1040,7 @@ impl PublishBuilder {
         self
     }
 
-    /// Set publish packet properties
+    /// Update publish packet properties
     pub fn set_properties<F>(&mut self, f: F)
     where
         F: FnOnce(&mut codec::PublishProperties),
@@ -248,15 +1048,129 @@ impl PublishBuilder {
         f(&mut self.packet.properties);
     }
 
+    /// Add user property
+    pub fn add_user_property(mut self, key: ByteString, val: ByteString) -> Self {
+        self.packet.properties.user_properties.push((key, val));
+        self
+    }
+
+    /// Set response topic
+    pub fn response_topic(mut self, topic: ByteString) -> Self {
+        self.packet.properties.response_topic = Some(topic);
+        self
+    }
+
+    /// Set correlation data
+    pub fn correlation_data(mut self, data: Bytes) -> Self {
+        self.packet.properties.correlation_data = Some(data);
+        self
+    }
+
+    /// Set correlation data to big-endian encoded `u64` id
+    ///
+    /// Convenience wrapper for `correlation_data()`, use `Publish::correlation_id()`
+    /// to read it on the receiving side.
+    pub fn correlation_id(self, id: u64) -> Self {
+        self.correlation_data(Bytes::copy_from_slice(&id.to_be_bytes()))
+    }
+
+    /// Set content type
+    pub fn content_type(mut self, content_type: ByteString) -> Self {
+        self.packet.properties.content_type = Some(content_type);
+        self
+    }
+
+    /// Send publish packet with QoS selected at runtime
+    ///
+    /// ```rust,no_run
+    /// use ntex::{time::Millis, util::Bytes};
+    /// use ntex_mqtt::v5::{error::SendAnyError, MqttSink, QoS, QosAckResult};
+    ///
+    /// async fn publish(sink: MqttSink, qos: QoS) {
+    ///     let res = sink.publish("my/topic", Bytes::new()).send(qos, Millis(5_000)).await;
+    ///     match res {
+    ///         Ok(QosAckResult::AtMostOnce) => println!("sent"),
+    ///         Ok(QosAckResult::AtLeastOnce(ack)) => println!("acked: {:?}", ack),
+    ///         Ok(QosAckResult::ExactlyOnce(ack)) => println!("completed: {:?}", ack),
+    ///         Err(SendAnyError::AtLeastOnce(err)) => println!("qos1 error: {}", err),
+    ///         Err(err) => println!("error: {}", err),
+    ///     }
+    /// }
+    /// ```
+    pub fn send(
+        self,
+        qos: QoS,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<QosAckResult, SendAnyError>> {
+        match qos {
+            QoS::AtMostOnce => Either::Left(Ready::from(
+                self.send_at_most_once()
+                    .map(|_| QosAckResult::AtMostOnce)
+                    .map_err(SendAnyError::AtMostOnce),
+            )),
+            QoS::AtLeastOnce => {
+                let fut = self.send_at_least_once(timeout);
+                Either::Right(Either::Left(async move {
+                    fut.await.map(QosAckResult::AtLeastOnce).map_err(SendAnyError::AtLeastOnce)
+                }))
+            }
+            QoS::ExactlyOnce => {
+                let fut = self.send_exactly_once(timeout);
+                Either::Right(Either::Right(async move {
+                    fut.await.map(QosAckResult::ExactlyOnce).map_err(SendAnyError::ExactlyOnce)
+                }))
+            }
+        }
+    }
+
+    /// Drop builder without sending, dropped builder is not reported
+    ///
+    /// Builder that is dropped without sending is logged as a warning,
+    /// use this method for template builders and for builders that
+    /// are not going to be sent, i.e. unused `publish_response()` result.
+    pub fn discard(mut self) {
+        self.sent = true;
+    }
+
+    /// Mark builder as sent and take its parts
+    fn into_parts(mut self) -> (Rc<MqttShared>, codec::Publish, Retransmit) {
+        self.sent = true;
+        let packet = mem::replace(
+            &mut self.packet,
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::new(),
+                packet_id: None,
+                payload: Bytes::new(),
+                properties: codec::PublishProperties::default(),
+            },
+        );
+        (self.shared.clone(), packet, self.retransmit)
+    }
+
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+        let (shared, mut packet, _) = self.into_parts();
 
-        if !self.shared.io.is_closed() {
+        if !shared.io.is_closed() && !shared.closing.get() {
+            if let Err(e) = packet.validate() {
+                return Err(SendPacketError::Encode(EncodeError::InvalidPublish(e)));
+            }
+            // check peer's max packet size before topic alias gets assigned
+            if let Err(e) = shared.codec.outbound_size(&packet) {
+                return Err(SendPacketError::Encode(e));
+            }
+            if shared.take_rate_token().is_some() {
+                log::trace!("Publish (QoS-0) to {:?} is rate limited", packet.topic);
+                return Err(SendPacketError::RateLimited);
+            }
+            shared.set_topic_alias(&mut packet);
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
-            self.shared
+            shared
                 .io
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode(codec::Packet::Publish(packet), &*shared)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
@@ -265,29 +1179,57 @@ impl PublishBuilder {
         }
     }
 
+    /// Send publish packet with QoS 0, errors are logged and dropped
+    pub fn send_fire_and_forget(self) {
+        self.send_fire_and_forget_with_callback(|err| {
+            log::debug!("Fire and forget publish failed: {}", err)
+        })
+    }
+
+    /// Send publish packet with QoS 0, errors are passed to `on_err` callback
+    pub fn send_fire_and_forget_with_callback<F: FnOnce(SendPacketError)>(self, on_err: F) {
+        if let Err(err) = self.send_at_most_once() {
+            on_err(err)
+        }
+    }
+
     /// Send publish packet with QoS 1
-    pub fn send_at_least_once(
+    ///
+    /// Publish gets re-sent if ack is not received within `timeout`,
+    /// `None` waits for ack indefinitely.
+    pub fn send_at_least_once<T>(
         self,
-        timeout: Millis,
-    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
-        let shared = self.shared;
-        let mut packet = self.packet;
+        timeout: T,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>>
+    where
+        T: Into<Option<Millis>>,
+    {
+        let timeout = timeout.into();
+        let (shared, mut packet, retransmit) = self.into_parts();
         packet.qos = QoS::AtLeastOnce;
 
-        if !shared.io.is_closed() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
+        if !shared.io.is_closed() && !shared.closing.get() {
+            // handle client receive maximum and rate limit
+            if !shared.has_credit() || shared.is_rate_limited() {
+                let rx = if shared.has_credit() { None } else { Some(shared.wait_credit()) };
 
                 return Either::Left(Either::Right(async move {
-                    if rx.await.is_err() {
-                        return Err(PublishQos1Error::Disconnected);
+                    if let Some(rx) = rx {
+                        if rx.await.is_err() {
+                            return Err(PublishQos1Error::Disconnected);
+                        }
                     }
-                    Self::send_at_least_once_inner(packet, shared, timeout).await
+                    shared.wait_rate_token().await;
+                    // concurrent publishes could take credit while waiting for token
+                    while !shared.has_credit() {
+                        if shared.wait_credit().await.is_err() {
+                            return Err(PublishQos1Error::Disconnected);
+                        }
+                    }
+                    Self::send_at_least_once_inner(packet, shared, timeout, retransmit).await
                 }));
             }
-            Either::Right(Self::send_at_least_once_inner(packet, shared, timeout))
+            Either::Right(Self::send_at_least_once_inner(packet, shared, timeout, retransmit))
         } else {
             Either::Left(Either::Left(Ready::Err(PublishQos1Error::Disconnected)))
         }
@@ -296,14 +1238,27 @@ impl PublishBuilder {
     fn send_at_least_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
-        _timeout: Millis,
+        timeout: Option<Millis>,
+        retransmit: Retransmit,
     ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
         // packet id
         let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
         if idx == 0 {
-            idx = shared.next_id();
+            match shared.next_id() {
+                Ok(id) => idx = id.get(),
+                Err(e) => return Either::Left(Ready::Err(PublishQos1Error::Encode(e))),
+            }
             packet.packet_id = NonZeroU16::new(idx);
         }
+        if let Err(e) = packet.validate() {
+            return Either::Left(Ready::Err(PublishQos1Error::Encode(
+                EncodeError::InvalidPublish(e),
+            )));
+        }
+        // check peer's max packet size before packet becomes in-flight
+        if let Err(e) = shared.codec.outbound_size(&packet) {
+            return Either::Left(Ready::Err(PublishQos1Error::Encode(e)));
+        }
 
         let rx = shared.with_queues(|queues| {
             // publish ack channel
@@ -314,6 +1269,9 @@ impl PublishBuilder {
             }
             queues.inflight.insert(idx, (tx, AckType::Publish));
             queues.inflight_order.push_back(idx);
+            if shared.track_packets.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
             Ok(rx)
         });
 
@@ -324,19 +1282,21 @@ impl PublishBuilder {
 
         // wait ack from peer
         Either::Right(async move {
+            shared.set_topic_alias(&mut packet);
             let mut pkt = packet.clone();
+            let mut retries = 0;
 
             // send publish to client
             loop {
                 log::trace!("Publish (QoS1) to {:#?}", &pkt);
 
                 if let Err(err) =
-                    shared.io.encode(codec::Packet::Publish(pkt.clone()), &shared.codec)
+                    shared.io.encode(codec::Packet::Publish(pkt.clone()), &*shared)
                 {
                     return Err(PublishQos1Error::Encode(err));
                 }
 
-                match timeout(_timeout, poll_fn(|cx| rx.poll_recv(cx))).await {
+                match recv_ack(&rx, timeout).await {
                     Ok(resp) => match resp {
                         Ok(pkt) => {
                             let pkt = pkt.publish();
@@ -351,86 +1311,175 @@ impl PublishBuilder {
                         }
                     },
                     Err(_) => {
+                        if retransmit.is_exceeded(retries) {
+                            log::warn!("Publish (QoS1) Timeout! Max retries exceeded");
+                            shared.remove_inflight(idx);
+                            return Err(PublishQos1Error::MaxRetriesExceeded);
+                        }
                         log::warn!("Publish (QoS1) Timeout! Try again!");
+                        retransmit.delay(retries).await;
+                        retries += 1;
                         pkt.dup = true;
+                        shared.with_queues(|q| {
+                            q.retransmitted.insert(idx);
+                            if let Some(p) = q.inflight_packets.get_mut(&idx) {
+                                p.dup = true;
+                            }
+                        });
                     }
                 }
             }
         })
     }
 
+    /// Send publish packet with QoS 1, fails immediately if no credit is available
+    ///
+    /// Returned future behaves identically to `send_at_least_once()`.
+    pub fn try_send_at_least_once<T>(
+        mut self,
+        timeout: T,
+    ) -> Result<
+        impl Future<Output = Result<codec::PublishAck, PublishQos1Error>>,
+        PublishQos1Error,
+    >
+    where
+        T: Into<Option<Millis>>,
+    {
+        if self.shared.has_credit() {
+            Ok(self.send_at_least_once(timeout))
+        } else {
+            self.sent = true;
+            Err(PublishQos1Error::NoCredit)
+        }
+    }
+
     /// Send publish packet with QoS 2
-    pub fn send_exactly_once(
+    ///
+    /// Publish gets re-sent if ack is not received within `timeout`,
+    /// `None` waits for ack indefinitely.
+    pub fn send_exactly_once<T>(
         self,
-        timeout: Millis,
-    ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>> {
-        let shared = self.shared;
-        let mut packet = self.packet;
+        timeout: T,
+    ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>>
+    where
+        T: Into<Option<Millis>>,
+    {
+        let timeout = timeout.into();
+        let (shared, mut packet, retransmit) = self.into_parts();
         packet.qos = QoS::ExactlyOnce;
 
-        if !shared.io.is_closed() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
+        if !shared.io.is_closed() && !shared.closing.get() {
+            // handle client receive maximum and rate limit
+            if !shared.has_credit() || shared.is_rate_limited() {
+                let rx = if shared.has_credit() { None } else { Some(shared.wait_credit()) };
 
                 return Either::Left(Either::Right(async move {
-                    if rx.await.is_err() {
-                        return Err(PublishQos2Error::Disconnected);
+                    if let Some(rx) = rx {
+                        if rx.await.is_err() {
+                            return Err(PublishQos2Error::Disconnected);
+                        }
                     }
-                    Self::send_exactly_once_inner(packet, shared, timeout).await
+                    shared.wait_rate_token().await;
+                    // concurrent publishes could take credit while waiting for token
+                    while !shared.has_credit() {
+                        if shared.wait_credit().await.is_err() {
+                            return Err(PublishQos2Error::Disconnected);
+                        }
+                    }
+                    Self::send_exactly_once_inner(packet, shared, timeout, retransmit).await
                 }));
             }
-            Either::Right(Self::send_exactly_once_inner(packet, shared, timeout))
+            Either::Right(Self::send_exactly_once_inner(packet, shared, timeout, retransmit))
         } else {
             Either::Left(Either::Left(Ready::Err(PublishQos2Error::Disconnected)))
         }
     }
 
+    /// Send publish packet with QoS 2, fails immediately if no credit is available
+    ///
+    /// Returned future behaves identically to `send_exactly_once()`.
+    pub fn try_send_exactly_once<T>(
+        mut self,
+        timeout: T,
+    ) -> Result<
+        impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>>,
+        PublishQos2Error,
+    >
+    where
+        T: Into<Option<Millis>>,
+    {
+        if self.shared.has_credit() {
+            Ok(self.send_exactly_once(timeout))
+        } else {
+            self.sent = true;
+            Err(PublishQos2Error::NoCredit)
+        }
+    }
+
     fn send_exactly_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
-        _timeout: Millis,
+        timeout: Option<Millis>,
+        retransmit: Retransmit,
     ) -> impl Future<Output = Result<codec::PublishAck2, PublishQos2Error>> {
         // packet id
         let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
         if idx == 0 {
-            idx = shared.next_id();
+            match shared.next_id() {
+                Ok(id) => idx = id.get(),
+                Err(e) => return Either::Left(Ready::Err(PublishQos2Error::Encode(e))),
+            }
             packet.packet_id = NonZeroU16::new(idx);
         }
+        if let Err(e) = packet.validate() {
+            return Either::Left(Ready::Err(PublishQos2Error::Encode(
+                EncodeError::InvalidPublish(e),
+            )));
+        }
+        // check peer's max packet size before packet becomes in-flight
+        if let Err(e) = shared.codec.outbound_size(&packet) {
+            return Either::Left(Ready::Err(PublishQos2Error::Encode(e)));
+        }
 
         let rx = shared.with_queues(|queues| {
-            // publish ack channel
-            let (tx, rx) = shared.pool.queue.channel();
-
             if queues.inflight.contains_key(&idx) {
                 return Err(PublishQos2Error::PacketIdInUse(idx));
             }
+            // publish ack channels, pubcomp channel takes over
+            // in-flight slot right after PUBREC
+            let (tx, rx) = shared.pool.queue.channel();
+            let (tx2, rx2) = shared.pool.queue.channel();
             queues.inflight.insert(idx, (tx, AckType::Publish));
             queues.inflight_order.push_back(idx);
-            Ok(rx)
+            queues.pubcomp.insert(idx, tx2);
+            if shared.track_packets.get() {
+                queues.inflight_packets.insert(idx, packet.clone());
+            }
+            Ok((rx, rx2))
         });
 
-        let rx = match rx {
+        let (rx, rx2) = match rx {
             Ok(rx) => rx,
             Err(e) => return Either::Left(Ready::Err(e)),
         };
 
         // wait ack from peer
         Either::Right(async move {
+            shared.set_topic_alias(&mut packet);
             let mut pkt = packet.clone();
+            let mut retries = 0;
 
             // send publish to client
             loop {
                 log::trace!("Publish (QoS2) to {:#?}", &pkt);
 
                 if let Err(err) =
-                    shared.io.encode(codec::Packet::Publish(pkt.clone()), &shared.codec)
+                    shared.io.encode(codec::Packet::Publish(pkt.clone()), &*shared)
                 {
                     return Err(PublishQos2Error::Encode(err));
                 }
 
-                match timeout(_timeout, poll_fn(|cx| rx.poll_recv(cx))).await {
+                match recv_ack(&rx, timeout).await {
                     Ok(resp) => match resp {
                         Ok(pkt) => {
                             let pkt = pkt.publish();
@@ -442,31 +1491,18 @@ impl PublishBuilder {
                                 reason_string: pkt.reason_string,
                             };
 
-                            let rx = shared.with_queues(|queues| {
-                                // publish ack channel
-                                let (tx, rx) = shared.pool.queue.channel();
-
-                                if queues.inflight.contains_key(&idx) {
-                                    return Err(PublishQos2Error::PacketIdInUse(idx));
-                                }
-                                queues.inflight.insert(idx, (tx, AckType::Publish2));
-                                queues.inflight_order.push_back(idx);
-                                Ok(rx)
-                            });
-                            let rx = match rx {
-                                Ok(rx) => rx,
-                                Err(_) => return Err(PublishQos2Error::PacketIdInUse(idx)),
-                            };
+                            let rx = &rx2;
+                            let mut retries = 0;
 
                             loop {
                                 if let Err(err) = shared.io.encode(
                                     codec::Packet::PublishRelease(pkt2.clone()),
-                                    &shared.codec,
+                                    &*shared,
                                 ) {
                                     return Err(PublishQos2Error::Encode(err));
                                 }
 
-                                match timeout(_timeout, poll_fn(|cx| rx.poll_recv(cx))).await {
+                                match recv_ack(rx, timeout).await {
                                     Ok(resp) => match resp {
                                         Ok(pkt) => {
                                             let pkt = pkt.publish2();
@@ -482,7 +1518,18 @@ impl PublishBuilder {
                                             return Err(PublishQos2Error::Disconnected);
                                         }
                                     },
-                                    Err(_) => log::warn!("Publish (QoS2) Timeout! Try again!"),
+                                    Err(_) => {
+                                        if retransmit.is_exceeded(retries) {
+                                            log::warn!(
+                                                "Publish (QoS2) Timeout! Max retries exceeded"
+                                            );
+                                            shared.remove_inflight(idx);
+                                            return Err(PublishQos2Error::MaxRetriesExceeded);
+                                        }
+                                        log::warn!("Publish (QoS2) Timeout! Try again!");
+                                        retransmit.delay(retries).await;
+                                        retries += 1;
+                                    }
                                 }
                             }
                         }
@@ -492,8 +1539,20 @@ impl PublishBuilder {
                         }
                     },
                     Err(_) => {
+                        if retransmit.is_exceeded(retries) {
+                            log::warn!("Publish (QoS2) Timeout! Max retries exceeded");
+                            shared.remove_inflight(idx);
+                            return Err(PublishQos2Error::MaxRetriesExceeded);
+                        }
                         log::warn!("Publish (QoS2) Timeout! Try again!");
+                        retransmit.delay(retries).await;
+                        retries += 1;
                         pkt.dup = true;
+                        shared.with_queues(|q| {
+                            if let Some(p) = q.inflight_packets.get_mut(&idx) {
+                                p.dup = true;
+                            }
+                        });
                     }
                 }
             }
@@ -501,32 +1560,178 @@ impl PublishBuilder {
     }
 }
 
+/// Removes canceled credit waiters, waiter gets canceled
+/// if `ready_timeout()` times out or its future is dropped
+struct WaitersGuard(Rc<MqttShared>);
+
+impl Drop for WaitersGuard {
+    fn drop(&mut self) {
+        self.0.with_queues(|q| q.waiters.retain(|tx| !tx.is_canceled()));
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Retransmit {
+    max_retries: Option<u32>,
+    backoff: Option<Backoff>,
+}
+
+#[derive(Copy, Clone)]
+struct Backoff {
+    initial: Millis,
+    max: Millis,
+    jitter: f32,
+}
+
+impl Retransmit {
+    fn is_exceeded(&self, retries: u32) -> bool {
+        self.max_retries.map(|max| retries >= max).unwrap_or(false)
+    }
+
+    /// Wait before re-transmission
+    async fn delay(&self, retries: u32) {
+        if let Some(ref backoff) = self.backoff {
+            let delay = backoff
+                .initial
+                .0
+                .saturating_mul(1u32.checked_shl(retries).unwrap_or(u32::MAX))
+                .min(backoff.max.0);
+            let delay = delay - (delay as f32 * backoff.jitter * random()) as u32;
+            sleep(Millis(delay)).await
+        }
+    }
+}
+
+/// Wait for ack, `None` timeout waits indefinitely
+async fn recv_ack(
+    rx: &pool::Receiver<Ack>,
+    timeout: Option<Millis>,
+) -> Result<Result<Ack, Canceled>, ()> {
+    let fut = poll_fn(|cx| rx.poll_recv(cx));
+    if let Some(timeout) = timeout {
+        ntex::time::timeout(timeout, fut).await.map_err(|_| ())
+    } else {
+        Ok(fut.await)
+    }
+}
+
+/// Random value in `[0.0, 1.0)` range
+fn random() -> f32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(0);
+    (hasher.finish() >> 40) as f32 / (1u64 << 24) as f32
+}
+
 /// Subscribe packet builder
 pub struct SubscribeBuilder {
     id: u16,
     packet: codec::Subscribe,
     shared: Rc<MqttShared>,
+    timeout: Option<Millis>,
+}
+
+impl fmt::Debug for SubscribeBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SubscribeBuilder")
+            .field("id", &self.id)
+            .field("packet", &self.packet)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
 }
 
 impl SubscribeBuilder {
     /// Set packet id.
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
+        self.id = id.get();
+        self
+    }
+
+    /// Set ack timeout, by default `send()` waits for ack indefinitely
     ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        if id == 0 {
-            panic!("id 0 is not allowed");
-        }
-        self.id = id;
+    /// On timeout packet id is released and `send()` fails with
+    /// `SendPacketError::Timeout`, late ack from peer is ignored.
+    pub fn with_timeout(mut self, timeout: Millis) -> Self {
+        self.timeout = Some(timeout);
         self
     }
 
     /// Add topic filter
-    pub fn topic_filter(
+    ///
+    /// ```rust,no_run
+    /// # use ntex_mqtt::v5::codec;
+    /// # async fn subscribe(sink: ntex_mqtt::v5::MqttSink) {
+    /// let opts = codec::SubscriptionOptions {
+    ///     qos: codec::QoS::AtLeastOnce,
+    ///     no_local: false,
+    ///     retain_as_published: false,
+    ///     retain_handling: codec::RetainHandling::AtSubscribe,
+    /// };
+    /// let ack = sink.subscribe(None).topic_filter("my/topic", opts).send().await;
+    /// # }
+    /// ```
+    pub fn topic_filter<U: Into<ByteString>>(
         mut self,
-        filter: ByteString,
+        filter: U,
         opts: codec::SubscriptionOptions,
     ) -> Self {
-        self.packet.topic_filters.push((filter, opts));
+        self.packet.topic_filters.push((filter.into(), opts));
+        self
+    }
+
+    /// Set QoS of the most recently added topic filter
+    pub fn qos(self, qos: QoS) -> Self {
+        self.last_options(|opts| opts.qos = qos)
+    }
+
+    /// Set `NoLocal` flag of the most recently added topic filter
+    pub fn no_local(self) -> Self {
+        self.last_options(|opts| opts.no_local = true)
+    }
+
+    /// Set `RetainAsPublished` flag of the most recently added topic filter
+    pub fn retain_as_published(self) -> Self {
+        self.last_options(|opts| opts.retain_as_published = true)
+    }
+
+    /// Set retain handling of the most recently added topic filter
+    pub fn retain_handling(self, handling: codec::RetainHandling) -> Self {
+        self.last_options(|opts| opts.retain_handling = handling)
+    }
+
+    fn last_options<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::SubscriptionOptions),
+    {
+        if let Some((_, opts)) = self.packet.topic_filters.last_mut() {
+            f(opts)
+        } else {
+            log::warn!("Subscribe builder does not contain any topic filter");
+        }
+        self
+    }
+
+    /// Add topic filter, filter gets validated
+    pub fn try_topic_filter<U: Into<ByteString>>(
+        self,
+        filter: U,
+        opts: codec::SubscriptionOptions,
+    ) -> Result<Self, TopicFilterError> {
+        Ok(self.topic_filter(validate_topic_filter(filter.into())?, opts))
+    }
+
+    /// Add multiple topic filters
+    pub fn topic_filters<I>(mut self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = (ByteString, codec::SubscriptionOptions)>,
+    {
+        self.packet.topic_filters.extend(filters);
+        self
+    }
+
+    /// Remove all topic filters
+    pub fn clear_topic_filters(mut self) -> Self {
+        self.packet.topic_filters.clear();
         self
     }
 
@@ -545,15 +1750,18 @@ impl SubscribeBuilder {
         if !shared.io.is_closed() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
+                let rx = shared.wait_credit();
 
                 if rx.await.is_err() {
                     return Err(SendPacketError::Disconnected);
                 }
             }
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
+            let idx = if self.id == 0 {
+                shared.next_id().map_err(SendPacketError::Encode)?.get()
+            } else {
+                self.id
+            };
             packet.packet_id = NonZeroU16::new(idx).unwrap();
             let rx = shared.with_queues(|queues| {
                 // ack channel
@@ -570,12 +1778,34 @@ impl SubscribeBuilder {
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
 
-            match shared.io.encode(codec::Packet::Subscribe(packet), &shared.codec) {
+            let filters = packet.topic_filters.clone();
+            match shared.io.encode(codec::Packet::Subscribe(packet), &*shared) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.subscribe())
+                    let ack = match recv_ack(&rx, self.timeout).await {
+                        Ok(Ok(pkt)) => pkt.subscribe(),
+                        Ok(Err(_)) => return Err(SendPacketError::Disconnected),
+                        Err(_) => {
+                            log::trace!("Subscribe ack timeout, packet id: {}", idx);
+                            shared.remove_inflight(idx);
+                            return Err(SendPacketError::Timeout);
+                        }
+                    };
+
+                    // track active subscriptions
+                    let mut subs = shared.subscriptions.borrow_mut();
+                    for ((filter, opts), status) in filters.into_iter().zip(ack.status.iter()) {
+                        match status {
+                            codec::SubscribeAckReason::GrantedQos0
+                            | codec::SubscribeAckReason::GrantedQos1
+                            | codec::SubscribeAckReason::GrantedQos2 => {
+                                subs.retain(|(f, _)| f != &filter);
+                                subs.push((filter, opts));
+                            }
+                            _ => (),
+                        }
+                    }
+                    Ok(ack)
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -583,6 +1813,29 @@ impl SubscribeBuilder {
             Err(SendPacketError::Disconnected)
         }
     }
+
+    /// Send subscribe packet, returns reason code for each topic filter
+    ///
+    /// Fails with `SendPacketError::Protocol` if number of reason codes in ack
+    /// does not match number of topic filters.
+    pub async fn send_checked(
+        self,
+    ) -> Result<Vec<(ByteString, codec::SubscribeAckReason)>, SendPacketError> {
+        let filters: Vec<_> =
+            self.packet.topic_filters.iter().map(|(f, _)| f.clone()).collect();
+        let ack = self.send().await?;
+
+        if ack.status.len() != filters.len() {
+            log::trace!(
+                "Number of reason codes does not match, expected {}, got: {}",
+                filters.len(),
+                ack.status.len()
+            );
+            Err(SendPacketError::Protocol)
+        } else {
+            Ok(filters.into_iter().zip(ack.status).collect())
+        }
+    }
 }
 
 /// Unsubscribe packet builder
@@ -590,23 +1843,61 @@ pub struct UnsubscribeBuilder {
     id: u16,
     packet: codec::Unsubscribe,
     shared: Rc<MqttShared>,
+    timeout: Option<Millis>,
+}
+
+impl fmt::Debug for UnsubscribeBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("UnsubscribeBuilder")
+            .field("id", &self.id)
+            .field("packet", &self.packet)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
 }
 
 impl UnsubscribeBuilder {
     /// Set packet id.
+    pub fn packet_id(mut self, id: NonZeroU16) -> Self {
+        self.id = id.get();
+        self
+    }
+
+    /// Set ack timeout, by default `send()` waits for ack indefinitely
     ///
-    /// panics if id is 0
-    pub fn packet_id(mut self, id: u16) -> Self {
-        if id == 0 {
-            panic!("id 0 is not allowed");
-        }
-        self.id = id;
+    /// On timeout packet id is released and `send()` fails with
+    /// `SendPacketError::Timeout`, late ack from peer is ignored.
+    pub fn with_timeout(mut self, timeout: Millis) -> Self {
+        self.timeout = Some(timeout);
         self
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString) -> Self {
-        self.packet.topic_filters.push(filter);
+    pub fn topic_filter<U: Into<ByteString>>(mut self, filter: U) -> Self {
+        self.packet.topic_filters.push(filter.into());
+        self
+    }
+
+    /// Add topic filter, filter gets validated
+    pub fn try_topic_filter<U: Into<ByteString>>(
+        self,
+        filter: U,
+    ) -> Result<Self, TopicFilterError> {
+        Ok(self.topic_filter(validate_topic_filter(filter.into())?))
+    }
+
+    /// Add multiple topic filters
+    pub fn topic_filters<I>(mut self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = ByteString>,
+    {
+        self.packet.topic_filters.extend(filters);
+        self
+    }
+
+    /// Remove all topic filters
+    pub fn clear_topic_filters(mut self) -> Self {
+        self.packet.topic_filters.clear();
         self
     }
 
@@ -625,15 +1916,18 @@ impl UnsubscribeBuilder {
         if !shared.io.is_closed() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.with_queues(|q| q.waiters.push_back(tx));
+                let rx = shared.wait_credit();
 
                 if rx.await.is_err() {
                     return Err(SendPacketError::Disconnected);
                 }
             }
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
+            let idx = if self.id == 0 {
+                shared.next_id().map_err(SendPacketError::Encode)?.get()
+            } else {
+                self.id
+            };
             let rx = shared.with_queues(|queues| {
                 // ack channel
                 let (tx, rx) = shared.pool.queue.channel();
@@ -650,12 +1944,28 @@ impl UnsubscribeBuilder {
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
 
-            match shared.io.encode(codec::Packet::Unsubscribe(packet), &shared.codec) {
+            let filters = packet.topic_filters.clone();
+            match shared.io.encode(codec::Packet::Unsubscribe(packet), &*shared) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.unsubscribe())
+                    let ack = match recv_ack(&rx, self.timeout).await {
+                        Ok(Ok(pkt)) => pkt.unsubscribe(),
+                        Ok(Err(_)) => return Err(SendPacketError::Disconnected),
+                        Err(_) => {
+                            log::trace!("Unsubscribe ack timeout, packet id: {}", idx);
+                            shared.remove_inflight(idx);
+                            return Err(SendPacketError::Timeout);
+                        }
+                    };
+
+                    // track active subscriptions
+                    let mut subs = shared.subscriptions.borrow_mut();
+                    for (filter, status) in filters.iter().zip(ack.status.iter()) {
+                        if *status == codec::UnsubscribeAckReason::Success {
+                            subs.retain(|(f, _)| f != filter);
+                        }
+                    }
+                    Ok(ack)
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
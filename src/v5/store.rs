@@ -0,0 +1,81 @@
+use std::sync::Mutex;
+
+use ntex::util::HashMap;
+
+use super::codec::SubscriptionOptions;
+use crate::topic::matches_filter;
+
+/// Server-side subscriptions storage
+///
+/// Storage is updated by server with subscriptions accepted by control service,
+/// see `MqttServer::subscription_store()`. Client subscriptions are removed when
+/// connection closes, if client connected with clean start flag or with zero
+/// session expiry interval.
+pub trait SubscriptionStore: Send + Sync {
+    /// Add client subscription, existing subscription for the same filter is replaced
+    fn subscribe(&self, client_id: &str, filter: &str, opts: SubscriptionOptions);
+
+    /// Remove client subscription
+    fn unsubscribe(&self, client_id: &str, filter: &str);
+
+    /// Remove all client subscriptions
+    fn remove_client(&self, client_id: &str);
+
+    /// Get clients subscribed to topic
+    ///
+    /// Client is returned once for each matching subscription.
+    fn subscribers_for(&self, topic: &str) -> Vec<(String, SubscriptionOptions)>;
+}
+
+/// In-memory subscriptions storage
+#[derive(Debug, Default)]
+pub struct InMemorySubscriptionStore {
+    /// Subscribers by topic filter
+    filters: Mutex<HashMap<String, Vec<(String, SubscriptionOptions)>>>,
+}
+
+impl InMemorySubscriptionStore {
+    /// Create empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SubscriptionStore for InMemorySubscriptionStore {
+    fn subscribe(&self, client_id: &str, filter: &str, opts: SubscriptionOptions) {
+        let mut filters = self.filters.lock().unwrap();
+        let subscribers = filters.entry(filter.to_string()).or_default();
+        if let Some(item) = subscribers.iter_mut().find(|(id, _)| id == client_id) {
+            item.1 = opts;
+        } else {
+            subscribers.push((client_id.to_string(), opts));
+        }
+    }
+
+    fn unsubscribe(&self, client_id: &str, filter: &str) {
+        let mut filters = self.filters.lock().unwrap();
+        if let Some(subscribers) = filters.get_mut(filter) {
+            subscribers.retain(|(id, _)| id != client_id);
+            if subscribers.is_empty() {
+                filters.remove(filter);
+            }
+        }
+    }
+
+    fn remove_client(&self, client_id: &str) {
+        self.filters.lock().unwrap().retain(|_, subscribers| {
+            subscribers.retain(|(id, _)| id != client_id);
+            !subscribers.is_empty()
+        });
+    }
+
+    fn subscribers_for(&self, topic: &str) -> Vec<(String, SubscriptionOptions)> {
+        self.filters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(filter, _)| matches_filter(filter, topic))
+            .flat_map(|(_, subscribers)| subscribers.iter().cloned())
+            .collect()
+    }
+}
@@ -0,0 +1,95 @@
+use ntex::time::Seconds;
+use ntex::util::{ByteString, Bytes};
+
+use super::codec;
+use crate::types::QoS;
+
+/// Last will message builder
+///
+/// ```rust
+/// use ntex::{time::Seconds, util::Bytes};
+/// use ntex_mqtt::v5::{QoS, WillMessageBuilder};
+///
+/// let will = WillMessageBuilder::new("client/status")
+///     .payload(Bytes::from_static(b"offline"))
+///     .qos(QoS::AtLeastOnce)
+///     .retain()
+///     .delay_interval(Seconds(10))
+///     .properties(|will| will.content_type = Some("text/plain".into()))
+///     .build();
+/// assert_eq!(will.will_delay_interval_sec, Some(10));
+/// ```
+#[derive(Debug, Clone)]
+pub struct WillMessageBuilder {
+    will: codec::LastWill,
+}
+
+impl WillMessageBuilder {
+    /// Create will message builder for the topic
+    ///
+    /// By default will message has empty payload and QoS 0.
+    pub fn new<U: Into<ByteString>>(topic: U) -> Self {
+        WillMessageBuilder {
+            will: codec::LastWill {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: topic.into(),
+                message: Bytes::new(),
+                will_delay_interval_sec: None,
+                correlation_data: None,
+                message_expiry_interval: None,
+                content_type: None,
+                user_properties: Vec::new(),
+                is_utf8_payload: None,
+                response_topic: None,
+            },
+        }
+    }
+
+    /// Set will topic
+    pub fn topic<U: Into<ByteString>>(mut self, topic: U) -> Self {
+        self.will.topic = topic.into();
+        self
+    }
+
+    /// Set will payload
+    pub fn payload(mut self, payload: Bytes) -> Self {
+        self.will.message = payload;
+        self
+    }
+
+    /// Set QoS level of the will message
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.will.qos = qos;
+        self
+    }
+
+    /// Retain will message when it gets published
+    pub fn retain(mut self) -> Self {
+        self.will.retain = true;
+        self
+    }
+
+    /// Set will delay interval
+    ///
+    /// Zero value resets delay interval.
+    pub fn delay_interval(mut self, interval: Seconds) -> Self {
+        self.will.will_delay_interval_sec =
+            if interval.non_zero() { Some(u32::from(interval.0)) } else { None };
+        self
+    }
+
+    /// Update will message properties
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::LastWill),
+    {
+        f(&mut self.will);
+        self
+    }
+
+    /// Create will message
+    pub fn build(self) -> codec::LastWill {
+        self.will
+    }
+}
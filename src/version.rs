@@ -5,14 +5,20 @@ use ntex::util::BytesMut;
 
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{packet_type, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
-use crate::utils;
+use crate::{proxy_protocol, utils};
 
+/// Mqtt protocol version
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub(super) enum ProtocolVersion {
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1
     MQTT3,
+    /// MQTT 5.0
     MQTT5,
 }
 
+/// Detects protocol version without consuming data
+///
+/// PROXY protocol header is skipped, it is stripped later by v3 or v5 server.
 #[derive(Debug)]
 pub(super) struct VersionCodec;
 
@@ -21,14 +27,22 @@ impl Decoder for VersionCodec {
     type Error = DecodeError;
 
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        let src = if proxy_protocol::is_header(src) {
+            match proxy_protocol::parse(src)? {
+                Some((len, _)) => &src[len..],
+                None => return Ok(None),
+            }
+        } else {
+            &src[..]
+        };
+
         let len = src.len();
         if len < 2 {
             return Ok(None);
         }
 
-        let src_slice = src.as_ref();
-        let first_byte = src_slice[0];
-        match utils::decode_variable_length(&src_slice[1..])? {
+        let first_byte = src[0];
+        match utils::decode_variable_length(&src[1..])? {
             Some((_, mut consumed)) => {
                 consumed += 1;
 
@@ -96,4 +110,23 @@ mod tests {
         let mut buf = BytesMut::from(b"\x10\x98\x02\0\x04MQTT".as_ref());
         assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
     }
+
+    #[test]
+    fn test_decode_proxy_header() {
+        let mut buf = BytesMut::from(
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10\x98\x02\0\x04MQTT\x05"
+                .as_ref(),
+        );
+        assert_eq!(ProtocolVersion::MQTT5, VersionCodec.decode(&mut buf).unwrap().unwrap());
+        assert!(buf.starts_with(b"PROXY"));
+
+        let mut buf = BytesMut::from(b"PROXY TCP4 192.168.0.1".as_ref());
+        assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
+
+        let mut buf = BytesMut::from(b"PROXY UNKNOWN\r\n\x10\x98".as_ref());
+        assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
+
+        let mut buf = BytesMut::from(b"PROXY UDP4\r\n\x10\x98\x02\0\x04MQTT\x05".as_ref());
+        assert_eq!(Err(DecodeError::InvalidProxyHeader), VersionCodec.decode(&mut buf));
+    }
 }
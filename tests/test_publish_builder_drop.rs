@@ -0,0 +1,89 @@
+use std::{convert::TryFrom, sync::Mutex};
+
+use ntex::server;
+use ntex::time::Millis;
+use ntex::util::{Bytes, Ready};
+
+use ntex_mqtt::v5::{client, Handshake, MqttServer, Publish, PublishAck};
+
+struct St;
+
+#[derive(Debug)]
+struct TestError;
+
+impl From<()> for TestError {
+    fn from(_: ()) -> Self {
+        TestError
+    }
+}
+
+impl TryFrom<TestError> for PublishAck {
+    type Error = TestError;
+
+    fn try_from(err: TestError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+/// Logger that captures warnings, logger is global so this test lives in its own binary
+struct Capture(Mutex<Vec<String>>);
+
+impl log::Log for Capture {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Capture = Capture(Mutex::new(Vec::new()));
+
+fn warnings() -> usize {
+    let mut logs = LOGGER.0.lock().unwrap();
+    let count =
+        logs.iter().filter(|msg| *msg == "PublishBuilder dropped without sending").count();
+    logs.clear();
+    count
+}
+
+#[ntex::test]
+async fn test_publish_builder_drop_warning() -> std::io::Result<()> {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake| Ready::Ok::<_, TestError>(con.ack(St)))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("test", Bytes::new()).send_at_most_once().unwrap();
+    sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    assert_eq!(warnings(), 0);
+
+    let builder = sink.publish("test", Bytes::new());
+    builder.clone().send_at_most_once().unwrap();
+    assert_eq!(warnings(), 0);
+    drop(builder);
+    assert_eq!(warnings(), 1);
+
+    // template builder is discarded explicitly
+    let builder = sink.publish("test", Bytes::new());
+    builder.clone().topic("topic1").send_at_most_once().unwrap();
+    builder.discard();
+    assert_eq!(warnings(), 0);
+
+    sink.close();
+    Ok(())
+}
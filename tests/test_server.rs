@@ -1,4 +1,4 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc, Mutex};
 use std::{num::NonZeroU16, time::Duration};
 
 use ntex::service::{Service, ServiceFactory};
@@ -7,7 +7,8 @@ use ntex::util::{join_all, ByteString, Bytes, Ready};
 use ntex::{server, service::pipeline_factory};
 
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Router,
+    Session,
 };
 
 struct St;
@@ -42,6 +43,34 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+#[allow(clippy::mutable_key_type)]
+async fn test_sink_eq_and_id() -> std::io::Result<()> {
+    let srv =
+        server::test_server(|| MqttServer::new(handshake).publish(|_t| Ready::Ok(())).finish());
+
+    let client1 =
+        client::MqttConnector::new(srv.addr()).client_id("user1").connect().await.unwrap();
+    let client2 =
+        client::MqttConnector::new(srv.addr()).client_id("user2").connect().await.unwrap();
+
+    let sink1 = client1.sink();
+    let sink2 = client2.sink();
+    assert_eq!(sink1, sink1.clone());
+    assert_ne!(sink1, sink2);
+    assert_eq!(sink1.connection_id(), sink1.clone().connection_id());
+    assert_ne!(sink1.connection_id(), sink2.connection_id());
+
+    let sinks: std::collections::HashSet<_> =
+        vec![sink1.clone(), sink2.clone(), sink1.clone()].into_iter().collect();
+    assert_eq!(sinks.len(), 2);
+    assert!(sinks.contains(&sink2));
+
+    sink1.close();
+    sink2.close();
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_connect_fail() -> std::io::Result<()> {
     // bad user name or password
@@ -419,3 +448,278 @@ async fn test_large_publish_openssl() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_sink_inflight_count() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_send(2)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(sink.max_inflight(), 2);
+    assert_eq!(sink.inflight_count(), 0);
+    assert_eq!(sink.waiter_count(), 0);
+
+    for _ in 0..3 {
+        let fut = sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000));
+        ntex::rt::spawn(async move {
+            let _ = fut.await;
+        });
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.credit(), 0);
+    assert_eq!(sink.inflight_count(), 2);
+    assert_eq!(sink.waiter_count(), 1);
+    assert_eq!(sink.inflight_packet_ids(), vec![1, 2]);
+    assert_eq!(sink.inflight_order(), vec![1, 2]);
+
+    sink.close();
+    assert_eq!(sink.inflight_count(), 0);
+    assert_eq!(sink.waiter_count(), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_subscribe() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic() == "topic2" {
+                            sub.fail();
+                        } else {
+                            sub.confirm(sub.qos());
+                        }
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => {
+                    topics.lock().unwrap().extend(msg.iter().cloned());
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter("topic1", codec::QoS::AtLeastOnce)
+        .topic_filters(vec![
+            ("topic2".into(), codec::QoS::AtLeastOnce),
+            ("topic3".into(), codec::QoS::AtMostOnce),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+        ]
+    );
+
+    let res = sink
+        .unsubscribe()
+        .topic_filter("topic0")
+        .clear_topic_filters()
+        .topic_filters(vec!["topic1".into(), "topic3".into()])
+        .send()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(*topics.lock().unwrap(), vec!["topic1", "topic3"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_default_control_subscribe() -> std::io::Result<()> {
+    let srv =
+        server::test_server(|| MqttServer::new(handshake).publish(|_t| Ready::Ok(())).finish());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter("topic1", codec::QoS::AtMostOnce)
+        .topic_filter("topic2", codec::QoS::ExactlyOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+        ]
+    );
+    assert!(sink.unsubscribe().topic_filter("topic1").send().await.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_ack_with() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(msg) => Ready::Ok(msg.ack_with(vec![
+                    codec::SubscribeReturnCode::Failure,
+                    codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+                ])),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter("topic1", codec::QoS::AtLeastOnce)
+        .topic_filter("topic2", codec::QoS::AtLeastOnce)
+        .topic_filter("topic3", codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+            codec::SubscribeReturnCode::Failure,
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let handler = move |name: &'static str| {
+            let hits = hits.clone();
+            move |p: Publish| {
+                hits.lock().unwrap().push((name, p.publish_topic().to_string(), p.qos()));
+                Ready::Ok::<_, ()>(())
+            }
+        };
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(ntex::service::fn_factory_with_config({
+                    let default = handler("default");
+                    move |_: Session<St>| {
+                        Ready::Ok::<_, ()>(ntex::service::fn_service(default.clone()))
+                    }
+                }))
+                .resource("sensors/{id}/temperature", handler("temp"))
+                .resource_fn("sensors/{tail}*", handler("all"))
+                .finish(),
+            )
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("sensors/1/temperature", Bytes::new()).send_at_most_once().unwrap();
+    sink.publish("sensors/1/humidity", Bytes::new()).send_at_most_once().unwrap();
+    for topic in ["sensors/2/temperature", "sensors/2", "other"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        *hits.lock().unwrap(),
+        vec![
+            ("temp", "sensors/1/temperature".to_string(), codec::QoS::AtMostOnce),
+            ("all", "sensors/1/humidity".to_string(), codec::QoS::AtMostOnce),
+            ("temp", "sensors/2/temperature".to_string(), codec::QoS::AtLeastOnce),
+            ("all", "sensors/2".to_string(), codec::QoS::AtLeastOnce),
+            ("default", "other".to_string(), codec::QoS::AtLeastOnce),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_peer_addr() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                *addr.lock().unwrap() = session.peer_addr();
+                Ready::Ok::<_, ()>(ntex::service::fn_service(|_: Publish| Ready::Ok(())))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("#"), Bytes::new())
+        .send_at_least_once(Millis(1_000))
+        .await;
+    assert!(res.is_ok());
+    let addr = addr.lock().unwrap().take().unwrap();
+    assert!(addr.ip().is_loopback());
+
+    sink.close();
+    Ok(())
+}
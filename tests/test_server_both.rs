@@ -1,8 +1,10 @@
-use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::{convert::TryFrom, net::SocketAddr, num::NonZeroU16};
 
-use ntex::server;
+use ntex::service::{fn_factory_with_config, fn_service};
 use ntex::time::Millis;
 use ntex::util::{ByteString, Bytes, Ready};
+use ntex::{codec::BytesCodec, server};
 
 use ntex_mqtt::{v3, v5, MqttServer};
 
@@ -46,8 +48,10 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let timeout = Millis(1_000);
-    let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once(timeout)
+        .await;
     assert!(res.is_ok());
     sink.close();
 
@@ -65,3 +69,105 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_proxy_protocol() -> std::io::Result<()> {
+    let addr: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr3 = addr2.clone();
+        let addr5 = addr2.clone();
+        MqttServer::new()
+            .v3(v3::MqttServer::new(|con: v3::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St, false))
+            })
+            .proxy_protocol(true)
+            .publish(fn_factory_with_config(move |session: v3::Session<St>| {
+                *addr3.lock().unwrap() = session.peer_addr();
+                Ready::Ok::<_, TestError>(fn_service(|_| Ready::Ok::<_, TestError>(())))
+            })))
+            .v5(v5::MqttServer::new(|con: v5::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St))
+            })
+            .proxy_protocol(true)
+            .publish(fn_factory_with_config(move |session: v5::Session<St>| {
+                *addr5.lock().unwrap() = session.peer_addr();
+                Ready::Ok::<_, TestError>(fn_service(|p: v5::Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            })))
+    });
+
+    // v5 client
+    let io = srv.connect().await.unwrap();
+    io.send(
+        Bytes::from_static(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n"),
+        &BytesCodec,
+    )
+    .await
+    .unwrap();
+    let codec = v5::codec::Codec::default();
+    io.send(
+        v5::codec::Packet::Connect(Box::new(v5::codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, v5::codec::Packet::ConnectAck(_)));
+    io.send(
+        v5::codec::Publish {
+            dup: false,
+            retain: false,
+            qos: v5::codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: Default::default(),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, v5::codec::Packet::PublishAck(_)));
+    assert_eq!(*addr.lock().unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+
+    // v3 client
+    let io = srv.connect().await.unwrap();
+    io.send(
+        Bytes::from_static(b"PROXY TCP4 192.168.0.2 192.168.0.11 56325 1883\r\n"),
+        &BytesCodec,
+    )
+    .await
+    .unwrap();
+    let codec = v3::codec::Codec::default();
+    io.send(
+        v3::codec::Packet::Connect(v3::codec::Connect::default().client_id("user").into()),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, v3::codec::Packet::ConnectAck { .. }));
+    io.send(
+        v3::codec::Packet::Publish(v3::codec::Publish {
+            dup: false,
+            retain: false,
+            qos: v3::codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, v3::codec::Packet::PublishAck { .. }));
+    assert_eq!(*addr.lock().unwrap(), Some("192.168.0.2:56325".parse().unwrap()));
+
+    Ok(())
+}
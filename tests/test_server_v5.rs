@@ -1,13 +1,16 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{convert::TryFrom, num::NonZeroU16, rc::Rc, time::Duration};
 
-use ntex::time::Millis;
+use ntex::service::{Service, ServiceFactory};
+use ntex::time::{Millis, Seconds};
 use ntex::util::{ByteString, Bytes, Ready};
 use ntex::{server, service::fn_service, time::sleep};
 
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, ControlMessage, GroupAckMerge, Handshake, HandshakeAck,
+    InMemorySubscriptionStore, MqttServer, Publish, PublishAck, QoS, QosAckResult, Router,
+    Session, SessionSnapshot, SubscriptionStore, WillMessageBuilder,
 };
 
 struct St;
@@ -63,7 +66,7 @@ async fn test_simple() -> std::io::Result<()> {
 
     let timeout = Millis(1_000);
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_ok());
 
     sink.close();
@@ -119,7 +122,7 @@ async fn test_disconnect() -> std::io::Result<()> {
 
     let timeout = Millis(1_000);
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_err());
 
     Ok(())
@@ -155,7 +158,7 @@ async fn test_disconnect_with_reason() -> std::io::Result<()> {
 
     let timeout = Millis(1_000);
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_err());
 
     Ok(())
@@ -511,6 +514,54 @@ async fn test_max_receive() {
     );
 }
 
+#[ntex::test]
+async fn test_max_receive_default_control() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            packet_id: Some(NonZeroU16::new(1).unwrap()),
+            qos: codec::QoS::ExactlyOnce,
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() }.into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ReceiveMaximumExceeded
+        ))
+    );
+}
+
 #[ntex::test]
 async fn test_keepalive() {
     let ka = Arc::new(AtomicBool::new(false));
@@ -580,11 +631,11 @@ async fn test_keepalive2() {
     let timeout = Millis(1_000);
     assert!(sink.is_open());
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_ok());
     sleep(Duration::from_millis(500)).await;
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_ok());
     sleep(Duration::from_millis(2000)).await;
 
@@ -592,6 +643,47 @@ async fn test_keepalive2() {
     assert!(ka.load(Relaxed));
 }
 
+#[ntex::test]
+async fn test_keepalive_max() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .keep_alive_max(1)
+            .keep_alive_tolerance(1.0)
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect {
+            keep_alive: 60,
+            ..codec::Connect::default().client_id("user")
+        })),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.server_keepalive_sec, Some(1));
+    } else {
+        panic!()
+    }
+
+    sleep(Duration::from_millis(2000)).await;
+    // connection is closed, disconnect packet could be dropped
+    if let Ok(Some(pkt)) = io.recv(&codec).await {
+        assert_eq!(
+            pkt,
+            codec::Packet::Disconnect(codec::Disconnect::new(
+                codec::DisconnectReasonCode::KeepAliveTimeout
+            ))
+        );
+    }
+    assert!(io.is_closed());
+}
+
 #[ntex::test]
 async fn test_sink_encoder_error_pub_qos1() {
     let srv = server::test_server(move || {
@@ -605,10 +697,12 @@ async fn test_sink_encoder_error_pub_qos1() {
             ntex::rt::spawn(async move {
                 let timeout = Millis(1_000);
                 let res = builder.send_at_least_once(timeout).await;
-                assert_eq!(
+                assert!(matches!(
                     res,
-                    Err(error::PublishQos1Error::Encode(error::EncodeError::InvalidLength))
-                );
+                    Err(error::PublishQos1Error::Encode(
+                        error::EncodeError::PacketTooLarge { .. }
+                    ))
+                ));
             });
             Ok(con.ack(St))
         })
@@ -637,7 +731,7 @@ async fn test_sink_encoder_error_pub_qos1() {
 
     let timeout = Millis(1_000);
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_ok());
 }
 
@@ -652,10 +746,10 @@ async fn test_sink_encoder_error_pub_qos0() {
                 ));
             });
             let res = builder.send_at_most_once();
-            assert_eq!(
+            assert!(matches!(
                 res,
-                Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength))
-            );
+                Err(error::SendPacketError::Encode(error::EncodeError::PacketTooLarge { .. }))
+            ));
             Ok(con.ack(St))
         })
         .publish(|p: Publish| async move {
@@ -683,7 +777,7 @@ async fn test_sink_encoder_error_pub_qos0() {
 
     let timeout = Millis(1_000);
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once(timeout).await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once(timeout).await;
     assert!(res.is_ok());
 }
 
@@ -721,7 +815,7 @@ async fn test_request_problem_info() {
 
     let timeout = Millis(1_000);
     let res = sink
-        .publish(ByteString::from_static("#"), Bytes::new())
+        .publish(ByteString::from_static("test"), Bytes::new())
         .send_at_least_once(timeout)
         .await
         .unwrap();
@@ -788,6 +882,86 @@ async fn test_suback_with_reason() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_ack_properties() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                Ready::Ok::<_, TestError>(
+                    p.ack()
+                        .user_property("key1".into(), "val1".into())
+                        .user_property("key2".into(), "val2".into())
+                        .reason("done".into()),
+                )
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: vec![("key1".into(), "val1".into()), ("key2".into(), "val2".into())],
+            reason_string: Some("done".into()),
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unexpected_ack() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(5).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ProtocolError
+        ))
+    );
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_handle_incoming() -> std::io::Result<()> {
     let publish = Arc::new(AtomicBool::new(false));
@@ -842,3 +1016,3714 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_sink_publish_batch() -> std::io::Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let counter2 = counter.clone();
+
+    let srv = server::test_server(move || {
+        let counter = counter2.clone();
+        MqttServer::new(handshake)
+            .max_size(30)
+            .publish(move |p: Publish| {
+                counter.fetch_add(1, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish_batch(vec![("test1", Bytes::new()), ("test2", Bytes::new())]);
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(counter.load(Relaxed), 2);
+
+    // second packet exceeds max packet size, nothing should be sent
+    let res = sink.publish_batch(vec![
+        ("test1", Bytes::new()),
+        ("test2", Bytes::from_static(b"ssssssssssssssssssssssssssssssssssss")),
+    ]);
+    assert!(matches!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::PacketTooLarge { .. }))
+    ));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(counter.load(Relaxed), 2);
+
+    // invalid topics, nothing should be sent
+    let res = sink.publish_batch(vec![("test1", Bytes::new()), ("a/#", Bytes::new())]);
+    assert_eq!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::InvalidPublish(
+            error::PublishValidationError::TopicWildcard
+        )))
+    );
+    let res = sink.publish_batch(vec![("test1", Bytes::new()), ("", Bytes::new())]);
+    assert_eq!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::InvalidPublish(
+            error::PublishValidationError::EmptyTopic
+        )))
+    );
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(counter.load(Relaxed), 2);
+    assert!(sink.is_open());
+
+    // each packet takes rate limit token, oversized packets do not
+    sink.rate_limit(2);
+    let res = sink
+        .publish("test1", Bytes::from_static(b"ssssssssssssssssssssssssssssssssssss"))
+        .send_at_most_once();
+    assert!(matches!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::PacketTooLarge { .. }))
+    ));
+    let res = sink.publish_batch(vec![
+        ("test1", Bytes::new()),
+        ("test2", Bytes::from_static(b"ssssssssssssssssssssssssssssssssssss")),
+    ]);
+    assert!(matches!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::PacketTooLarge { .. }))
+    ));
+    let res = sink.publish_batch(vec![
+        ("test1", Bytes::new()),
+        ("test2", Bytes::new()),
+        ("test3", Bytes::new()),
+    ]);
+    assert_eq!(res, Err(error::SendPacketError::RateLimited));
+    sink.publish_batch(vec![("test1", Bytes::new()), ("test2", Bytes::new())]).unwrap();
+    assert_eq!(
+        sink.publish_batch(vec![("test1", Bytes::new())]),
+        Err(error::SendPacketError::RateLimited)
+    );
+    sink.rate_limit(0);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(counter.load(Relaxed), 4);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_max_retries() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let builder = con.sink().publish("test", Bytes::new()).max_retries(2);
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let res = builder.send_at_least_once(Millis(100)).await;
+                    *result.lock().unwrap() = Some(res);
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(_)));
+
+    // peer never acks publish, packet get re-sent twice
+    for dup in [false, true, true] {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => assert_eq!(pkt.dup, dup),
+            pkt => panic!("unexpected packet: {:?}", pkt),
+        }
+    }
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(*result.lock().unwrap(), Some(Err(error::PublishQos1Error::MaxRetriesExceeded)));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_inflight_count() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(2)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(sink.max_inflight(), 2);
+    assert_eq!(sink.inflight_count(), 0);
+    assert_eq!(sink.waiter_count(), 0);
+
+    for _ in 0..3 {
+        let fut = sink.publish("test", Bytes::new()).send_at_least_once(Millis(10000));
+        ntex::rt::spawn(async move {
+            let _ = fut.await;
+        });
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.credit(), 0);
+    assert_eq!(sink.inflight_count(), 2);
+    assert_eq!(sink.waiter_count(), 1);
+    assert_eq!(sink.inflight_packet_ids(), vec![1, 2]);
+    assert_eq!(sink.inflight_order(), vec![1, 2]);
+    assert!(format!("{:?}", sink).contains("inflight: 2"));
+
+    sink.close();
+    assert_eq!(sink.inflight_count(), 0);
+    assert_eq!(sink.waiter_count(), 0);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_resources() -> std::io::Result<()> {
+    let inits = Arc::new(AtomicUsize::new(0));
+    let hits = Arc::new(AtomicUsize::new(0));
+    let inits2 = inits.clone();
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let inits = inits2.clone();
+        let hits = hits2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(ntex::service::fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resources(
+                    ["sensors/temp", "devices/temp"],
+                    ntex::service::fn_factory_with_config(move |_: Session<St>| {
+                        inits.fetch_add(1, Relaxed);
+                        let hits = hits.clone();
+                        Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                            hits.fetch_add(1, Relaxed);
+                            Ready::Ok::<_, TestError>(p.ack())
+                        }))
+                    }),
+                ),
+            )
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["sensors/temp", "devices/temp", "other", "sensors/temp"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(inits.load(Relaxed), 1);
+    assert_eq!(hits.load(Relaxed), 3);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_subscribe_topic_filters() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut s in msg.iter_mut() {
+                        topics.lock().unwrap().push(s.topic().clone());
+                        s.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => {
+                    for topic in msg.iter() {
+                        topics.lock().unwrap().push(topic.clone());
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let ack = sink
+        .subscribe(None)
+        .topic_filter("topic0", opts.clone())
+        .clear_topic_filters()
+        .topic_filters(vec![("topic1".into(), opts.clone()), ("topic2".into(), opts.clone())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(ack.status.len(), 2);
+    assert_eq!(*topics.lock().unwrap(), vec!["topic1", "topic2"]);
+
+    let res = sink
+        .subscribe(None)
+        .topic_filter("topic3", opts.clone())
+        .topic_filter("topic4", opts)
+        .send_checked()
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        vec![
+            ("topic3".into(), codec::SubscribeAckReason::GrantedQos1),
+            ("topic4".into(), codec::SubscribeAckReason::GrantedQos1)
+        ]
+    );
+
+    topics.lock().unwrap().clear();
+    let ack = sink
+        .unsubscribe()
+        .topic_filter("topic0")
+        .clear_topic_filters()
+        .topic_filters(vec!["topic1".into(), "topic2".into()])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(ack.status.len(), 2);
+    assert_eq!(*topics.lock().unwrap(), vec!["topic1", "topic2"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_resource_priority() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let handler = move |name: &'static str| {
+            let hits = hits.clone();
+            ntex::service::fn_factory_with_config(move |_: Session<St>| {
+                let hits = hits.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    hits.lock().unwrap().push(name);
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            })
+        };
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(handler("default"))
+                    .resource("sensors/{tail}*", handler("all"))
+                    .resource_with_priority("sensors/{id}/temperature", 10, handler("temp"))
+                    .resource_with_priority("sensors/{id}/{kind}", 5, handler("kind")),
+            )
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["sensors/1/temperature", "sensors/1/humidity", "sensors/1", "other"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(*hits.lock().unwrap(), vec!["temp", "kind", "all", "default"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_topic_aliases() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publishes
+                    .lock()
+                    .unwrap()
+                    .push((p.publish_topic().to_string(), p.packet().properties.topic_alias));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    sink.enable_topic_aliases(1);
+    let timeout = Millis(1_000);
+    sink.publish("topic1", Bytes::new()).send_at_least_once(timeout).await.unwrap();
+    sink.publish("topic1", Bytes::new()).send_at_most_once().unwrap();
+    sink.publish("topic2", Bytes::new()).send_at_least_once(timeout).await.unwrap();
+    sink.publish("topic1", Bytes::new()).send_at_least_once(timeout).await.unwrap();
+
+    let alias = NonZeroU16::new(1);
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec![
+            ("topic1".to_string(), alias),
+            ("".to_string(), alias),
+            ("topic2".to_string(), None),
+            ("".to_string(), alias),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_handler_count() -> std::io::Result<()> {
+    fn router() -> Router<St, TestError> {
+        let handler = || {
+            ntex::service::fn_factory_with_config(|_: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            })
+        };
+        Router::new(handler())
+            .resource("topic1", handler())
+            .resources(["topic2", "topic3"], handler())
+            .resource("topic4", handler())
+    }
+
+    assert_eq!(router().finish().handler_names(), vec!["topic1", "topic2", "topic3", "topic4"]);
+
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let counts2 = counts.clone();
+
+    let srv = server::test_server(move || {
+        let counts = counts2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let counts = counts.clone();
+                let fut = router().finish().new_service(session);
+                async move {
+                    let srv = Rc::new(fut.await?);
+                    Ok::<_, TestError>(fn_service(move |p: Publish| {
+                        let srv = srv.clone();
+                        let counts = counts.clone();
+                        async move {
+                            let res = srv.call(p).await;
+                            counts
+                                .lock()
+                                .unwrap()
+                                .push((srv.handler_count(), srv.initialized_handler_count()));
+                            res
+                        }
+                    }))
+                }
+            }))
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["topic1", "topic2", "topic3", "topic1", "other"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(*counts.lock().unwrap(), vec![(3, 1), (3, 2), (3, 2), (3, 2), (3, 2)]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_close_graceful() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(200)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let result = Rc::new(std::cell::RefCell::new(None));
+    let result2 = result.clone();
+    let fut = sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000));
+    ntex::rt::spawn(async move {
+        *result2.borrow_mut() = Some(fut.await);
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let close = sink.clone().close_graceful(Millis(1_000));
+    assert!(sink.is_open());
+    assert_eq!(
+        sink.publish("test", Bytes::new()).send_at_most_once(),
+        Err(error::SendPacketError::Disconnected)
+    );
+    close.await;
+
+    assert!(result.borrow_mut().take().unwrap().is_ok());
+    assert!(!sink.is_open());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_properties() -> std::io::Result<()> {
+    let props = Arc::new(Mutex::new(None));
+    let props2 = props.clone();
+
+    let srv = server::test_server(move || {
+        let props = props2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                assert_eq!(p.content_type(), Some(&"text".into()));
+                assert_eq!(p.response_topic(), Some(&"reply".into()));
+                assert_eq!(p.correlation_data(), Some(&Bytes::from_static(b"data")));
+                assert_eq!(p.correlation_id(), None);
+                assert_eq!(p.message_expiry_interval(), None);
+                *props.lock().unwrap() = Some(p.packet().properties.clone());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish("test", Bytes::new())
+        .properties(|p| p.is_utf8_payload = Some(true))
+        .content_type("text".into())
+        .add_user_property("key1".into(), "val1".into())
+        .add_user_property("key2".into(), "val2".into())
+        .response_topic("reply".into())
+        .correlation_data(Bytes::from_static(b"data"))
+        .send_at_least_once(Millis(1_000))
+        .await;
+    assert!(res.is_ok());
+
+    let props = props.lock().unwrap().take().unwrap();
+    assert_eq!(props.is_utf8_payload, Some(true));
+    assert_eq!(props.content_type, Some("text".into()));
+    assert_eq!(
+        props.user_properties,
+        vec![("key1".into(), "val1".into()), ("key2".into(), "val2".into())]
+    );
+    assert_eq!(props.response_topic, Some("reply".into()));
+    assert_eq!(props.correlation_data, Some(Bytes::from_static(b"data")));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_correlation_id() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                ids.lock().unwrap().push(p.correlation_id());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for id in [0, 42, u64::MAX] {
+        let res = sink
+            .publish("test", Bytes::new())
+            .correlation_id(id)
+            .send_at_least_once(Millis(1_000))
+            .await;
+        assert!(res.is_ok());
+    }
+    let res = sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+
+    assert_eq!(*ids.lock().unwrap(), vec![Some(0), Some(42), Some(u64::MAX), None]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_json() -> std::io::Result<()> {
+    let msgs = Arc::new(Mutex::new(Vec::new()));
+    let msgs2 = msgs.clone();
+
+    let srv = server::test_server(move || {
+        let msgs = msgs2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                assert!(p.payload_as_str().is_ok());
+                if p.content_type().is_some() {
+                    let val: serde_json::Value = p.payload_as_json().unwrap();
+                    assert_eq!(val, serde_json::json!({"key": 1}));
+                } else {
+                    assert!(p.payload_as_json::<serde_json::Value>().is_err());
+                }
+                let props = &p.packet().properties;
+                msgs.lock().unwrap().push((
+                    props.content_type.clone(),
+                    props.is_utf8_payload,
+                    p.packet().payload.clone(),
+                ));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish_str("test", "text").send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+    let res = sink
+        .publish_json("test", &serde_json::json!({"key": 1}))
+        .unwrap()
+        .send_at_least_once(Millis(1_000))
+        .await;
+    assert!(res.is_ok());
+
+    let msgs = msgs.lock().unwrap();
+    assert_eq!(msgs[0], (None, Some(true), Bytes::from_static(b"text")));
+    assert_eq!(
+        msgs[1],
+        (Some("application/json".into()), Some(true), Bytes::from_static(b"{\"key\":1}"))
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+#[allow(clippy::mutable_key_type)]
+async fn test_sink_eq_and_id() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client1 =
+        client::MqttConnector::new(srv.addr()).client_id("user1").connect().await.unwrap();
+    let client2 =
+        client::MqttConnector::new(srv.addr()).client_id("user2").connect().await.unwrap();
+
+    let sink1 = client1.sink();
+    let sink2 = client2.sink();
+    assert_eq!(sink1, sink1.clone());
+    assert_ne!(sink1, sink2);
+    assert_eq!(sink1.connection_id(), sink1.clone().connection_id());
+    assert_ne!(sink1.connection_id(), sink2.connection_id());
+
+    let sinks: std::collections::HashSet<_> =
+        vec![sink1.clone(), sink2.clone(), sink1.clone()].into_iter().collect();
+    assert_eq!(sinks.len(), 2);
+    assert!(sinks.contains(&sink2));
+
+    sink1.close();
+    sink2.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_raw() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                ids.lock().unwrap().push(p.id());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    // qos1/2 packets require in-flight tracking
+    let res = sink.publish_raw(pkt_publish());
+    assert_eq!(res, Err(error::SendPacketError::UnsupportedQoS(codec::QoS::AtLeastOnce)));
+    assert_eq!(sink.inflight_count(), 0);
+
+    // packet id is required
+    let res = sink
+        .publish_raw_qos1(codec::Publish { packet_id: None, ..pkt_publish() }, Millis(1_000))
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::Encode(error::EncodeError::PacketIdRequired)));
+
+    let res = sink.publish_raw(codec::Publish {
+        qos: codec::QoS::AtMostOnce,
+        packet_id: None,
+        ..pkt_publish()
+    });
+    assert!(res.is_ok());
+    let res = sink
+        .publish_raw_qos1(
+            codec::Publish { packet_id: NonZeroU16::new(5), ..pkt_publish() },
+            Millis(1_000),
+        )
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(*ids.lock().unwrap(), vec![None, NonZeroU16::new(5)]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_resource_fn() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let hits3 = hits2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(ntex::service::fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource_fn("topic1", move |p: Publish| {
+                    hits.lock().unwrap().push(p.publish_topic().to_string());
+                    Ready::Ok(p.ack())
+                })
+                .resource_fn_with_session(
+                    "topic2",
+                    move |p: Publish, s: Session<St>| {
+                        assert!(s.sink().is_open());
+                        hits3.lock().unwrap().push(p.publish_topic().to_string());
+                        Ready::Ok(p.ack())
+                    },
+                ),
+            )
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["topic1", "topic2", "topic3"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(*hits.lock().unwrap(), vec!["topic1", "topic2"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_ready_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(sink.ready_timeout(Millis(50)).await, Ok(true));
+
+    let fut = sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000));
+    ntex::rt::spawn(async move {
+        let _ = fut.await;
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(sink.ready_timeout(Millis(50)).await, Err(error::TimeoutError));
+    assert_eq!(sink.waiter_count(), 0);
+
+    // dropped future does not leave waiter behind
+    let res = ntex::time::timeout(Millis(50), sink.ready_timeout(Millis(10_000))).await;
+    assert!(res.is_err());
+    assert_eq!(sink.waiter_count(), 0);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_session_snapshot() -> std::io::Result<()> {
+    let dups = Arc::new(Mutex::new(Vec::new()));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let dups = dups.clone();
+                async move {
+                    if !p.dup() {
+                        sleep(Duration::from_millis(10000)).await;
+                    }
+                    dups.lock().unwrap().push(p.dup());
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut s in msg.iter_mut() {
+                        s.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight_packets(true);
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    sink.subscribe(None).topic_filter("topic1", opts.clone()).send().await.unwrap();
+
+    let fut = sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000));
+    ntex::rt::spawn(async move {
+        let _ = fut.await;
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    let snapshot = sink.snapshot();
+    assert_eq!(snapshot.inflight.len(), 1);
+    assert_eq!(snapshot.inflight[0].topic, "test");
+    assert_eq!(snapshot.subscriptions, vec![("topic1".into(), opts)]);
+    sink.close();
+
+    // restore session on new connection
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight_packets(true);
+    ntex::rt::spawn(client.start_default());
+
+    sink.restore(snapshot.clone());
+    assert_eq!(sink.inflight_count(), 1);
+    assert_eq!(sink.snapshot(), snapshot);
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(*dups.lock().unwrap(), vec![true]);
+    assert_eq!(sink.inflight_count(), 0);
+    assert!(sink.snapshot().inflight.is_empty());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_session_snapshot_qos2() -> std::io::Result<()> {
+    let snapshots = Arc::new(Mutex::new(Vec::new()));
+    let snapshots2 = snapshots.clone();
+
+    let srv = server::test_server(move || {
+        let snapshots = snapshots2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let snapshots = snapshots.clone();
+            async move {
+                let sink = con.sink();
+                sink.track_inflight_packets(true);
+                let snapshot = snapshots.lock().unwrap().last().cloned();
+                ntex::rt::spawn(async move {
+                    if let Some(snapshot) = snapshot {
+                        sink.restore(snapshot);
+                    } else {
+                        for topic in ["topic1", "topic2"] {
+                            let fut = sink.publish(topic, Bytes::new()).send_exactly_once(None);
+                            ntex::rt::spawn(async move {
+                                let _ = fut.await;
+                            });
+                        }
+                    }
+                    sleep(Duration::from_millis(200)).await;
+                    snapshots.lock().unwrap().push(sink.snapshot());
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let ack = |packet_id| codec::PublishAck {
+        packet_id,
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    let ack2 = |packet_id| codec::PublishAck2 {
+        packet_id,
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(p) if p.qos == QoS::ExactlyOnce => {
+                ids.push(p.packet_id.unwrap())
+            }
+            pkt => panic!("unexpected packet: {:?}", pkt),
+        }
+    }
+    io.send(codec::Packet::PublishReceived(ack(ids[0])), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(p) if p.packet_id == ids[0]));
+    sleep(Duration::from_millis(500)).await;
+    drop(io);
+
+    let snapshot = snapshots.lock().unwrap()[0].clone();
+    assert_eq!(snapshot.released, vec![ids[0].get()]);
+    assert_eq!(snapshot.inflight.len(), 1);
+    assert_eq!(snapshot.inflight[0].topic, "topic2");
+    assert_eq!(snapshot.inflight[0].qos, QoS::ExactlyOnce);
+
+    // restore session on new connection
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(p) if p.packet_id == ids[0]));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(p) if p.dup && p.packet_id == Some(ids[1])));
+
+    // sink releases restored packet on its own
+    io.send(codec::Packet::PublishComplete(ack2(ids[0])), &codec).await.unwrap();
+    io.send(codec::Packet::PublishReceived(ack(ids[1])), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(p) if p.packet_id == ids[1]));
+    io.send(codec::Packet::PublishComplete(ack2(ids[1])), &codec).await.unwrap();
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(snapshots.lock().unwrap()[1], Default::default());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_restore_receive_max() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(move |p: Publish| {
+                let topics = topics.clone();
+                async move {
+                    sleep(Duration::from_millis(50)).await;
+                    topics.lock().unwrap().push((p.publish_topic().to_string(), p.dup()));
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight_packets(true);
+    ntex::rt::spawn(client.start_default());
+
+    let snapshot = SessionSnapshot {
+        inflight: (1..=3)
+            .map(|id| codec::Publish {
+                topic: format!("topic{}", id).into(),
+                packet_id: NonZeroU16::new(id),
+                ..pkt_publish()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    sink.restore(snapshot.clone());
+
+    // only one packet fits into server's receive maximum
+    assert_eq!(sink.inflight_count(), 1);
+    assert_eq!(sink.credit(), 0);
+    assert_eq!(sink.snapshot(), snapshot);
+
+    sleep(Duration::from_millis(500)).await;
+    assert!(sink.is_open());
+    assert_eq!(sink.inflight_count(), 0);
+    assert_eq!(
+        *topics.lock().unwrap(),
+        vec![
+            ("topic1".to_string(), true),
+            ("topic2".to_string(), true),
+            ("topic3".to_string(), true)
+        ]
+    );
+
+    // new packet ids do not collide with restored ones
+    let ack = sink.publish("test", Bytes::new()).send_at_least_once(None).await.unwrap();
+    assert_eq!(ack.packet_id.get(), 4);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_sys_handler() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let handler = move |name: &'static str| {
+            let hits = hits.clone();
+            ntex::service::fn_factory_with_config(move |_: Session<St>| {
+                let hits = hits.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    hits.lock().unwrap().push((name, p.publish_topic().to_string()));
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            })
+        };
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(handler("default"))
+                    .resource("{tail}*", handler("all"))
+                    .sys_handler(handler("sys")),
+            )
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["$SYS/broker/uptime", "sensors/1", "$SYS"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        *hits.lock().unwrap(),
+        vec![
+            ("sys", "$SYS/broker/uptime".to_string()),
+            ("all", "sensors/1".to_string()),
+            ("all", "$SYS".to_string())
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_set_default() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let router =
+            Router::new(ntex::service::fn_factory_with_config(|_: Session<St>| async {
+                panic!("replaced default service must not be created");
+                #[allow(unreachable_code)]
+                Ok::<_, TestError>(fn_service(|p: Publish| Ready::Ok::<_, TestError>(p.ack())))
+            }));
+        MqttServer::new(handshake)
+            .publish(router.set_default(move |p: Publish| {
+                hits.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+    assert_eq!(*hits.lock().unwrap(), vec!["test".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_builder_clone() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push((p.publish_topic().to_string(), p.retain()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let base = sink.publish("base", Bytes::from_static(b"data")).retain();
+    for topic in ["topic1", "topic2"] {
+        let res = base.clone().topic(topic).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    base.discard();
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec![("topic1".to_string(), true), ("topic2".to_string(), true)]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reauth() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Auth(msg) => {
+                    let pkt = msg.packet();
+                    assert_eq!(pkt.auth_method, Some("SCRAM".into()));
+                    let reason_code = match pkt.reason_code {
+                        codec::AuthReasonCode::ReAuth => codec::AuthReasonCode::ContinueAuth,
+                        _ => codec::AuthReasonCode::Success,
+                    };
+                    Ready::Ok::<_, TestError>(msg.ack(codec::Auth {
+                        reason_code,
+                        auth_method: Some("SCRAM".into()),
+                        ..Default::default()
+                    }))
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for (code, expected) in [
+        (codec::AuthReasonCode::ReAuth, codec::AuthReasonCode::ContinueAuth),
+        (codec::AuthReasonCode::ContinueAuth, codec::AuthReasonCode::Success),
+    ] {
+        let pkt = codec::Auth {
+            reason_code: code,
+            auth_method: Some("SCRAM".into()),
+            auth_data: Some(Bytes::from_static(b"data")),
+            ..Default::default()
+        };
+        io.send(codec::Packet::Auth(pkt), &codec).await.unwrap();
+
+        let result = io.recv(&codec).await.unwrap().unwrap();
+        if let codec::Packet::Auth(pkt) = result {
+            assert_eq!(pkt.reason_code, expected);
+        } else {
+            panic!();
+        }
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reauth_default_control() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = codec::Auth {
+        reason_code: codec::AuthReasonCode::ReAuth,
+        auth_method: Some("SCRAM".into()),
+        ..Default::default()
+    };
+    io.send(codec::Packet::Auth(pkt), &codec).await.unwrap();
+
+    let result = io.recv(&codec).await.unwrap().unwrap();
+    if let codec::Packet::Disconnect(pkt) = result {
+        assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::NotAuthorized);
+    } else {
+        panic!();
+    }
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_error_packet_id() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                if p.publish_topic() == "slow" {
+                    sleep(Duration::from_millis(10000)).await;
+                }
+                Ok::<_, TestError>(PublishAck::new(codec::PublishAckReason::UnspecifiedError))
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let err = sink
+        .publish("test", Bytes::new())
+        .packet_id(NonZeroU16::new(3).unwrap())
+        .send_at_least_once(Millis(1_000))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, error::PublishQos1Error::Fail(_)));
+    assert_eq!(err.packet_id(), Some(3));
+
+    let fut = sink
+        .publish("slow", Bytes::new())
+        .packet_id(NonZeroU16::new(5).unwrap())
+        .send_at_least_once(Millis(10_000));
+    ntex::rt::spawn(async move {
+        let _ = fut.await;
+    });
+
+    let err = sink
+        .publish("test", Bytes::new())
+        .packet_id(NonZeroU16::new(5).unwrap())
+        .send_at_least_once(Millis(1_000))
+        .await
+        .unwrap_err();
+    assert_eq!(err, error::PublishQos1Error::PacketIdInUse(5));
+    assert_eq!(err.packet_id(), Some(5));
+
+    let err = sink
+        .publish("test", Bytes::new())
+        .packet_id(NonZeroU16::new(5).unwrap())
+        .send_exactly_once(Millis(1_000))
+        .await
+        .unwrap_err();
+    assert_eq!(err, error::PublishQos2Error::PacketIdInUse(5));
+    assert_eq!(err.packet_id(), Some(5));
+
+    sink.close();
+    let err = sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert_eq!(err.unwrap_err().packet_id(), None);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_try_topic_filter() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut s in msg.iter_mut() {
+                        s.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let err = sink.subscribe(None).try_topic_filter("topic/#/a", opts.clone()).unwrap_err();
+    assert_eq!(err, ntex_mqtt::TopicFilterError::InvalidFilter("topic/#/a".into()));
+    let err = sink.unsubscribe().try_topic_filter("topic+").unwrap_err();
+    assert_eq!(err, ntex_mqtt::TopicFilterError::InvalidFilter("topic+".into()));
+
+    let ack =
+        sink.subscribe(None).try_topic_filter("topic/+/a", opts).unwrap().send().await.unwrap();
+    assert_eq!(ack.status, vec![codec::SubscribeAckReason::GrantedQos1]);
+    let ack = sink.unsubscribe().try_topic_filter("topic/+/a").unwrap().send().await.unwrap();
+    assert_eq!(ack.status, vec![codec::UnsubscribeAckReason::Success]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_ack_with() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(msg) => {
+                    let codes = msg
+                        .packet()
+                        .topic_filters
+                        .iter()
+                        .map(|(filter, _)| {
+                            if filter.starts_with("private/") {
+                                codec::SubscribeAckReason::NotAuthorized
+                            } else {
+                                codec::SubscribeAckReason::GrantedQos0
+                            }
+                        })
+                        .collect();
+                    Ready::Ok::<_, TestError>(msg.ack_with(codes))
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtMostOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let ack = sink
+        .subscribe(None)
+        .topic_filter("public/a", opts.clone())
+        .topic_filter("private/b", opts)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        ack.status,
+        vec![codec::SubscribeAckReason::GrantedQos0, codec::SubscribeAckReason::NotAuthorized]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+struct Counter(Arc<AtomicUsize>);
+
+impl<S> ntex::service::Transform<S> for Counter {
+    type Service = CounterService<S>;
+
+    fn new_transform(&self, service: S) -> Self::Service {
+        self.0.fetch_add(1, Relaxed);
+        CounterService(service)
+    }
+}
+
+struct CounterService<S>(S);
+
+impl<S: Service<Publish, Response = PublishAck>> Service<Publish> for CounterService<S> {
+    type Response = PublishAck;
+    type Error = S::Error;
+    type Future = ntex::util::Either<S::Future, Ready<PublishAck, S::Error>>;
+
+    fn poll_ready(
+        &self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        if req.payload().is_empty() {
+            let ack = PublishAck::new(codec::PublishAckReason::NotAuthorized);
+            ntex::util::Either::Right(Ready::Ok(ack))
+        } else {
+            ntex::util::Either::Left(self.0.call(req))
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_router_resource_middleware() -> std::io::Result<()> {
+    let transforms = Arc::new(AtomicUsize::new(0));
+    let transforms2 = transforms.clone();
+
+    let srv = server::test_server(move || {
+        let transforms = transforms2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(ntex::service::fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource_with_middleware(
+                    "private",
+                    |p: Publish| Ready::Ok::<_, TestError>(p.ack()),
+                    Counter(transforms),
+                ),
+            )
+            .finish()
+    });
+
+    for _ in 0..2 {
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+        let sink = client.sink();
+        ntex::rt::spawn(client.start_default());
+
+        let res = sink.publish("public", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+        let res = sink.publish("private", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(matches!(res, Err(error::PublishQos1Error::Fail(_))));
+        let res = sink
+            .publish("private", Bytes::from_static(b"data"))
+            .send_at_least_once(Millis(1_000))
+            .await;
+        assert!(res.is_ok());
+        sink.close();
+    }
+
+    // middleware is applied for each session
+    assert_eq!(transforms.load(Relaxed), 2);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_resubscribe() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut s in msg.iter_mut() {
+                        events.lock().unwrap().push(format!("sub {}", s.topic()));
+                        s.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => {
+                    for topic in msg.iter() {
+                        events.lock().unwrap().push(format!("unsub {}", topic));
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let (unsub_ack, sub_ack) =
+        sink.resubscribe("topic1".into(), "topic2".into(), opts.clone()).await.unwrap();
+    assert_eq!(unsub_ack.status, vec![codec::UnsubscribeAckReason::Success]);
+    assert_eq!(sub_ack.status, vec![codec::SubscribeAckReason::GrantedQos1]);
+    assert_eq!(*events.lock().unwrap(), vec!["unsub topic1", "sub topic2"]);
+
+    sink.close();
+    let err = sink.resubscribe("topic2".into(), "topic3".into(), opts).await.unwrap_err();
+    assert_eq!(err, error::ResubscribeError::Unsubscribe(error::SendPacketError::Disconnected));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_overload() -> std::io::Result<()> {
+    let overload = Arc::new(Mutex::new(None));
+    let overload2 = overload.clone();
+
+    let srv = server::test_server(move || {
+        let overload = overload2.clone();
+        MqttServer::new(|con: Handshake| async move {
+            let sink = con.sink();
+            ntex::rt::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                for _ in 0..2 {
+                    let fut =
+                        sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000));
+                    ntex::rt::spawn(async move {
+                        let _ = fut.await;
+                    });
+                }
+            });
+            Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::Overload(msg) => {
+                *overload.lock().unwrap() = Some((msg.current_inflight(), msg.capacity()));
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id("user").receive_max(1),
+        )),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // overload is reported without waiting for next inbound packet
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*overload.lock().unwrap(), Some((1, 1)));
+    *overload.lock().unwrap() = None;
+
+    for _ in 0..2 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        let packet_id = if let codec::Packet::Publish(pkt) = pkt {
+            pkt.packet_id.unwrap()
+        } else {
+            panic!()
+        };
+        io.send(
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id,
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    // reported once
+    assert_eq!(*overload.lock().unwrap(), None);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_qos2() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+    let inflight = Arc::new(AtomicUsize::new(usize::MAX));
+    let inflight2 = inflight.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        let inflight = inflight2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let results = results.clone();
+            let inflight = inflight.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let f1 =
+                        sink.publish("test", Bytes::new()).send_exactly_once(Millis(10_000));
+                    let f2 =
+                        sink.publish("test", Bytes::new()).send_exactly_once(Millis(10_000));
+                    let (r1, r2) = ntex::util::join(f1, f2).await;
+                    results.lock().unwrap().push(r1.map(|ack| ack.packet_id.get()));
+                    results.lock().unwrap().push(r2.map(|ack| ack.packet_id.get()));
+                    inflight.store(sink.inflight_count(), Relaxed);
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        if let codec::Packet::Publish(pkt) = io.recv(&codec).await.unwrap().unwrap() {
+            ids.push(pkt.packet_id.unwrap());
+        } else {
+            panic!()
+        }
+    }
+    for packet_id in &ids {
+        io.send(
+            codec::Packet::PublishReceived(codec::PublishAck {
+                packet_id: *packet_id,
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    for packet_id in &ids {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert!(
+            matches!(pkt, codec::Packet::PublishRelease(ref p) if p.packet_id == *packet_id)
+        );
+        io.send(
+            codec::Packet::PublishComplete(codec::PublishAck2 {
+                packet_id: *packet_id,
+                reason_code: codec::PublishAck2Reason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    let ids: Vec<_> = ids.iter().map(|id| Ok(id.get())).collect();
+    assert_eq!(*results.lock().unwrap(), ids);
+    assert_eq!(inflight.load(Relaxed), 0);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_many_exactly_once() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let results = results.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let res = sink
+                        .publish_many_exactly_once(
+                            (0..4).map(|i| sink.publish(format!("topic{}", i), Bytes::new())),
+                            Millis(10_000),
+                        )
+                        .await;
+                    *results.lock().unwrap() = res;
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id("user").receive_max(2),
+        )),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = std::collections::HashMap::new();
+    let mut outstanding = 0;
+    let mut completed = 0;
+    while completed < 4 {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => {
+                outstanding += 1;
+                assert!(outstanding <= 2);
+                ids.insert(pkt.topic.to_string(), pkt.packet_id.unwrap());
+                io.send(
+                    codec::Packet::PublishReceived(codec::PublishAck {
+                        packet_id: pkt.packet_id.unwrap(),
+                        reason_code: codec::PublishAckReason::Success,
+                        properties: Default::default(),
+                        reason_string: None,
+                    }),
+                    &codec,
+                )
+                .await
+                .unwrap();
+            }
+            codec::Packet::PublishRelease(pkt) => {
+                outstanding -= 1;
+                completed += 1;
+                io.send(
+                    codec::Packet::PublishComplete(codec::PublishAck2 {
+                        packet_id: pkt.packet_id,
+                        reason_code: codec::PublishAck2Reason::Success,
+                        properties: Default::default(),
+                        reason_string: None,
+                    }),
+                    &codec,
+                )
+                .await
+                .unwrap();
+            }
+            pkt => panic!("unexpected packet: {:?}", pkt),
+        }
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    let results: Vec<_> =
+        results.lock().unwrap().iter().map(|res| res.as_ref().unwrap().packet_id).collect();
+    let expected: Vec<_> = (0..4).map(|i| ids[&format!("topic{}", i)]).collect();
+    assert_eq!(results, expected);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_cancel_inflight() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let fut =
+                        sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000));
+                    let id = NonZeroU16::new(sink.inflight_packet_ids()[0]).unwrap();
+                    let cancel = sink.cancel_inflight(id);
+                    let cancel2 = sink.cancel_inflight(id);
+                    let res = fut.await;
+                    let count = sink.inflight_count();
+
+                    // late ack from peer does not close connection
+                    sleep(Duration::from_millis(100)).await;
+                    *result.lock().unwrap() =
+                        Some((res, cancel, cancel2, count, sink.is_open()));
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    let ack = codec::PublishAck {
+        packet_id,
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    io.send(codec::Packet::PublishAck(ack), &codec).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(
+        result.lock().unwrap().take(),
+        Some((Err(error::PublishQos1Error::Disconnected), true, false, 0, true))
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_rate_limit() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    sink.rate_limit(10);
+
+                    // burst of qos0 publishes drains the bucket
+                    let limited = (0..15)
+                        .map(|_| sink.publish("test", Bytes::new()).send_at_most_once())
+                        .filter(|res| matches!(res, Err(error::SendPacketError::RateLimited)))
+                        .count();
+
+                    // qos1 publishes wait for tokens
+                    let start = std::time::Instant::now();
+                    let res = ntex::util::join_all((0..3).map(|_| {
+                        sink.publish("test", Bytes::new()).send_at_least_once(Millis(10_000))
+                    }))
+                    .await;
+                    *result.lock().unwrap() =
+                        Some((limited, res.iter().all(|r| r.is_ok()), start.elapsed()));
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut qos0 = 0;
+    let mut qos1 = 0;
+    while qos1 < 3 {
+        match io.recv(&codec).await.unwrap().unwrap() {
+            codec::Packet::Publish(pkt) => {
+                if let Some(packet_id) = pkt.packet_id {
+                    qos1 += 1;
+                    io.send(
+                        codec::Packet::PublishAck(codec::PublishAck {
+                            packet_id,
+                            reason_code: codec::PublishAckReason::Success,
+                            properties: Default::default(),
+                            reason_string: None,
+                        }),
+                        &codec,
+                    )
+                    .await
+                    .unwrap();
+                } else {
+                    qos0 += 1;
+                }
+            }
+            pkt => panic!("unexpected packet: {:?}", pkt),
+        }
+    }
+    assert_eq!(qos0, 10);
+    sleep(Duration::from_millis(50)).await;
+
+    let (limited, ok, elapsed) = result.lock().unwrap().take().unwrap();
+    assert_eq!(limited, 5);
+    assert!(ok);
+    assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_options_builder() -> std::io::Result<()> {
+    let filters = Arc::new(Mutex::new(Vec::new()));
+    let filters2 = filters.clone();
+
+    let srv = server::test_server(move || {
+        let filters = filters2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    filters.lock().unwrap().extend(msg.packet().topic_filters.iter().cloned());
+                    for mut s in msg.iter_mut() {
+                        s.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtMostOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    sink.subscribe(None)
+        .no_local()
+        .topic_filter("topic1", opts.clone())
+        .qos(codec::QoS::AtLeastOnce)
+        .no_local()
+        .retain_as_published()
+        .topic_filter("topic2", opts.clone())
+        .retain_handling(codec::RetainHandling::NoAtSubscribe)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *filters.lock().unwrap(),
+        vec![
+            (
+                "topic1".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: true,
+                    retain_as_published: true,
+                    ..opts.clone()
+                }
+            ),
+            (
+                "topic2".into(),
+                codec::SubscriptionOptions {
+                    retain_handling: codec::RetainHandling::NoAtSubscribe,
+                    ..opts
+                }
+            ),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_message() -> std::io::Result<()> {
+    let will = Arc::new(Mutex::new(None));
+    let published = Arc::new(Mutex::new(Vec::new()));
+    let will2 = will.clone();
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let will = will2.clone();
+        let will3 = will2.clone();
+        let published = published2.clone();
+        MqttServer::new(move |con: Handshake| {
+            *will.lock().unwrap() = con.packet().last_will.clone();
+            Ready::Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::Disconnect(msg) => {
+                // normal disconnect discards will message
+                will3.lock().unwrap().take();
+                Ready::Ok::<_, TestError>(msg.ack())
+            }
+            ControlMessage::Closed(msg) => {
+                if let Some(will) = will3.lock().unwrap().take() {
+                    published.lock().unwrap().push(will);
+                }
+                Ready::Ok(msg.ack())
+            }
+            _ => Ready::Ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let will_msg = |topic| {
+        WillMessageBuilder::new("will")
+            .topic(topic)
+            .payload(Bytes::from_static(b"offline"))
+            .qos(codec::QoS::AtLeastOnce)
+            .retain()
+            .delay_interval(ntex::time::Seconds(5))
+            .properties(|will| will.content_type = Some("text".into()))
+            .build()
+    };
+    let codec = codec::Codec::default();
+
+    // disconnect with DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.last_will = Some(will_msg("will/1"));
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect(codec::Disconnect::default()), &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+    assert!(published.lock().unwrap().is_empty());
+
+    // connection dropped without DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.last_will = Some(will_msg("will/2"));
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+
+    let published = published.lock().unwrap();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].topic, "will/2");
+    assert_eq!(published[0].message, Bytes::from_static(b"offline"));
+    assert_eq!(published[0].qos, codec::QoS::AtLeastOnce);
+    assert!(published[0].retain);
+    assert_eq!(published[0].will_delay_interval_sec, Some(5));
+    assert_eq!(published[0].content_type, Some("text".into()));
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_duplicate_ack() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let res = sink
+                        .publish("test", Bytes::new())
+                        .send_at_least_once(Millis(100))
+                        .await;
+                    result.lock().unwrap().push(res.is_ok());
+                    let res = sink
+                        .publish("test", Bytes::new())
+                        .send_at_least_once(Millis(1_000))
+                        .await;
+                    result.lock().unwrap().push(res.is_ok());
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let ack = |packet_id| {
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id,
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    };
+
+    // original and retransmitted publish, both get acked
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if !p.dup));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let id = if let codec::Packet::Publish(p) = pkt {
+        assert!(p.dup);
+        p.packet_id.unwrap()
+    } else {
+        panic!("unexpected packet: {:?}", pkt)
+    };
+    io.send(ack(id), &codec).await.unwrap();
+    io.send(ack(id), &codec).await.unwrap();
+
+    // connection is still usable
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let id = if let codec::Packet::Publish(p) = pkt {
+        p.packet_id.unwrap()
+    } else {
+        panic!("unexpected packet: {:?}", pkt)
+    };
+    io.send(ack(id), &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*result.lock().unwrap(), vec![true, true]);
+    assert!(!io.is_closed());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_user_data() -> std::io::Result<()> {
+    let data = Arc::new(Mutex::new(Vec::new()));
+    let data2 = data.clone();
+
+    let srv = server::test_server(move || {
+        let data = data2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(ntex::service::fn_factory_with_config(|_: Session<St>| {
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }))
+                .resource(
+                    "set",
+                    ntex::service::fn_factory_with_config(|session: Session<St>| {
+                        Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                            session.set_user_data(1u32);
+                            session.set_user_data(String::from("data"));
+                            session.set_user_data(2u32);
+                            Ready::Ok::<_, TestError>(p.ack())
+                        }))
+                    }),
+                )
+                .resource(
+                    "get",
+                    ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                        let data = data.clone();
+                        Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                            data.lock().unwrap().push((
+                                session.user_data::<u32>().map(|v| *v),
+                                session.user_data::<String>().map(|v| v.to_string()),
+                                session.user_data::<u64>().is_some(),
+                            ));
+                            Ready::Ok::<_, TestError>(p.ack())
+                        }))
+                    }),
+                ),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for topic in ["get", "set", "get"] {
+        let res = sink.publish(topic, Bytes::new()).send_at_least_once(Millis(1_000)).await;
+        assert!(res.is_ok());
+    }
+    assert_eq!(
+        *data.lock().unwrap(),
+        vec![(None, None, false), (Some(2), Some("data".to_string()), false)]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_validate() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("topic/+", Bytes::new()).send_at_most_once();
+    assert_eq!(
+        res,
+        Err(error::SendPacketError::Encode(error::EncodeError::InvalidPublish(
+            error::PublishValidationError::TopicWildcard
+        )))
+    );
+    let res = sink.publish("", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert_eq!(
+        res,
+        Err(error::PublishQos1Error::Encode(error::EncodeError::InvalidPublish(
+            error::PublishValidationError::EmptyTopic
+        )))
+    );
+    let res = sink.publish("topic/#", Bytes::new()).send_exactly_once(Millis(1_000)).await;
+    assert!(matches!(
+        res,
+        Err(error::PublishQos2Error::Encode(error::EncodeError::InvalidPublish(
+            error::PublishValidationError::TopicWildcard
+        )))
+    ));
+    assert_eq!(sink.inflight_count(), 0);
+
+    let res = sink.publish("topic", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_flush() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    for _ in 0..10 {
+                        sink.publish("test", Bytes::from_static(b"data"))
+                            .send_at_most_once()
+                            .unwrap();
+                    }
+                    let res1 = sink.flush().await;
+                    sink.close();
+                    let res2 = sink.flush().await;
+                    *result.lock().unwrap() = Some((res1, res2));
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for _ in 0..10 {
+        let pkt = io.recv(&codec).await.unwrap().unwrap();
+        assert!(matches!(pkt, codec::Packet::Publish(_)));
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        result.lock().unwrap().take(),
+        Some((Ok(()), Err(error::SendPacketError::Disconnected)))
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_resource_group() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        let handler = move |name: &'static str, reason: codec::PublishAckReason| {
+            let hits = hits.clone();
+            ntex::service::fn_factory_with_config(move |_: Session<St>| {
+                let hits = hits.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    hits.lock().unwrap().push((name, p.publish_topic().to_string()));
+                    Ready::Ok::<_, TestError>(PublishAck::new(reason))
+                }))
+            })
+        };
+        MqttServer::new(handshake)
+            .publish(
+                Router::new(handler("default", codec::PublishAckReason::Success))
+                    .resource_group(
+                        "group1",
+                        vec![
+                            handler("log", codec::PublishAckReason::Success),
+                            handler("auth", codec::PublishAckReason::NotAuthorized),
+                        ],
+                    )
+                    .resource_group_with_merge(
+                        "group2",
+                        GroupAckMerge::AnySuccess,
+                        vec![
+                            handler("auth", codec::PublishAckReason::NotAuthorized),
+                            handler("log", codec::PublishAckReason::Success),
+                        ],
+                    ),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("group1", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(matches!(
+        res,
+        Err(error::PublishQos1Error::Fail(ref ack))
+            if ack.reason_code == codec::PublishAckReason::NotAuthorized
+    ));
+    let res = sink.publish("group2", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+
+    let mut hits = hits.lock().unwrap().clone();
+    hits.sort();
+    assert_eq!(
+        hits,
+        vec![
+            ("auth", "group1".to_string()),
+            ("auth", "group2".to_string()),
+            ("log", "group1".to_string()),
+            ("log", "group2".to_string()),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_send_qos() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let results = results.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+                        let res =
+                            sink.publish("test", Bytes::new()).send(qos, Millis(10_000)).await;
+                        results.lock().unwrap().push(res);
+                    }
+                    sink.close();
+                    let res = sink
+                        .publish("test", Bytes::new())
+                        .send(QoS::AtLeastOnce, Millis(1_000))
+                        .await;
+                    results.lock().unwrap().push(res);
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let ack = |packet_id| codec::PublishAck {
+        packet_id,
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    let ack2 = |packet_id| codec::PublishAck2 {
+        packet_id,
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Publish(ref p) if p.qos == QoS::AtMostOnce));
+
+    let id1 = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(p) if p.qos == QoS::AtLeastOnce => p.packet_id.unwrap(),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    io.send(codec::Packet::PublishAck(ack(id1)), &codec).await.unwrap();
+
+    let id2 = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(p) if p.qos == QoS::ExactlyOnce => p.packet_id.unwrap(),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    io.send(codec::Packet::PublishReceived(ack(id2)), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(_)));
+    io.send(codec::Packet::PublishComplete(ack2(id2)), &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        *results.lock().unwrap(),
+        vec![
+            Ok(QosAckResult::AtMostOnce),
+            Ok(QosAckResult::AtLeastOnce(ack(id1))),
+            Ok(QosAckResult::ExactlyOnce(ack2(id2))),
+            Err(error::SendAnyError::AtLeastOnce(error::PublishQos1Error::Disconnected)),
+        ]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_ack_with_reason() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                Ready::Ok::<_, TestError>(match p.publish_topic() {
+                    "quota" => p.ack_with_reason(
+                        codec::PublishAckReason::QuotaExceeded,
+                        Some("too many".into()),
+                    ),
+                    _ => p.ack_error(codec::PublishAckReason::NotAuthorized),
+                })
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.topic = "quota".into();
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            properties: Default::default(),
+            reason_string: Some("too many".into()),
+        })
+    );
+
+    let mut pkt = pkt_publish();
+    pkt.packet_id = NonZeroU16::new(2);
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_with_default_fn() -> std::io::Result<()> {
+    let hits = Arc::new(Mutex::new(Vec::new()));
+    let hits2 = hits.clone();
+
+    let srv = server::test_server(move || {
+        let hits = hits2.clone();
+        MqttServer::new(handshake)
+            .publish(
+                Router::<St, TestError>::with_default_fn(move |p: Publish| {
+                    hits.lock().unwrap().push(p.publish_topic().to_string());
+                    Ready::Ok(p.ack_error(codec::PublishAckReason::TopicNameInvalid))
+                })
+                .resource_fn("topic1", |p: Publish| Ready::Ok(p.ack())),
+            )
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("topic1", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+    let res = sink.publish("topic2", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(matches!(res, Err(error::PublishQos1Error::Fail(_))));
+    assert_eq!(*hits.lock().unwrap(), vec!["topic2"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_forward_to() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let fut = p.forward_to(session.sink(), "out".into());
+                    async move {
+                        fut.await.unwrap();
+                        Ok::<_, TestError>(PublishAck::new(codec::PublishAckReason::Success))
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.topic = "in".into();
+    pkt.retain = true;
+    pkt.payload = Bytes::from_static(b"data");
+    pkt.properties.correlation_data = Some(Bytes::from_static(b"corr"));
+    pkt.properties.content_type = Some("text/plain".into());
+    pkt.properties.user_properties.push(("key".into(), "val".into()));
+    io.send(pkt.into(), &codec).await.unwrap();
+
+    let pkt = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt,
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    assert_eq!(pkt.topic, "out");
+    assert_eq!(pkt.qos, QoS::AtLeastOnce);
+    assert!(pkt.retain);
+    assert_eq!(pkt.payload, Bytes::from_static(b"data"));
+    assert_eq!(pkt.properties.correlation_data, Some(Bytes::from_static(b"corr")));
+    assert_eq!(pkt.properties.content_type, Some("text/plain".into()));
+    assert_eq!(pkt.properties.user_properties, vec![("key".into(), "val".into())]);
+    assert_eq!(pkt.properties.topic_alias, None);
+
+    io.send(
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: pkt.packet_id.unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(ref ack) if ack.packet_id.get() == 1));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_try_send_no_credit() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(|p: Publish| async move {
+                sleep(Duration::from_millis(10000)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let fut = sink.publish("test", Bytes::new()).try_send_at_least_once(Millis(10000)).unwrap();
+    ntex::rt::spawn(async move {
+        let _ = fut.await;
+    });
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.credit(), 0);
+
+    let res = sink.publish("test", Bytes::new()).try_send_at_least_once(Millis(10000));
+    assert!(matches!(res, Err(error::PublishQos1Error::NoCredit)));
+    let res = sink.publish("test", Bytes::new()).try_send_exactly_once(Millis(10000));
+    assert!(matches!(res, Err(error::PublishQos2Error::NoCredit)));
+    assert_eq!(sink.waiter_count(), 0);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_connect_info() -> std::io::Result<()> {
+    let info = Arc::new(Mutex::new(Vec::new()));
+    let info2 = info.clone();
+
+    let srv = server::test_server(move || {
+        let info = info2.clone();
+        MqttServer::new(|con: Handshake| async move {
+            let assign = con.packet().client_id.is_empty();
+            Ok::<_, TestError>(con.ack(St).with(|ack| {
+                if assign {
+                    ack.assigned_client_id = Some("assigned".into());
+                }
+            }))
+        })
+        .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+            info.lock().unwrap().push((
+                session.client_id().clone(),
+                session.clean_start(),
+                session.keep_alive(),
+                session.protocol_version(),
+            ));
+            Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                Ready::Ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .clean_start()
+        .keep_alive(Seconds(30))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    sink.close();
+
+    let client = client::MqttConnector::new(srv.addr()).clean_start().connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    sink.close();
+
+    assert_eq!(
+        *info.lock().unwrap(),
+        vec![
+            ("user".into(), true, 30, ntex_mqtt::ProtocolVersion::MQTT5),
+            ("assigned".into(), true, 0, ntex_mqtt::ProtocolVersion::MQTT5),
+        ]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_server_properties() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake| async move {
+            assert!(con.sink().server_properties().is_none());
+            Ok::<_, TestError>(con.ack(St).with(|ack| {
+                ack.topic_alias_max = 7;
+                ack.max_packet_size = Some(64);
+            }))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let props = sink.server_properties().unwrap();
+    assert_eq!(props.topic_alias_max, 7);
+    assert_eq!(props.max_packet_size, Some(64));
+
+    let payload = Bytes::from(vec![0; 128]);
+    let res = sink.publish("test", payload.clone()).send_at_least_once(Millis(1_000)).await;
+    assert_eq!(
+        res,
+        Err(error::PublishQos1Error::Encode(ntex_mqtt::error::EncodeError::PacketTooLarge {
+            size: 140,
+            max: 64
+        }))
+    );
+    let res = sink.publish("test", payload).send_exactly_once(Millis(1_000)).await;
+    assert_eq!(
+        res,
+        Err(error::PublishQos2Error::Encode(ntex_mqtt::error::EncodeError::PacketTooLarge {
+            size: 140,
+            max: 64
+        }))
+    );
+    assert_eq!(sink.inflight_count(), 0);
+
+    let res = sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await;
+    assert!(res.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscription_store() -> std::io::Result<()> {
+    let store = Arc::new(InMemorySubscriptionStore::new());
+    let store2 = store.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .subscription_store(store2.clone())
+            .control(|msg: ControlMessage<TestError>| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic().starts_with("denied") {
+                            sub.fail(codec::SubscribeAckReason::NotAuthorized);
+                        } else {
+                            sub.subscribe(codec::QoS::AtLeastOnce);
+                        }
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::ExactlyOnce,
+        no_local: true,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    sink.subscribe(None)
+        .topic_filter("sensors/+", opts.clone())
+        .topic_filter("denied/#", opts.clone())
+        .send()
+        .await
+        .unwrap();
+
+    let granted = codec::SubscriptionOptions { qos: codec::QoS::AtLeastOnce, ..opts };
+    assert_eq!(store.subscribers_for("sensors/1"), vec![("user".to_string(), granted.clone())]);
+    assert!(store.subscribers_for("denied/1").is_empty());
+    assert!(store.subscribers_for("other").is_empty());
+
+    sink.unsubscribe().topic_filter("sensors/+").send().await.unwrap();
+    assert!(store.subscribers_for("sensors/1").is_empty());
+
+    // subscriptions of session with zero expiry are removed on close
+    sink.subscribe(None).topic_filter("sensors/+", opts.clone()).send().await.unwrap();
+    sink.close();
+    sleep(Duration::from_millis(50)).await;
+    assert!(store.subscribers_for("sensors/1").is_empty());
+
+    // persistent session keeps subscriptions
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .packet(|pkt| pkt.session_expiry_interval_secs = Some(60))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.subscribe(None).topic_filter("sensors/+", opts).send().await.unwrap();
+    sink.close();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.subscribers_for("sensors/1"), vec![("user2".to_string(), granted)]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_fire_and_forget() -> std::io::Result<()> {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                publishes.fetch_add(1, Relaxed);
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("test", Bytes::new()).send_fire_and_forget();
+    sink.publish("test", Bytes::new())
+        .send_fire_and_forget_with_callback(|_| panic!("publish should succeed"));
+    sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    assert_eq!(publishes.load(Relaxed), 3);
+
+    sink.close();
+    sleep(Duration::from_millis(50)).await;
+
+    let err = Rc::new(std::cell::RefCell::new(None));
+    let err2 = err.clone();
+    sink.publish("test", Bytes::new()).send_fire_and_forget();
+    sink.publish("test", Bytes::new())
+        .send_fire_and_forget_with_callback(move |e| *err2.borrow_mut() = Some(e));
+    assert_eq!(err.borrow_mut().take(), Some(error::SendPacketError::Disconnected));
+    Ok(())
+}
+
+#[test]
+fn test_publish_v3_conversion() {
+    let payload = Bytes::from_static(b"data");
+    let mut pkt = pkt_publish();
+    pkt.qos = codec::QoS::ExactlyOnce;
+    pkt.retain = true;
+    pkt.payload = payload.clone();
+    pkt.properties.content_type = Some("text".into());
+
+    let v3 = Publish::new(pkt).into_v3();
+    assert_eq!(v3.publish_topic(), "test");
+    assert_eq!(v3.qos(), codec::QoS::ExactlyOnce);
+    assert!(v3.retain());
+    assert!(!v3.dup());
+    assert_eq!(v3.id(), NonZeroU16::new(1));
+    assert_eq!(v3.payload().as_ptr(), payload.as_ptr());
+
+    let v5 = v3.into_v5();
+    assert_eq!(v5.publish_topic(), "test");
+    assert_eq!(v5.qos(), codec::QoS::ExactlyOnce);
+    assert!(v5.retain());
+    assert_eq!(v5.id(), NonZeroU16::new(1));
+    assert_eq!(v5.payload(), &payload);
+    assert_eq!(v5.payload().as_ptr(), payload.as_ptr());
+    assert_eq!(v5.content_type(), None);
+
+    // aliased publish, topic is resolved by router
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::new();
+    pkt.properties.topic_alias = NonZeroU16::new(1);
+    let mut publish = Publish::new(pkt);
+    *publish.topic_mut() = ntex::router::Path::new(ByteString::from("test"));
+    assert_eq!(publish.into_v3().publish_topic(), "test");
+}
+
+#[ntex::test]
+async fn test_sink_publish_response() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(fn_service(move |p: Publish| {
+                    if let Some(builder) =
+                        session.sink().publish_response(&p, Bytes::from_static(b"pong"))
+                    {
+                        builder.send_at_most_once().unwrap();
+                    }
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let responses = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let responses2 = responses.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("reply", move |p: Publish| {
+                responses2
+                    .borrow_mut()
+                    .push((p.payload().clone(), p.correlation_data().cloned()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    sink.publish("request", Bytes::new())
+        .response_topic("reply".into())
+        .correlation_data(Bytes::from_static(b"id-1"))
+        .send_at_least_once(Millis(1_000))
+        .await
+        .unwrap();
+    // request without response topic is not answered
+    sink.publish("request", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        *responses.borrow(),
+        vec![(Bytes::from_static(b"pong"), Some(Bytes::from_static(b"id-1")))]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_ping_rtt() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let (rtt1, rtt2) = ntex::util::join(sink.ping_rtt(), sink.ping_rtt()).await;
+    assert!(rtt1.unwrap() < Duration::from_secs(1));
+    assert!(rtt2.unwrap() < Duration::from_secs(1));
+
+    let rtt = sink.ping_rtt();
+    sink.close();
+    assert_eq!(rtt.await, None);
+    assert_eq!(sink.ping_rtt().await, None);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_ping_rtt_keepalive() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .keep_alive_tolerance(10.0)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| async move {
+                match msg {
+                    ControlMessage::Ping(msg) => {
+                        sleep(Duration::from_millis(300)).await;
+                        Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(1))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // response to keep-alive ping must not resolve rtt waiter
+    sleep(Duration::from_millis(1100)).await;
+    let rtt = sink.ping_rtt().await.unwrap();
+    assert!(rtt >= Duration::from_millis(350), "{:?}", rtt);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_control_closed() -> std::io::Result<()> {
+    let closed = Arc::new(AtomicUsize::new(0));
+    let closed2 = closed.clone();
+
+    let srv = server::test_server(move || {
+        let closed = closed2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Closed(msg) => {
+                    closed.fetch_add(1, Relaxed);
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Disconnect(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let codec = codec::Codec::default();
+
+    // clean close with DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect(codec::Disconnect::default()), &codec).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(closed.load(Relaxed), 1);
+    drop(io);
+
+    // connection dropped without DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(closed.load(Relaxed), 2);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_no_timeout() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let results = results.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let res = sink.publish("test", Bytes::new()).send_at_least_once(None).await;
+                    results.lock().unwrap().push(res.is_ok());
+                    let res = sink.publish("test", Bytes::new()).send_exactly_once(None).await;
+                    results.lock().unwrap().push(res.is_ok());
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let ack = |packet_id| codec::PublishAck {
+        packet_id,
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+
+    let id1 = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(p) if p.qos == QoS::AtLeastOnce => p.packet_id.unwrap(),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    // publish is not re-sent
+    assert!(ntex::time::timeout(Millis(200), io.recv(&codec)).await.is_err());
+    io.send(codec::Packet::PublishAck(ack(id1)), &codec).await.unwrap();
+
+    let id2 = match io.recv(&codec).await.unwrap().unwrap() {
+        codec::Packet::Publish(p) if p.qos == QoS::ExactlyOnce => p.packet_id.unwrap(),
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    };
+    assert!(ntex::time::timeout(Millis(200), io.recv(&codec)).await.is_err());
+    io.send(codec::Packet::PublishReceived(ack(id2)), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(_)));
+    assert!(ntex::time::timeout(Millis(200), io.recv(&codec)).await.is_err());
+    io.send(
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: id2,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(*results.lock().unwrap(), vec![true, true]);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_peer_addr() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let addr = addr.clone();
+                Ready::Ok::<_, TestError>(fn_service(move |msg| match msg {
+                    ControlMessage::Ping(msg) => {
+                        *addr.lock().unwrap() = session.peer_addr();
+                        Ready::Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    assert!(sink.ping_rtt().await.is_some());
+    let addr = addr.lock().unwrap().take().unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), srv.addr().port());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_with_codec() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(sink.with_codec(|codec| codec.get_max_outbound_size()), 0);
+    sink.publish("test", Bytes::from(vec![0; 100]))
+        .send_at_least_once(Millis(1_000))
+        .await
+        .unwrap();
+
+    sink.with_codec(|codec| codec.set_max_outbound_size(64));
+    assert_eq!(sink.with_codec(|codec| codec.get_max_outbound_size()), 64);
+    let res =
+        sink.publish("test", Bytes::from(vec![0; 100])).send_at_least_once(Millis(1_000)).await;
+    assert!(matches!(
+        res,
+        Err(error::PublishQos1Error::Encode(error::EncodeError::PacketTooLarge { .. }))
+    ));
+    sink.publish("test", Bytes::new()).send_at_least_once(Millis(1_000)).await.unwrap();
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_proxy_protocol() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        MqttServer::new(handshake)
+            .proxy_protocol(true)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                *addr.lock().unwrap() = session.peer_addr();
+                Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    io.send(
+        Bytes::from_static(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n"),
+        &ntex::codec::BytesCodec,
+    )
+    .await
+    .unwrap();
+
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(ref ack)
+        if ack.reason_code == codec::ConnectAckReason::Success));
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    assert_eq!(*addr.lock().unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+
+    // connection without header is rejected
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_proxy_protocol_selector() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        ntex_mqtt::v5::Selector::new().variant(
+            |_: &Handshake| Ready::Ok::<_, TestError>(true),
+            MqttServer::new(handshake).proxy_protocol(true).publish(
+                ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                    *addr.lock().unwrap() = session.peer_addr();
+                    Ready::Ok::<_, TestError>(fn_service(|p: Publish| {
+                        Ready::Ok::<_, TestError>(p.ack())
+                    }))
+                }),
+            ),
+        )
+    });
+
+    let io = srv.connect().await.unwrap();
+    io.send(
+        Bytes::from_static(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n"),
+        &ntex::codec::BytesCodec,
+    )
+    .await
+    .unwrap();
+
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck(ref ack)
+        if ack.reason_code == codec::ConnectAckReason::Success));
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    assert_eq!(*addr.lock().unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+
+    // connection without header is rejected
+    let io = srv.connect().await.unwrap();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_publish_ack_into_result() {
+    assert_eq!(PublishAck::new(codec::PublishAckReason::Success).into_result(), Ok(()));
+
+    let err = PublishAck::new(codec::PublishAckReason::NotAuthorized)
+        .reason("denied".into())
+        .into_result()
+        .unwrap_err();
+    assert_eq!(err.reason_code, codec::PublishAckReason::NotAuthorized);
+    assert_eq!(err.reason_string, Some("denied".into()));
+    assert_eq!(err.to_string(), "Publish is rejected: NotAuthorized (denied)");
+
+    let res: Result<(), error::PublishAckError> =
+        PublishAck::new(codec::PublishAckReason::NoMatchingSubscribers).into();
+    assert!(res.is_err());
+}
+
+#[ntex::test]
+async fn test_sink_bytes_counters() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let (sent, received) = (sink.bytes_sent(), sink.bytes_received());
+    let payload = Bytes::from(vec![0; 100]);
+    sink.publish("test", payload.clone()).send_at_least_once(Millis(1_000)).await.unwrap();
+
+    // fixed header is 2 bytes for packets shorter than 128 bytes
+    let size = codec::Publish { payload, ..pkt_publish() }.encoded_size() as u64 + 2;
+    assert_eq!(sink.bytes_sent() - sent, size);
+    // puback: fixed header, packet id, reason code and empty properties
+    assert_eq!(sink.bytes_received() - received, 6);
+
+    sink.publish("test", Bytes::new()).send_at_most_once().unwrap();
+    assert_eq!(sink.bytes_sent() - sent, size + 2 + 7);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_subscribe_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(|msg: ControlMessage<TestError>| async move {
+                // slow broker
+                sleep(Millis(500)).await;
+                match msg {
+                    ControlMessage::Subscribe(msg) => Ok::<_, TestError>(msg.ack()),
+                    ControlMessage::Unsubscribe(msg) => Ok(msg.ack()),
+                    _ => Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let res = sink
+        .subscribe(None)
+        .topic_filter("topic1", opts.clone())
+        .with_timeout(Millis(50))
+        .send()
+        .await;
+    assert_eq!(res.unwrap_err(), error::SendPacketError::Timeout);
+    assert_eq!(sink.inflight_count(), 0);
+    assert!(sink.inflight_order().is_empty());
+
+    let res = sink.unsubscribe().topic_filter("topic1").with_timeout(Millis(50)).send().await;
+    assert_eq!(res.unwrap_err(), error::SendPacketError::Timeout);
+    assert_eq!(sink.inflight_count(), 0);
+    assert!(sink.inflight_order().is_empty());
+
+    // late acks from slow broker are ignored
+    sleep(Duration::from_millis(1200)).await;
+    assert!(sink.is_open());
+    let ack = sink.subscribe(None).topic_filter("topic2", opts).send().await.unwrap();
+    assert_eq!(ack.status.len(), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[test]
+fn test_publish_ack_from_publish() {
+    let publish = Publish::new(pkt_publish());
+    let ack = codec::PublishAck::from(&publish);
+    assert_eq!(ack.packet_id, NonZeroU16::new(1).unwrap());
+    assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+    assert!(ack.properties.is_empty());
+
+    let ack = codec::PublishAck2::from(&publish);
+    assert_eq!(ack.packet_id, NonZeroU16::new(1).unwrap());
+    assert_eq!(ack.reason_code, codec::PublishAck2Reason::Success);
+}
+
+#[test]
+#[should_panic(expected = "packet id")]
+fn test_publish_ack_from_qos0_publish() {
+    let publish = Publish::new(codec::Publish {
+        qos: codec::QoS::AtMostOnce,
+        packet_id: None,
+        ..pkt_publish()
+    });
+    let _ = codec::PublishAck::from(&publish);
+}
+
+#[ntex::test]
+async fn test_sink_drain_inflight_on_reconnect() -> std::io::Result<()> {
+    let dups = Arc::new(Mutex::new(Vec::new()));
+    let dups2 = dups.clone();
+
+    let srv = server::test_server(move || {
+        let dups = dups2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let dups = dups.clone();
+                async move {
+                    if !p.dup() {
+                        sleep(Duration::from_millis(10000)).await;
+                    }
+                    dups.lock().unwrap().push((p.publish_topic().to_string(), p.id()));
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.track_inflight_packets(true);
+    ntex::rt::spawn(client.start_default());
+
+    let res = Arc::new(Mutex::new(Vec::new()));
+    for topic in ["topic1", "topic2"] {
+        let fut = sink.publish(topic, Bytes::new()).send_at_least_once(None);
+        let res = res.clone();
+        ntex::rt::spawn(async move {
+            let r = fut.await;
+            res.lock().unwrap().push(r);
+        });
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(sink.inflight_order(), vec![1, 2]);
+    sink.close();
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(res.lock().unwrap().len(), 2);
+    assert!(res.lock().unwrap().iter().all(|r| r.is_err()));
+
+    // re-send on new connection
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let new_sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let futs = sink.drain_inflight_on_reconnect(new_sink.clone());
+    assert_eq!(futs.len(), 2);
+    assert!(sink.snapshot().inflight.is_empty());
+    assert_eq!(new_sink.inflight_order(), vec![1, 2]);
+
+    for res in ntex::util::join_all(futs).await {
+        assert_eq!(res.unwrap().reason_code, codec::PublishAckReason::Success);
+    }
+    assert_eq!(
+        *dups.lock().unwrap(),
+        vec![
+            ("topic1".to_string(), NonZeroU16::new(1)),
+            ("topic2".to_string(), NonZeroU16::new(2))
+        ]
+    );
+    assert_eq!(new_sink.inflight_count(), 0);
+
+    new_sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_late_ack_for_released_id() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let fut1 = sink
+                        .publish("test1", Bytes::new())
+                        .max_retries(0)
+                        .send_at_least_once(Millis(50));
+                    let fut2 =
+                        sink.publish("test2", Bytes::new()).send_at_least_once(Millis(10_000));
+                    let (res1, res2) = ntex::util::join(fut1, fut2).await;
+                    sleep(Duration::from_millis(50)).await;
+                    *result.lock().unwrap() = Some((
+                        res1.map(|_| ()),
+                        res2.map(|ack| ack.packet_id.get()),
+                        sink.is_open(),
+                    ));
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        if let codec::Packet::Publish(pkt) = io.recv(&codec).await.unwrap().unwrap() {
+            ids.push(pkt.packet_id.unwrap());
+        } else {
+            panic!("publish packet is expected");
+        }
+    }
+    // first publish fails, its packet id is released
+    sleep(Duration::from_millis(150)).await;
+
+    for packet_id in ids {
+        let ack = codec::PublishAck {
+            packet_id,
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        };
+        io.send(codec::Packet::PublishAck(ack), &codec).await.unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        result.lock().unwrap().take(),
+        Some((Err(error::PublishQos1Error::MaxRetriesExceeded), Ok(2), true))
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_rate_limit_credit() -> std::io::Result<()> {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        MqttServer::new(move |con: Handshake| {
+            let result = result.clone();
+            async move {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    sink.rate_limit(1000);
+
+                    // burst is larger than peer's receive maximum
+                    for _ in 0..5 {
+                        let fut = sink
+                            .publish("test", Bytes::new())
+                            .send_at_least_once(Millis(10_000));
+                        ntex::rt::spawn(async move {
+                            let _ = fut.await;
+                        });
+                    }
+                    sleep(Duration::from_millis(100)).await;
+                    *result.lock().unwrap() = Some(sink.inflight_count());
+                });
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(
+        codec::Packet::Connect(Box::new(
+            codec::Connect::default().client_id("user").receive_max(2),
+        )),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(result.lock().unwrap().take(), Some(2));
+    Ok(())
+}